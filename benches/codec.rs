@@ -0,0 +1,85 @@
+// decoding a burst of spot events: the length-prefixed read into a fresh
+// vector that `read_proto_message` used to do, against `OpenApiCodec`
+// decoding from one reused buffer.
+//
+//     cargo bench --bench codec
+
+use std::hint::black_box;
+use std::io::Read;
+
+use bytes::{BufMut, BytesMut};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use prost::Message;
+use tokio_util::codec::{Decoder, Encoder};
+
+use rust_ctrader::OpenApiCodec;
+use rust_ctrader::open_api::{ProtoMessage, ProtoOaPayloadType, ProtoOaSpotEvent};
+
+const EVENTS: usize = 10_000;
+
+fn spot_events() -> Vec<u8> {
+    let mut codec = OpenApiCodec::default();
+    let mut wire = BytesMut::new();
+    for i in 0..EVENTS as u64 {
+        let spot = ProtoOaSpotEvent {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaSpotEvent as i32),
+            ctid_trader_account_id: 12_345_678,
+            symbol_id: 41,
+            bid: Some(108_250 + i % 100),
+            ask: Some(108_270 + i % 100),
+            timestamp: Some(1_700_000_000_000 + i as i64),
+            ..Default::default()
+        };
+        let message = ProtoMessage {
+            payload_type: ProtoOaPayloadType::ProtoOaSpotEvent as u32,
+            payload: Some(spot.encode_to_vec().into()),
+            client_msg_id: None,
+        };
+        codec.encode(message, &mut wire).unwrap();
+    }
+    wire.to_vec()
+}
+
+fn decode_spot(message: &ProtoMessage) -> u64 {
+    let spot = ProtoOaSpotEvent::decode(message.payload.as_deref().unwrap_or_default()).unwrap();
+    spot.bid.unwrap_or_default()
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let wire = spot_events();
+    let mut group = c.benchmark_group("decode spot events");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    group.bench_function("vec per frame", |b| {
+        b.iter(|| {
+            let mut reader = &wire[..];
+            let mut len = [0u8; 4];
+            let mut sum = 0;
+            while reader.read_exact(&mut len).is_ok() {
+                let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+                reader.read_exact(&mut buf).unwrap();
+                let message = ProtoMessage::decode(&buf[..]).unwrap();
+                sum += decode_spot(&message);
+            }
+            black_box(sum)
+        })
+    });
+
+    group.bench_function("OpenApiCodec", |b| {
+        let mut codec = OpenApiCodec::default();
+        let mut buf = BytesMut::with_capacity(wire.len());
+        b.iter(|| {
+            buf.put_slice(&wire);
+            let mut sum = 0;
+            while let Some(message) = codec.decode(&mut buf).unwrap() {
+                sum += decode_spot(&message);
+            }
+            black_box(sum)
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode);
+criterion_main!(benches);
@@ -1,6 +1,10 @@
 fn main() -> Result<(), Box<dyn std::error::Error>>{
     tonic_prost_build::Config::new()
     .default_package_filename("open_api")
+    // decoded as a slice of the frame instead of a copy of it
+    .bytes([".ProtoMessage.payload"])
+    // the message descriptors, for the json transport
+    .file_descriptor_set_path(std::path::PathBuf::from(std::env::var("OUT_DIR")?).join("open_api_descriptor.bin"))
     .compile_protos(&[
         "proto/OpenApiCommonMessages.proto",
         "proto/OpenApiCommonModelMessages.proto",
@@ -8,5 +12,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>>{
         "proto/OpenApiModelMessages.proto"
     ], &["proto/"])?;
 
+    // the server version the files in proto/ were taken from, compared with
+    // the version the server reports
+    let version = std::fs::read_to_string("proto/VERSION")?;
+    let version = version
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or("proto/VERSION holds no version")?;
+    println!("cargo:rerun-if-changed=proto/VERSION");
+    println!("cargo:rustc-env=OPEN_API_PROTO_VERSION={}", version);
+
     Ok(())
 }
\ No newline at end of file
@@ -1,11 +1,99 @@
+use crate::token_store::TokenStore;
 use crate::types::{Scope, Tokens};
-use reqwest::Client;
+use crate::utilities::REDACTED;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tracing::{debug, warn};
 
+// stored tokens expiring within this many seconds are refreshed before use
+const REFRESH_MARGIN_SECS: i64 = 60 * 60;
+
+// how long authorize_interactive waits for the browser to come back
+const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// a connection that does not send its request line in time is dropped, so it
+// cannot hold up the redirect
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_AUTHORIZATION_BASE_URL: &str = "https://id.ctrader.com";
+const DEFAULT_TOKEN_BASE_URL: &str = "https://openapi.ctrader.com";
+
+/// Error returned by the token endpoint requests.
 #[derive(Debug)]
+pub enum AuthError {
+    /// the request could not be sent or its body could not be read
+    Request(reqwest::Error),
+    /// the token endpoint answered with an `errorCode`, e.g. `ACCESS_DENIED`
+    Api {
+        error_code: String,
+        description: Option<String>,
+    },
+    /// the response is neither tokens nor an error payload
+    InvalidResponse { status: u16, body: String },
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Request(e) => write!(f, "token request failed: {e}"),
+            AuthError::Api {
+                error_code,
+                description: Some(description),
+            } => write!(f, "token endpoint error {error_code}: {description}"),
+            AuthError::Api { error_code, .. } => write!(f, "token endpoint error {error_code}"),
+            AuthError::InvalidResponse { status, body } => {
+                write!(f, "unexpected token endpoint response ({status}): {body}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AuthError::Request(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for AuthError {
+    fn from(e: reqwest::Error) -> Self {
+        AuthError::Request(e)
+    }
+}
+
+// the error payload of the token endpoint, its fields are null on success
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenErrorBody {
+    error_code: Option<String>,
+    description: Option<String>,
+}
+
 pub struct AuthClient {
     pub client_id: String,
     pub client_secret: String,
     pub redirect_uri: String,
+    /// where the user grants access, `https://id.ctrader.com` by default
+    pub authorization_base_url: String,
+    /// where codes and refresh tokens are exchanged, `https://openapi.ctrader.com` by default
+    pub token_base_url: String,
+}
+
+impl std::fmt::Debug for AuthClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthClient")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &REDACTED)
+            .field("redirect_uri", &self.redirect_uri)
+            .field("authorization_base_url", &self.authorization_base_url)
+            .field("token_base_url", &self.token_base_url)
+            .finish()
+    }
 }
 
 impl AuthClient {
@@ -14,27 +102,54 @@ impl AuthClient {
             client_id: String::from(client_id),
             client_secret: String::from(client_secret),
             redirect_uri: String::from(redirect_uri),
+            authorization_base_url: String::from(DEFAULT_AUTHORIZATION_BASE_URL),
+            token_base_url: String::from(DEFAULT_TOKEN_BASE_URL),
         }
     }
 
-    pub fn get_authorization_url(&self, scope: Scope) -> String {
+    /// Use another authorization server, e.g. a local stand-in.
+    pub fn with_authorization_base_url(mut self, url: &str) -> Self {
+        self.authorization_base_url = url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Use another token endpoint host, e.g. a local stand-in.
+    pub fn with_token_base_url(mut self, url: &str) -> Self {
+        self.token_base_url = url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// The url the user visits to grant access.  `state` is sent back
+    /// untouched with the redirect and should be checked against it.
+    pub fn get_authorization_url(&self, scope: Scope, state: Option<&str>) -> String {
         let scope_ = if scope == Scope::Trading {
             "trading"
         } else {
             "accounts"
         };
-        format!(
-            "https://id.ctrader.com/my/settings/openapi/grantingaccess/?client_id={}&redirect_uri={}&scope={scope_}&product=web",
-            self.client_id, self.redirect_uri
-        )
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
+            ("redirect_uri", self.redirect_uri.as_str()),
+            ("scope", scope_),
+            ("product", "web"),
+        ];
+        if let Some(state) = state {
+            params.push(("state", state));
+        }
+
+        let base = format!("{}/my/settings/openapi/grantingaccess/", self.authorization_base_url);
+        match Url::parse_with_params(&base, &params) {
+            Ok(url) => url.to_string(),
+            // an invalid base url is kept as is so the error shows up when visiting it
+            Err(_) => base,
+        }
     }
 
-    pub async fn get_access_tokens(
-        &self,
-        code: &str,
-    ) -> Result<Tokens, Box<dyn std::error::Error>> {
-        let client = Client::new();
+    fn token_url(&self) -> String {
+        format!("{}/apps/token", self.token_base_url)
+    }
 
+    pub async fn get_access_tokens(&self, code: &str) -> Result<Tokens, AuthError> {
         let params = [
             ("grant_type", "authorization_code"),
             ("code", code),
@@ -43,37 +158,202 @@ impl AuthClient {
             ("client_secret", &self.client_secret),
         ];
 
-        let response = client
-            .post("https://openapi.ctrader.com/apps/token")
-            .form(&params)
-            .header("Accept", "application_json")
-            .send()
-            .await?;
-
-        let token = response.json::<Tokens>().await?;
-
-        Ok(token)
+        self.request_tokens(&params).await
     }
 
-    pub async fn refresh_token(
-        &self,
-        refresh_token: &str,
-    ) -> Result<Tokens, Box<dyn std::error::Error>> {
-        let client = Client::new();
-
+    pub async fn refresh_token(&self, refresh_token: &str) -> Result<Tokens, AuthError> {
         let params = [
             ("grant_type", "refresh_token"),
             ("refresh_token", refresh_token),
             ("client_id", &self.client_id),
             ("client_secret", &self.client_secret),
         ];
-        let response = client
-            .post("https://openapi.ctrader.com/apps/token")
-            .form(&params)
+
+        self.request_tokens(&params).await
+    }
+
+    // only the grant type of the form is logged, the rest are credentials
+    #[tracing::instrument(
+        name = "token_request",
+        skip_all,
+        fields(grant_type = params.iter().find(|(key, _)| *key == "grant_type").map(|(_, value)| *value))
+    )]
+    async fn request_tokens(&self, params: &[(&str, &str)]) -> Result<Tokens, AuthError> {
+        let started = Instant::now();
+        let response = Client::new()
+            .post(self.token_url())
+            .form(params)
+            .header("Accept", "application/json")
             .send()
             .await?;
-        let token = response.json::<Tokens>().await?;
+        let status = response.status().as_u16();
+        let body = response.text().await?;
+        debug!(status, latency_ms = started.elapsed().as_secs_f64() * 1000.0, "token endpoint answered");
 
-        Ok(token)
+        // errors come back as {"errorCode": "...", "description": "..."}
+        if let Ok(TokenErrorBody {
+            error_code: Some(error_code),
+            description,
+        }) = serde_json::from_str(&body)
+        {
+            warn!(error_code = %error_code, description = description.as_deref(), "token request refused");
+            return Err(AuthError::Api {
+                error_code,
+                description,
+            });
+        }
+
+        serde_json::from_str::<Tokens>(&body).map_err(|_| AuthError::InvalidResponse { status, body })
+    }
+
+    /// Load the tokens from `store`, refreshing and saving them first when the
+    /// access token is expired or about to expire.
+    pub async fn fresh_tokens(
+        &self,
+        store: &dyn TokenStore,
+    ) -> Result<Tokens, Box<dyn std::error::Error>> {
+        let tokens = store
+            .load()?
+            .ok_or("the token store is empty, run the authorization flow first")?;
+        if !tokens.expires_within(REFRESH_MARGIN_SECS) {
+            return Ok(tokens);
+        }
+        debug!(remaining_secs = tokens.remaining_secs(), "stored access token about to expire, refreshing");
+
+        let tokens = self.refresh_token(&tokens.refresh_token).await?;
+        store.save(&tokens)?;
+
+        Ok(tokens)
+    }
+
+    /// Run the whole authorization code flow: listen on the port of
+    /// `redirect_uri`, log the authorization url, wait for cTrader to redirect
+    /// the browser back with the code and exchange it for tokens.  The url is
+    /// logged at info level, see `authorize_interactive_with` to show it
+    /// yourself.
+    ///
+    /// The `redirect_uri` must point to this machine, e.g.
+    /// `http://localhost:8080/callback`.
+    pub async fn authorize_interactive(
+        &self,
+        scope: Scope,
+    ) -> Result<Tokens, Box<dyn std::error::Error>> {
+        self.authorize_interactive_with(scope, |url| {
+            tracing::info!(url, "visit the url to authorize the accounts");
+        })
+        .await
+    }
+
+    /// Like `authorize_interactive`, handing the authorization url to
+    /// `show_url`, e.g. to print it or open a browser.
+    pub async fn authorize_interactive_with(
+        &self,
+        scope: Scope,
+        show_url: impl FnOnce(&str),
+    ) -> Result<Tokens, Box<dyn std::error::Error>> {
+        let redirect_uri = Url::parse(&self.redirect_uri)?;
+        let host = redirect_uri
+            .host_str()
+            .ok_or("the redirect uri has no host")?
+            .to_string();
+        let port = redirect_uri
+            .port_or_known_default()
+            .ok_or("the redirect uri has no port")?;
+
+        let listener = TcpListener::bind((host.as_str(), port)).await?;
+
+        let state = random_state()?;
+        let authorization_url = self.get_authorization_url(scope, Some(&state));
+        show_url(&authorization_url);
+
+        let code = tokio::time::timeout(
+            AUTHORIZATION_TIMEOUT,
+            receive_authorization_code(&listener, redirect_uri.path(), &state),
+        )
+        .await
+        .map_err(|_| "timed out waiting for the authorization redirect")??;
+
+        Ok(self.get_access_tokens(&code).await?)
     }
 }
+
+/// Accept connections until the browser hits `path` with `state`, answer it
+/// and return the `code` query parameter.  Requests for other paths or with
+/// another state are answered and ignored.
+async fn receive_authorization_code(
+    listener: &TcpListener,
+    path: &str,
+    state: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+
+        // only the request line matters: GET /callback?code=..&state=.. HTTP/1.1
+        let mut request_line = String::new();
+        let read = tokio::time::timeout(REQUEST_TIMEOUT, BufReader::new(&mut socket).read_line(&mut request_line)).await;
+        if !matches!(read, Ok(Ok(_))) {
+            debug!("dropped a connection that sent no request");
+            continue;
+        }
+        let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+        let Ok(url) = Url::parse("http://localhost").and_then(|base| base.join(target)) else {
+            let _ = respond(&mut socket, "400 Bad Request", "Bad request").await;
+            continue;
+        };
+
+        if url.path() != path {
+            // e.g. the browser asking for /favicon.ico
+            let _ = respond(&mut socket, "404 Not Found", "Not found").await;
+            continue;
+        }
+
+        let query_param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+
+        if query_param("state").as_deref() != Some(state) {
+            // not the redirect of this flow, e.g. a stale tab or a forged link
+            warn!("ignored a redirect with an unexpected state");
+            let _ = respond(&mut socket, "400 Bad Request", "Invalid state, you can close this window.").await;
+            continue;
+        }
+
+        if let Some(error) = query_param("error") {
+            respond(&mut socket, "400 Bad Request", "Authorization failed, you can close this window.").await?;
+            let description = query_param("error_description").unwrap_or_default();
+            return Err(format!("authorization denied: {error} {description}").trim_end().into());
+        }
+
+        let Some(code) = query_param("code") else {
+            respond(&mut socket, "400 Bad Request", "No authorization code received.").await?;
+            return Err("the redirect did not contain an authorization code".into());
+        };
+
+        respond(&mut socket, "200 OK", "Authorization complete, you can close this window.").await?;
+        return Ok(code);
+    }
+}
+
+async fn respond(
+    socket: &mut tokio::net::TcpStream,
+    status: &str,
+    body: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+// unguessable value for the oauth `state` parameter, 128 bits from the
+// operating system's random number generator
+fn random_state() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
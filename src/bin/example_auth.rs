@@ -1,4 +1,4 @@
-use rust_ctrader::{AuthClient, Scope};
+use rust_ctrader::{AuthClient, FileTokenStore, Scope, TokenStore};
 use std::{env, io::stdin};
 use dotenv::dotenv;
 
@@ -13,11 +13,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
     let client = AuthClient::new(&client_id, &client_secret, &redirect_uri);
     let scope = Scope::Trading;
 
-    let mut code_buf = String::new();
+    //with a local redirect uri (e.g. http://localhost:8080/callback) the client catches the
+    //redirect itself, otherwise the code has to be copied from the redirected page
+    let tokens = if redirect_uri.starts_with("http://localhost") || redirect_uri.starts_with("http://127.0.0.1") {
+        client
+            .authorize_interactive_with(scope, |url| {
+                println!("visit the url below to authorize the accounts:\n {}", url)
+            })
+            .await?
+    } else {
+        let mut code_buf = String::new();
 
-    //getting the authorization url to visit so as to get the code which will be used in gettting access tokens 
+        //getting the authorization url to visit so as to get the code which will be used in gettting access tokens 
 
-        let authorization_url = client.get_authorization_url(scope);
+        let authorization_url = client.get_authorization_url(scope, None);
 
         println!("visit the url below to authorize the accounts and get the authorization code:\n {}", authorization_url);
 
@@ -28,17 +37,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
         println!("\n====================\n");
         println!("The code recieved is: {}", code_buf.trim());
 
-    //now lets get the access tokens
-
-    let tokens = client.get_access_tokens(&code_buf.trim()).await?;
+        //now lets get the access tokens
+        client.get_access_tokens(code_buf.trim()).await?
+    };
 
     println!("Got the access tokens: {:#?}", tokens);
-    //after printing the access tokens you can now copy and paste the access tokens that is the access_token and the refresh token in the .env file or just export them in your environment 
 
-    //now lets try to refresh our token (this is done after you have set the refresh token in the .env file or your environment variables )
-    //let refresh_token = env::var("refresh_token")?;
+    //save the tokens (readable only by you) so the example client can pick them up,
+    //the file defaults to tokens.json and can be changed with the token_store variable
+    let store = FileTokenStore::new(env::var("token_store").unwrap_or(String::from("tokens.json")));
+    store.save(&tokens)?;
+    println!("Tokens saved to {}", store.path().display());
 
-    //let refreshed_tokens = client.refresh_token(&refresh_token).await?;
+    //now lets try to refresh our token, the stored tokens are only refreshed when they are about to expire
+    //let refreshed_tokens = client.fresh_tokens(&store).await?;
 
     //println!("the refreshed tokens are: {:#?}", refreshed_tokens);
 
@@ -1,7 +1,9 @@
 // This is an example client that demonstrates how to use the CtraderClient 
 //to connect to the cTrader Open API, authorize an application and account, 
 // Example usage:
-// 1. Add a .env file with the following variables: client_id, client_secret, access_token.
+// 1. Add a .env file with the following variables: client_id, client_secret, redirect_uri
+//    and run `cargo run --bin example_auth` once to save the tokens into tokens.json
+//    (or set access_token in the .env file).
 // 2. Choose the API endpoint when connecting (Endpoint::Demo or Endpoint::Live).
 // 3. Call CtraderClient::connect(client_id, client_secret, access_token, endpoint).await
 //    which returns (client, event_rx).
@@ -15,19 +17,19 @@
 //    - StreamEvent::TrendbarsData: handle received trend bars.
 //    - StreamEvent::Error: handle error messages.
 // 7. Notes:
-//    - account_id must be replaced with a valid account ID returned in AccountsData (the example stores it from accounts[1].id).
+//    - account_id must be replaced with a valid account ID returned in AccountsData (the example picks the first demo account with trading access).
 //    - Timestamps for get_trend_bar_data are in milliseconds since UNIX epoch.
 //    - Use the chrono crate (Utc, Duration) to compute time ranges (as shown below).
-            /// for the date range I am yet to work on the easiest way 
-            /// to get the timestamp from date string a user will enter not 
-            /// just entering the timestamp directly. I would like to make it 
-            /// user friendly by allowing date strings like (2025, 12, 15).
+//          for the date range I am yet to work on the easiest way 
+//          to get the timestamp from date string a user will enter not 
+//          just entering the timestamp directly. I would like to make it 
+//          user friendly by allowing date strings like (2025, 12, 15).
 //    - The client emits other events; match on StreamEvent to handle them as needed.
 // 8. Run the binary with `cargo run --bin example_client` after populating .env.
 
 use dotenv::dotenv;
-use rust_ctrader::{CtraderClient, Endpoint, StreamEvent, TimeFrame, types::{Signal, Position}, strategies::{self,  moving_average_strategy::{Ema}}};
-use std::env;
+use rust_ctrader::{AccountFilter, AuthClient, FileTokenStore, CtraderClient, Endpoint, ReconnectPolicy, StreamEvent, TimeFrame, types::{Signal, Position}, OrderSize, strategies::{self,  moving_average_strategy::{Ema}}};
+use std::{env, sync::Arc};
 
 
 
@@ -39,15 +41,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
 
     let client_id = env::var("client_id")?;
     let client_secret = env::var("client_secret")?;
-    let access_token = env::var("access_token")?;
+    let redirect_uri = env::var("redirect_uri").unwrap_or_default();
+
+    //use the tokens saved by example_auth, refreshed if they are about to expire
+    let store = Arc::new(FileTokenStore::new(env::var("token_store").unwrap_or(String::from("tokens.json"))));
+    let auth_client = AuthClient::new(&client_id, &client_secret, &redirect_uri);
+    let access_token = match auth_client.fresh_tokens(store.as_ref()).await {
+        Ok(tokens) => tokens.access_token,
+        Err(_) => env::var("access_token")?,
+    };
 
 
     println!("Connecting to cTrader Open API...");
 
-    let (mut client, mut event_rx) = CtraderClient::connect(&client_id, &client_secret, &access_token, Endpoint::Demo).await?;
+    //the builder also reads a config file (CtraderClientBuilder::from_file("ctrader.toml"))
+    //or CTRADER_* environment variables (CtraderClientBuilder::from_env())
+    let (client, mut event_rx) = CtraderClient::builder()
+        .credentials(&client_id, &client_secret, &access_token)
+        .endpoint(Endpoint::Demo)
+        .reconnect(Some(ReconnectPolicy::default()))
+        .build()
+        .await?;
 
     client.start().await;
 
+    //keep the stored tokens refreshed while the client runs
+    if let Ok(_refresher) = client.attach_token_store(store).await {
+        println!("Token store attached, the access token will be refreshed automatically.");
+    }
+
     client.authorize_application().await?;
 
     let short_period: usize = 2_usize;
@@ -77,8 +99,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
             }
             StreamEvent::AccountsData(accounts) => {
                 println!("Accounts data received: {:#?}", accounts);
-                account_id = accounts[0].id as i64; //select the first account for authorization
-                client.authorize_account(accounts[0].id as i64).await?; 
+                //select the first demo account with trading access for authorization
+                let demo_accounts = AccountFilter::new().demo_only().trading_only().apply(&accounts);
+                let Some(account) = demo_accounts.first() else {
+                    println!("No demo account with trading access found.");
+                    break;
+                };
+                account_id = account.id as i64;
+                //a handle bound to the account, so the id is not repeated on every call
+                client.account(account_id).authorize().await?; 
             }
             StreamEvent::AccountAuthorized(msg) => {
                 println!("Account authorized event received: {}", msg);
@@ -88,7 +117,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
                 // }           
                 //requesting trend bar data for the last week for the selected symbol client.get_trend_bar_data(symbols[4].symbol_id as i64, TimeFrame::M1, account_id, from_timestamp, to_timestamp).await?;
                 
-                client.subscribe_live_bars(account_id, 41 as i64, TimeFrame::M1).await?;
+                client.account(account_id).subscribe_live_bars(41_i64, TimeFrame::M1).await?;
 
                 println!("Subscribed to live bars for symbol ID 41 on account ID {}.", account_id);
 
@@ -102,8 +131,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
                 use chrono::{Utc, Datelike, Duration};
                 let now = Utc::now();
                 let last_monday = now - Duration::days((now.weekday().num_days_from_monday() + 7) as i64);
-                let from_timestamp = last_monday.timestamp_millis();
-                let to_timestamp = now.timestamp_millis();
+                let _from_timestamp = last_monday.timestamp_millis();
+                let _to_timestamp = now.timestamp_millis();
 
                 //client.get_symbol_by_id(account_id, symbols[0].symbol_id as i64).await?;
 
@@ -118,7 +147,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
 
             }
             
-            StreamEvent::LiveData((quote, bar_data, last_closed_candle)) => {
+            StreamEvent::LiveData((_quote, _bar_data, last_closed_candle)) => {
                 //on last closed bar receiving to calculate the ema 
                 let close_price = match &last_closed_candle{
                     
@@ -144,7 +173,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
                 //placing a trade accorging to the signal genrated 
                 if signal != Signal::Hold{
                     println!("Signal generated: {:?}. Attempting to take a trade...", signal);
-                    strategies::take_a_trade(&mut client.clone(), account_id, signal, &mut in_position, &mut prev_signal, &mut positions).await?;
+                    strategies::take_a_trade(&mut client.clone(), account_id, OrderSize::Lots(0.05), signal, &mut in_position, &mut prev_signal, &mut positions).await?;
                 }
 
             }
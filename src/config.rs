@@ -0,0 +1,487 @@
+// this is the typed client configuration.  everything `CtraderClientBuilder`
+// can be told is also readable from a TOML or JSON file and from `CTRADER_*`
+// environment variables, so deployments describe the connection in one place
+// instead of wiring credentials and options by hand.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::ctrader::codec::DEFAULT_MAX_FRAME_SIZE;
+use crate::rate_limit::RateLimits;
+use crate::types::Endpoint;
+use crate::utilities::REDACTED;
+use crate::version::VersionPolicy;
+
+/// How a dropped connection is re-established.  Attempts are spaced by a
+/// delay starting at `initial_delay_ms` and doubling up to `max_delay_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_delay_ms: 1_000,
+            max_delay_ms: 60_000,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before the given attempt (starting at 1).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2_u64.saturating_pow(attempt.saturating_sub(1));
+        Duration::from_millis(self.initial_delay_ms.saturating_mul(factor).min(self.max_delay_ms))
+    }
+}
+
+/// Which built-in transport the client uses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// length prefixed protobuf on port 5035
+    #[default]
+    Protobuf,
+    /// json over a websocket on port 5036
+    WebSocket,
+}
+
+/// The settings of a `CtraderClientBuilder`.
+///
+/// In TOML:
+///
+/// ```toml
+/// client_id = "..."
+/// client_secret = "..."
+/// access_token = "..."
+/// endpoint = "demo"            # "live", or [endpoint.custom] host = "..." port = 5035
+/// proxy = "socks5://proxy.internal:1080"
+/// heartbeat_interval_secs = 10
+/// version_check = "warn"       # "off" or "refuse"
+/// metrics_addr = "127.0.0.1:9184"
+/// log_messages = true
+///
+/// [rate_limits]
+/// requests_per_sec = 40
+///
+/// [reconnect]
+/// max_attempts = 5
+/// ```
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CtraderConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub endpoint: Endpoint,
+    pub transport: TransportKind,
+    /// `http://` or `socks5://` proxy url
+    pub proxy: Option<String>,
+    /// connect without TLS, for local mock servers only
+    pub plain_tcp: bool,
+    /// PEM file with the only roots to trust instead of the webpki ones
+    pub ca_file: Option<PathBuf>,
+    pub connect_timeout_secs: u64,
+    pub handshake_timeout_secs: u64,
+    /// limit for writing one request to the connection, no limit when unset
+    pub write_timeout_secs: Option<u64>,
+    /// send a heartbeat this often, never when unset
+    pub heartbeat_interval_secs: Option<u64>,
+    pub event_channel_capacity: usize,
+    pub account_channel_capacity: usize,
+    /// largest message accepted from the server, in bytes
+    pub max_frame_size: usize,
+    /// reconnect after the connection drops, never when unset
+    pub reconnect: Option<ReconnectPolicy>,
+    /// throttle requests on the client, not at all when unset
+    pub rate_limits: Option<RateLimits>,
+    /// record every message sent and received to this file
+    pub record_file: Option<PathBuf>,
+    /// compare the server version with the compiled messages on connect
+    pub version_check: VersionPolicy,
+    /// serve the client metrics for prometheus on this address
+    pub metrics_addr: Option<SocketAddr>,
+    /// log every message sent and received at info level instead of debug
+    pub log_messages: bool,
+}
+
+impl Default for CtraderConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            access_token: String::new(),
+            refresh_token: None,
+            endpoint: Endpoint::Demo,
+            transport: TransportKind::Protobuf,
+            proxy: None,
+            plain_tcp: false,
+            ca_file: None,
+            connect_timeout_secs: 10,
+            handshake_timeout_secs: 10,
+            write_timeout_secs: None,
+            heartbeat_interval_secs: Some(10),
+            event_channel_capacity: 100,
+            account_channel_capacity: 100,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            reconnect: None,
+            rate_limits: Some(RateLimits::default()),
+            record_file: None,
+            version_check: VersionPolicy::Off,
+            metrics_addr: None,
+            log_messages: false,
+        }
+    }
+}
+
+impl std::fmt::Debug for CtraderConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CtraderConfig")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &REDACTED)
+            .field("access_token", &REDACTED)
+            .field("refresh_token", &self.refresh_token.as_ref().map(|_| REDACTED))
+            .field("endpoint", &self.endpoint)
+            .field("transport", &self.transport)
+            // the url may carry the proxy password
+            .field("proxy", &self.proxy.as_ref().map(|_| REDACTED))
+            .field("plain_tcp", &self.plain_tcp)
+            .field("ca_file", &self.ca_file)
+            .field("connect_timeout_secs", &self.connect_timeout_secs)
+            .field("handshake_timeout_secs", &self.handshake_timeout_secs)
+            .field("write_timeout_secs", &self.write_timeout_secs)
+            .field("heartbeat_interval_secs", &self.heartbeat_interval_secs)
+            .field("event_channel_capacity", &self.event_channel_capacity)
+            .field("account_channel_capacity", &self.account_channel_capacity)
+            .field("max_frame_size", &self.max_frame_size)
+            .field("reconnect", &self.reconnect)
+            .field("rate_limits", &self.rate_limits)
+            .field("record_file", &self.record_file)
+            .field("version_check", &self.version_check)
+            .field("metrics_addr", &self.metrics_addr)
+            .field("log_messages", &self.log_messages)
+            .finish()
+    }
+}
+
+impl CtraderConfig {
+    /// Read a `.toml` or `.json` file; other extensions are parsed as TOML.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+            Ok(serde_json::from_str(&content)?)
+        } else {
+            Ok(toml::from_str(&content)?)
+        }
+    }
+
+    /// The defaults overridden by the environment, see `apply_env`.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = Self::default();
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Override the settings present in the environment:
+    /// `CTRADER_CLIENT_ID`, `CTRADER_CLIENT_SECRET`, `CTRADER_ACCESS_TOKEN`,
+    /// `CTRADER_REFRESH_TOKEN`, `CTRADER_ENDPOINT` (`demo`, `live`, `host:port`
+    /// or `[ipv6]:port`), `CTRADER_TRANSPORT` (`protobuf` or `websocket`),
+    /// `CTRADER_PROXY`, `CTRADER_PLAIN_TCP`, `CTRADER_CA_FILE`,
+    /// `CTRADER_CONNECT_TIMEOUT_SECS`, `CTRADER_HANDSHAKE_TIMEOUT_SECS`,
+    /// `CTRADER_WRITE_TIMEOUT_SECS`, `CTRADER_HEARTBEAT_INTERVAL_SECS`,
+    /// `CTRADER_EVENT_CHANNEL_CAPACITY`, `CTRADER_ACCOUNT_CHANNEL_CAPACITY`,
+    /// `CTRADER_MAX_FRAME_SIZE`,
+    /// `CTRADER_RECONNECT_MAX_ATTEMPTS` (0 disables reconnecting),
+    /// `CTRADER_RATE_LIMIT_PER_SEC` (0 disables rate limiting),
+    /// `CTRADER_HISTORICAL_RATE_LIMIT_PER_SEC`, `CTRADER_RECORD_FILE`,
+    /// `CTRADER_VERSION_CHECK` (`off`, `warn` or `refuse`),
+    /// `CTRADER_METRICS_ADDR` and `CTRADER_LOG_MESSAGES`.
+    pub fn apply_env(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(value) = env_var("CLIENT_ID") {
+            self.client_id = value;
+        }
+        if let Some(value) = env_var("CLIENT_SECRET") {
+            self.client_secret = value;
+        }
+        if let Some(value) = env_var("ACCESS_TOKEN") {
+            self.access_token = value;
+        }
+        if let Some(value) = env_var("REFRESH_TOKEN") {
+            self.refresh_token = Some(value);
+        }
+        if let Some(value) = env_var("ENDPOINT") {
+            self.endpoint = parse_endpoint(&value)?;
+        }
+        if let Some(value) = env_var("TRANSPORT") {
+            self.transport = match value.trim().to_ascii_lowercase().as_str() {
+                "protobuf" => TransportKind::Protobuf,
+                "websocket" => TransportKind::WebSocket,
+                _ => return Err(format!("invalid value for CTRADER_TRANSPORT: {}", value).into()),
+            };
+        }
+        if let Some(value) = env_var("PROXY") {
+            self.proxy = Some(value);
+        }
+        if let Some(value) = env_var("PLAIN_TCP") {
+            self.plain_tcp = parse_env("PLAIN_TCP", &value)?;
+        }
+        if let Some(value) = env_var("CA_FILE") {
+            self.ca_file = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env_var("CONNECT_TIMEOUT_SECS") {
+            self.connect_timeout_secs = parse_env("CONNECT_TIMEOUT_SECS", &value)?;
+        }
+        if let Some(value) = env_var("HANDSHAKE_TIMEOUT_SECS") {
+            self.handshake_timeout_secs = parse_env("HANDSHAKE_TIMEOUT_SECS", &value)?;
+        }
+        if let Some(value) = env_var("WRITE_TIMEOUT_SECS") {
+            self.write_timeout_secs = Some(parse_env("WRITE_TIMEOUT_SECS", &value)?);
+        }
+        if let Some(value) = env_var("HEARTBEAT_INTERVAL_SECS") {
+            self.heartbeat_interval_secs = Some(parse_env("HEARTBEAT_INTERVAL_SECS", &value)?);
+        }
+        if let Some(value) = env_var("EVENT_CHANNEL_CAPACITY") {
+            self.event_channel_capacity = parse_env("EVENT_CHANNEL_CAPACITY", &value)?;
+        }
+        if let Some(value) = env_var("ACCOUNT_CHANNEL_CAPACITY") {
+            self.account_channel_capacity = parse_env("ACCOUNT_CHANNEL_CAPACITY", &value)?;
+        }
+        if let Some(value) = env_var("MAX_FRAME_SIZE") {
+            self.max_frame_size = parse_env("MAX_FRAME_SIZE", &value)?;
+        }
+        if let Some(value) = env_var("RECONNECT_MAX_ATTEMPTS") {
+            let max_attempts: u32 = parse_env("RECONNECT_MAX_ATTEMPTS", &value)?;
+            self.reconnect = (max_attempts > 0).then(|| ReconnectPolicy {
+                max_attempts,
+                ..self.reconnect.unwrap_or_default()
+            });
+        }
+        if let Some(value) = env_var("RATE_LIMIT_PER_SEC") {
+            let requests_per_sec: f64 = parse_env("RATE_LIMIT_PER_SEC", &value)?;
+            self.rate_limits = (requests_per_sec > 0.0).then(|| RateLimits {
+                requests_per_sec,
+                ..self.rate_limits.unwrap_or_default()
+            });
+        }
+        if let Some(value) = env_var("HISTORICAL_RATE_LIMIT_PER_SEC") {
+            let historical_per_sec = parse_env("HISTORICAL_RATE_LIMIT_PER_SEC", &value)?;
+            if let Some(rate_limits) = &mut self.rate_limits {
+                rate_limits.historical_per_sec = historical_per_sec;
+            }
+        }
+        if let Some(value) = env_var("RECORD_FILE") {
+            self.record_file = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env_var("VERSION_CHECK") {
+            self.version_check = match value.trim().to_ascii_lowercase().as_str() {
+                "off" => VersionPolicy::Off,
+                "warn" => VersionPolicy::Warn,
+                "refuse" => VersionPolicy::Refuse,
+                _ => return Err(format!("invalid value for CTRADER_VERSION_CHECK: {}", value).into()),
+            };
+        }
+        if let Some(value) = env_var("METRICS_ADDR") {
+            self.metrics_addr = Some(parse_env("METRICS_ADDR", &value)?);
+        }
+        if let Some(value) = env_var("LOG_MESSAGES") {
+            self.log_messages = parse_env("LOG_MESSAGES", &value)?;
+        }
+        Ok(())
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(format!("CTRADER_{}", name))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, Box<dyn std::error::Error>> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid value for CTRADER_{}: {}", name, value).into())
+}
+
+fn parse_endpoint(value: &str) -> Result<Endpoint, Box<dyn std::error::Error>> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "demo" => Ok(Endpoint::Demo),
+        "live" => Ok(Endpoint::Live),
+        _ => {
+            let invalid = || format!("invalid value for CTRADER_ENDPOINT: {}", value);
+            let value = value.trim();
+            // an IPv6 host is written in brackets, `[::1]:5035`
+            let (host, port) = match value.strip_prefix('[') {
+                Some(rest) => rest.split_once("]:").ok_or_else(invalid)?,
+                None => value.rsplit_once(':').filter(|(host, _)| !host.contains(':')).ok_or_else(invalid)?,
+            };
+            if host.is_empty() {
+                return Err(invalid().into());
+            }
+            Ok(Endpoint::Custom {
+                host: host.to_string(),
+                port: parse_env("ENDPOINT", port)?,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str, extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_ctrader_{}_{}.{}", name, std::process::id(), extension))
+    }
+
+    #[test]
+    fn reads_a_toml_file() {
+        let path = temp_path("config", "toml");
+        std::fs::write(
+            &path,
+            r#"
+client_id = "id"
+client_secret = "secret"
+access_token = "token"
+proxy = "socks5://proxy.internal:1080"
+heartbeat_interval_secs = 30
+version_check = "refuse"
+
+[endpoint.custom]
+host = "localhost"
+port = 5036
+
+[reconnect]
+max_attempts = 3
+"#,
+        )
+        .unwrap();
+        let config = CtraderConfig::from_file(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(config.client_id, "id");
+        assert_eq!(config.client_secret, "secret");
+        assert_eq!(config.access_token, "token");
+        assert_eq!(config.proxy.as_deref(), Some("socks5://proxy.internal:1080"));
+        assert_eq!(config.heartbeat_interval_secs, Some(30));
+        assert_eq!(config.version_check, VersionPolicy::Refuse);
+        assert_eq!(
+            config.endpoint,
+            Endpoint::Custom {
+                host: "localhost".to_string(),
+                port: 5036
+            }
+        );
+        // the rest of a section and of the file keeps the defaults
+        let reconnect = config.reconnect.unwrap();
+        assert_eq!(reconnect.max_attempts, 3);
+        assert_eq!(reconnect.initial_delay_ms, ReconnectPolicy::default().initial_delay_ms);
+        assert_eq!(config.event_channel_capacity, 100);
+    }
+
+    #[test]
+    fn reads_a_json_file() {
+        let path = temp_path("config", "json");
+        std::fs::write(
+            &path,
+            r#"{"client_id": "id", "access_token": "token", "endpoint": "live", "plain_tcp": true}"#,
+        )
+        .unwrap();
+        let config = CtraderConfig::from_file(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(config.client_id, "id");
+        assert_eq!(config.access_token, "token");
+        assert_eq!(config.endpoint, Endpoint::Live);
+        assert!(config.plain_tcp);
+        assert_eq!(config.heartbeat_interval_secs, Some(10));
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let mut config = CtraderConfig {
+            client_id: "from file".to_string(),
+            client_secret: "from file".to_string(),
+            ..Default::default()
+        };
+        // the only test touching the CTRADER_ variables, so none races it
+        unsafe {
+            std::env::set_var("CTRADER_CLIENT_ID", "from env");
+            std::env::set_var("CTRADER_ENDPOINT", "[::1]:5036");
+            std::env::set_var("CTRADER_HEARTBEAT_INTERVAL_SECS", "20");
+            std::env::set_var("CTRADER_RECONNECT_MAX_ATTEMPTS", "4");
+            std::env::set_var("CTRADER_RATE_LIMIT_PER_SEC", "0");
+            std::env::set_var("CTRADER_VERSION_CHECK", "Warn");
+            // empty values are ignored
+            std::env::set_var("CTRADER_CLIENT_SECRET", "");
+        }
+        let applied = config.apply_env();
+
+        unsafe { std::env::set_var("CTRADER_PLAIN_TCP", "maybe") };
+        let invalid = CtraderConfig::default().apply_env().map(|_| ()).map_err(|e| e.to_string());
+        for name in [
+            "CLIENT_ID",
+            "ENDPOINT",
+            "HEARTBEAT_INTERVAL_SECS",
+            "RECONNECT_MAX_ATTEMPTS",
+            "RATE_LIMIT_PER_SEC",
+            "VERSION_CHECK",
+            "CLIENT_SECRET",
+            "PLAIN_TCP",
+        ] {
+            unsafe { std::env::remove_var(format!("CTRADER_{}", name)) };
+        }
+
+        applied.unwrap();
+        assert_eq!(config.client_id, "from env");
+        assert_eq!(config.client_secret, "from file");
+        assert_eq!(
+            config.endpoint,
+            Endpoint::Custom {
+                host: "::1".to_string(),
+                port: 5036
+            }
+        );
+        assert_eq!(config.heartbeat_interval_secs, Some(20));
+        assert_eq!(config.reconnect.map(|policy| policy.max_attempts), Some(4));
+        assert!(config.rate_limits.is_none());
+        assert_eq!(config.version_check, VersionPolicy::Warn);
+        assert_eq!(invalid, Err("invalid value for CTRADER_PLAIN_TCP: maybe".to_string()));
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        let custom = |host: &str, port| Endpoint::Custom {
+            host: host.to_string(),
+            port,
+        };
+        let cases = [
+            ("demo", Some(Endpoint::Demo)),
+            (" LIVE ", Some(Endpoint::Live)),
+            ("localhost:5036", Some(custom("localhost", 5036))),
+            ("10.0.0.1:5035", Some(custom("10.0.0.1", 5035))),
+            ("[::1]:5035", Some(custom("::1", 5035))),
+            ("[fe80::1%eth0]:5035", Some(custom("fe80::1%eth0", 5035))),
+            ("localhost:port", None),
+            ("localhost:70000", None),
+            ("localhost", None),
+            (":5035", None),
+            ("::1:5035", None),
+            ("[::1]", None),
+            ("[::1]5035", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_endpoint(value).ok(), expected, "{}", value);
+        }
+    }
+}
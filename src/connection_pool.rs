@@ -0,0 +1,233 @@
+// this is the demo + live connection pool.  a cTrader connection only serves
+// the accounts of its own environment, so teams trading demo and live
+// accounts side by side need two `CtraderClient`s.  the pool opens both,
+// learns from every `AccountsData` which account lives where and forwards
+// orders, subscriptions and account handles to the right connection.  the
+// events of both connections are merged into one stream tagged with their
+// origin.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, mpsc};
+
+use crate::ctrader::{AccountHandle, ClientTasks, CtraderClient, CtraderClientBuilder, ShutdownOptions};
+use crate::types::{Account, AccountFilter, Endpoint, Order, StreamEvent, TimeFrame};
+
+/// An event of one of the pooled connections.
+#[derive(Debug)]
+pub struct PoolEvent {
+    /// `true` when the event comes from the live connection
+    pub is_live: bool,
+    pub event: StreamEvent,
+}
+
+pub struct ConnectionPool {
+    demo: Arc<CtraderClient>,
+    live: Arc<CtraderClient>,
+    // account id -> is_live, filled from the AccountsData of both connections
+    routes: Arc<Mutex<HashMap<i64, bool>>>,
+}
+
+impl ConnectionPool {
+    /// Open a connection to the demo and the live endpoint with the same
+    /// application and access token and the default builder settings.
+    pub async fn connect(
+        client_id: &str,
+        client_secret: &str,
+        access_token: &str,
+    ) -> Result<(Arc<Self>, mpsc::Receiver<PoolEvent>), Box<dyn std::error::Error>> {
+        Self::from_builder(CtraderClient::builder().credentials(client_id, client_secret, access_token)).await
+    }
+
+    /// Open a connection to the demo and the live endpoint, both with the
+    /// settings of `builder`; its endpoint is ignored.  Only the demo
+    /// connection serves the metrics, and a recording of the live one goes to
+    /// a file of its own (`session.rec` becomes `session.live.rec`).
+    pub async fn from_builder(
+        builder: CtraderClientBuilder,
+    ) -> Result<(Arc<Self>, mpsc::Receiver<PoolEvent>), Box<dyn std::error::Error>> {
+        let live = builder.clone().live_half();
+        Self::from_builders(builder.endpoint(Endpoint::Demo), live).await
+    }
+
+    /// Open the demo and the live connection from a builder each, e.g. to
+    /// give them different endpoints or settings.
+    pub async fn from_builders(
+        demo: CtraderClientBuilder,
+        live: CtraderClientBuilder,
+    ) -> Result<(Arc<Self>, mpsc::Receiver<PoolEvent>), Box<dyn std::error::Error>> {
+        let (demo, live) = tokio::try_join!(demo.build(), live.build())?;
+        Ok(Self::from_clients(demo, live))
+    }
+
+    /// Pool two already connected clients, e.g. built with custom endpoints.
+    /// The merged event channel is as large as the larger of their
+    /// `event_channel_capacity` settings.
+    pub fn from_clients(
+        (demo, demo_rx): (Arc<CtraderClient>, mpsc::Receiver<StreamEvent>),
+        (live, live_rx): (Arc<CtraderClient>, mpsc::Receiver<StreamEvent>),
+    ) -> (Arc<Self>, mpsc::Receiver<PoolEvent>) {
+        let capacity = demo.event_channel_capacity().max(live.event_channel_capacity());
+        let (event_tx, event_rx) = mpsc::channel(capacity);
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+
+        forward_events(demo_rx, false, event_tx.clone(), Arc::clone(&routes));
+        forward_events(live_rx, true, event_tx, Arc::clone(&routes));
+
+        (Arc::new(Self { demo, live, routes }), event_rx)
+    }
+
+    pub fn demo(&self) -> &Arc<CtraderClient> {
+        &self.demo
+    }
+
+    pub fn live(&self) -> &Arc<CtraderClient> {
+        &self.live
+    }
+
+    /// Start the read loops of both connections, returning the tasks of the
+    /// demo and the live one.
+    pub async fn start(&self) -> (ClientTasks, ClientTasks) {
+        (self.demo.start().await, self.live.start().await)
+    }
+
+    /// Shut both connections down, see `CtraderClient::shutdown_with`.  The
+    /// live one is shut down even when the demo one fails.
+    pub async fn shutdown(&self, options: ShutdownOptions) -> Result<(), Box<dyn std::error::Error>> {
+        let demo = self.demo.shutdown_with(options).await;
+        let live = self.live.shutdown_with(options).await;
+        demo.and(live)
+    }
+
+    /// Authorize the application on both connections; each answers with its
+    /// own `StreamEvent::ApplicationAuthorized`.
+    pub async fn authorize_application(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.demo.authorize_application().await?;
+        self.live.authorize_application().await?;
+        Ok(())
+    }
+
+    pub async fn keep_alive(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.demo.keep_alive().await?;
+        self.live.keep_alive().await?;
+        Ok(())
+    }
+
+    /// Request the accounts of the access token.  The list is the same on
+    /// both endpoints, so it is only asked on the demo connection; the
+    /// answer also teaches the pool where every account lives.
+    pub async fn get_accounts(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.demo.get_accounts().await
+    }
+
+    /// The accounts of the last `get_accounts` answer matching `filter`.
+    pub async fn accounts(&self, filter: &AccountFilter) -> Vec<Account> {
+        self.demo.accounts(filter).await
+    }
+
+    /// Route an account by hand, for accounts known without `get_accounts`.
+    pub async fn set_route(&self, account_id: i64, is_live: bool) {
+        self.routes.lock().await.insert(account_id, is_live);
+    }
+
+    /// The connection serving `account_id`, `None` until the account was
+    /// seen in an `AccountsData` or routed with `set_route`.
+    pub async fn client_for(&self, account_id: i64) -> Option<&Arc<CtraderClient>> {
+        let is_live = *self.routes.lock().await.get(&account_id)?;
+        Some(if is_live { &self.live } else { &self.demo })
+    }
+
+    async fn routed_client(&self, account_id: i64) -> Result<&Arc<CtraderClient>, Box<dyn std::error::Error>> {
+        self.client_for(account_id)
+            .await
+            .ok_or_else(|| format!("account {} is not known, call get_accounts first", account_id).into())
+    }
+
+    /// A handle on `account_id` bound to the connection of its environment.
+    pub async fn account(&self, account_id: i64) -> Result<AccountHandle, Box<dyn std::error::Error>> {
+        Ok(self.routed_client(account_id).await?.account(account_id))
+    }
+
+    pub async fn authorize_accounts(&self, account_ids: &[i64]) -> Result<(), Box<dyn std::error::Error>> {
+        for account_id in account_ids {
+            self.routed_client(*account_id).await?.authorize_account(*account_id).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn new_order(&self, order: Order) -> Result<(), Box<dyn std::error::Error>> {
+        self.routed_client(order.account_id as i64).await?.new_order(order).await
+    }
+
+    pub async fn close_position(
+        &self,
+        account_id: i64,
+        position_id: i64,
+        volume: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.routed_client(account_id)
+            .await?
+            .close_position(account_id, position_id, volume)
+            .await
+    }
+
+    pub async fn cancel_order(&self, account_id: i64, order_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.routed_client(account_id).await?.cancel_order(account_id, order_id).await
+    }
+
+    pub async fn subscribe_spot(&self, account_id: i64, symbol_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.routed_client(account_id).await?.subscribe_spot(account_id, symbol_id).await
+    }
+
+    pub async fn subscribe_live_bars(
+        &self,
+        account_id: i64,
+        symbol_id: i64,
+        timeframe: TimeFrame,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.routed_client(account_id)
+            .await?
+            .subscribe_live_bars(account_id, symbol_id, timeframe)
+            .await
+    }
+
+    pub async fn unsubscribe_spot(&self, account_id: i64, symbol_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.routed_client(account_id).await?.unsubscribe_spot(account_id, symbol_id).await
+    }
+
+    pub async fn unsubscribe_live_bars(
+        &self,
+        account_id: i64,
+        symbol_id: i64,
+        timeframe: TimeFrame,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.routed_client(account_id)
+            .await?
+            .unsubscribe_live_bars(account_id, symbol_id, timeframe)
+            .await
+    }
+}
+
+// moves the events of one connection to the pool stream, recording the
+// environment of every account listed on the way
+fn forward_events(
+    mut rx: mpsc::Receiver<StreamEvent>,
+    is_live: bool,
+    tx: mpsc::Sender<PoolEvent>,
+    routes: Arc<Mutex<HashMap<i64, bool>>>,
+) {
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let StreamEvent::AccountsData(accounts) = &event {
+                let mut routes = routes.lock().await;
+                for account in accounts {
+                    routes.insert(account.id as i64, account.is_live);
+                }
+            }
+            if tx.send(PoolEvent { is_live, event }).await.is_err() {
+                break;
+            }
+        }
+    });
+}
@@ -0,0 +1,345 @@
+// this is the local pnl / pip value engine.  it keeps the assets, the light
+// symbol metadata (base/quote asset), the conversion chains returned by the
+// ProtoOASymbolsForConversionReq and the latest spot prices of the symbols in
+// those chains, so that any amount denominated in a symbol's quote currency
+// can be expressed in the account deposit currency.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::types::{Asset, Position, Quote, SymbolData, TradeSide};
+
+/// One symbol of a conversion chain together with the assets it converts
+/// between.
+#[derive(Debug, Clone)]
+pub struct ConversionLeg {
+    pub symbol_id: i64,
+    pub base_asset_id: i64,
+    pub quote_asset_id: i64,
+}
+
+/// The ordered list of symbols needed to convert `from_asset_id` into
+/// `to_asset_id` (e.g. EUR/USD, USD/JPY to go from EUR to JPY).
+#[derive(Debug, Clone)]
+pub struct ConversionChain {
+    pub account_id: i64,
+    pub from_asset_id: i64,
+    pub to_asset_id: i64,
+    pub legs: Vec<ConversionLeg>,
+}
+
+#[derive(Debug, Default)]
+pub struct ConversionEngine {
+    assets: HashMap<i64, Asset>,
+    // (base asset, quote asset) of every symbol seen in a symbols list
+    symbol_assets: HashMap<i64, (i64, i64)>,
+    deposit_assets: HashMap<i64, i64>,
+    chains: HashMap<(i64, i64), ConversionChain>,
+    quotes: HashMap<i64, Quote>,
+    // symbols whose spot feed was opened by the engine itself
+    subscribed: HashSet<i64>,
+}
+
+impl ConversionEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_assets(&mut self, assets: &[Asset]) {
+        for asset in assets {
+            self.assets.insert(asset.asset_id, asset.clone());
+        }
+    }
+
+    pub fn asset(&self, asset_id: i64) -> Option<&Asset> {
+        self.assets.get(&asset_id)
+    }
+
+    pub fn asset_id_by_name(&self, name: &str) -> Option<i64> {
+        self.assets
+            .values()
+            .find(|asset| asset.name == name)
+            .map(|asset| asset.asset_id)
+    }
+
+    pub fn set_symbol_assets(&mut self, symbol_id: i64, base_asset_id: i64, quote_asset_id: i64) {
+        self.symbol_assets.insert(symbol_id, (base_asset_id, quote_asset_id));
+    }
+
+    pub fn symbol_assets(&self, symbol_id: i64) -> Option<(i64, i64)> {
+        self.symbol_assets.get(&symbol_id).copied()
+    }
+
+    pub fn set_deposit_asset(&mut self, account_id: i64, asset_id: i64) {
+        self.deposit_assets.insert(account_id, asset_id);
+    }
+
+    pub fn deposit_asset(&self, account_id: i64) -> Option<i64> {
+        self.deposit_assets.get(&account_id).copied()
+    }
+
+    pub fn add_chain(&mut self, chain: ConversionChain) {
+        self.chains.insert((chain.from_asset_id, chain.to_asset_id), chain);
+    }
+
+    pub fn has_chain(&self, from_asset_id: i64, to_asset_id: i64) -> bool {
+        from_asset_id == to_asset_id || self.chains.contains_key(&(from_asset_id, to_asset_id))
+    }
+
+    /// Record a spot price.  The quote must already be converted with
+    /// `Quote::change_to_actual_quote_price`.
+    pub fn update_quote(&mut self, quote: &Quote) {
+        let entry = self.quotes.entry(quote.symbol_id).or_insert_with(|| quote.clone());
+        // spot events only carry the side that changed
+        if quote.bid.is_some() {
+            entry.bid = quote.bid;
+        }
+        if quote.ask.is_some() {
+            entry.ask = quote.ask;
+        }
+        entry.timestamp = quote.timestamp;
+    }
+
+    pub fn quote(&self, symbol_id: i64) -> Option<&Quote> {
+        self.quotes.get(&symbol_id)
+    }
+
+    /// Mark a symbol as subscribed by the engine.  Returns `false` if it was
+    /// already subscribed so the caller can skip a duplicate request.
+    pub fn mark_subscribed(&mut self, symbol_id: i64) -> bool {
+        self.subscribed.insert(symbol_id)
+    }
+
+    /// Forget a subscription that could not be opened.
+    pub fn unmark_subscribed(&mut self, symbol_id: i64) {
+        self.subscribed.remove(&symbol_id);
+    }
+
+    /// Forget the engine's subscriptions, e.g. after a reconnect.
+    pub fn clear_subscriptions(&mut self) {
+        self.subscribed.clear();
+    }
+
+    pub fn subscribed_symbols(&self) -> Vec<i64> {
+        self.subscribed.iter().copied().collect()
+    }
+
+    fn mid_price(&self, symbol_id: i64) -> Option<f64> {
+        let quote = self.quotes.get(&symbol_id)?;
+        match (quote.bid, quote.ask) {
+            (Some(bid), Some(ask)) => Some((bid + ask) / 2.0),
+            (Some(price), None) | (None, Some(price)) => Some(price),
+            (None, None) => None,
+        }
+    }
+
+    /// Convert `amount` of `from_asset_id` into `to_asset_id` by walking the
+    /// cached conversion chain at the latest mid prices.
+    pub fn convert(&self, amount: f64, from_asset_id: i64, to_asset_id: i64) -> Option<f64> {
+        if from_asset_id == to_asset_id {
+            return Some(amount);
+        }
+        let chain = self.chains.get(&(from_asset_id, to_asset_id))?;
+
+        let mut value = amount;
+        let mut current = from_asset_id;
+        for leg in &chain.legs {
+            let price = self.mid_price(leg.symbol_id)?;
+            if leg.base_asset_id == current {
+                value *= price;
+                current = leg.quote_asset_id;
+            } else if leg.quote_asset_id == current && price != 0.0 {
+                value /= price;
+                current = leg.base_asset_id;
+            } else {
+                return None;
+            }
+        }
+
+        (current == to_asset_id).then_some(value)
+    }
+
+    /// Value of one pip for one lot of the symbol, in the account deposit
+    /// currency.
+    pub fn pip_value_per_lot(&self, account_id: i64, symbol: &SymbolData) -> Option<f64> {
+        let (_, quote_asset) = self.symbol_assets(symbol.symbol_id as i64)?;
+        let deposit_asset = self.deposit_asset(account_id)?;
+
+        // lot_size is expressed in cents of the base asset
+        let lot_units = symbol.lot_size? as f64 / 100.0;
+        let pip_size = 10_f64.powi(-symbol.pip_position?);
+
+        self.convert(pip_size * lot_units, quote_asset, deposit_asset)
+    }
+
+    /// Unrealized PnL of an open position in the deposit currency, using the
+    /// bid for longs and the ask for shorts as the closing price.
+    pub fn unrealized_pnl(&self, position: &Position) -> Option<f64> {
+        let account_id = position.account_id?;
+        let symbol_id = position.symbol_id?;
+        let entry_price = position.price?;
+        let (_, quote_asset) = self.symbol_assets(symbol_id)?;
+        let deposit_asset = self.deposit_asset(account_id)?;
+        let quote = self.quotes.get(&symbol_id)?;
+
+        let units = position.volume as f64 / 100.0;
+        let pnl_in_quote = match position.trade_side? {
+            TradeSide::Buy => (quote.bid? - entry_price) * units,
+            TradeSide::Sell => (entry_price - quote.ask?) * units,
+        };
+
+        self.convert(pnl_in_quote, quote_asset, deposit_asset)
+    }
+
+    /// Notional value of the position (volume in base asset) expressed in the
+    /// deposit currency.
+    pub fn notional_exposure(&self, position: &Position) -> Option<f64> {
+        let account_id = position.account_id?;
+        let (base_asset, _) = self.symbol_assets(position.symbol_id?)?;
+        let deposit_asset = self.deposit_asset(account_id)?;
+
+        self.convert(position.volume as f64 / 100.0, base_asset, deposit_asset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EUR: i64 = 1;
+    const USD: i64 = 2;
+    const JPY: i64 = 3;
+    const GBP: i64 = 4;
+    const EURUSD: i64 = 10;
+    const USDJPY: i64 = 11;
+    const GBPUSD: i64 = 12;
+    const ACCOUNT: i64 = 100;
+
+    fn leg(symbol_id: i64) -> ConversionLeg {
+        let (base_asset_id, quote_asset_id) = match symbol_id {
+            EURUSD => (EUR, USD),
+            USDJPY => (USD, JPY),
+            GBPUSD => (GBP, USD),
+            _ => unreachable!(),
+        };
+        ConversionLeg { symbol_id, base_asset_id, quote_asset_id }
+    }
+
+    fn quote(symbol_id: i64, bid: f64, ask: f64) -> Quote {
+        Quote { symbol_id, bid: Some(bid), ask: Some(ask), timestamp: 0 }
+    }
+
+    // mid prices of 1.1 for EURUSD, 150 for USDJPY and 1.25 for GBPUSD
+    fn engine() -> ConversionEngine {
+        let mut engine = ConversionEngine::new();
+        for (symbol_id, base, quote) in [(EURUSD, EUR, USD), (USDJPY, USD, JPY), (GBPUSD, GBP, USD)] {
+            engine.set_symbol_assets(symbol_id, base, quote);
+        }
+        engine.update_quote(&quote(EURUSD, 1.0999, 1.1001));
+        engine.update_quote(&quote(USDJPY, 149.99, 150.01));
+        engine.update_quote(&quote(GBPUSD, 1.2499, 1.2501));
+
+        let chains = [
+            (EUR, USD, vec![EURUSD]),
+            (USD, EUR, vec![EURUSD]),
+            (EUR, JPY, vec![EURUSD, USDJPY]),
+            (GBP, EUR, vec![GBPUSD, EURUSD]),
+            (JPY, GBP, vec![USDJPY, GBPUSD]),
+            // does not end in the asset it claims to
+            (EUR, GBP, vec![EURUSD, USDJPY]),
+        ];
+        for (from_asset_id, to_asset_id, symbols) in chains {
+            engine.add_chain(ConversionChain {
+                account_id: ACCOUNT,
+                from_asset_id,
+                to_asset_id,
+                legs: symbols.into_iter().map(leg).collect(),
+            });
+        }
+        engine
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("no conversion");
+        assert!((actual - expected).abs() < 1e-6, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn converts_along_the_chain() {
+        let engine = engine();
+        let cases = [
+            // same asset
+            (100.0, USD, USD, 100.0),
+            // direct, base into quote
+            (100.0, EUR, USD, 110.0),
+            // inverse, quote into base
+            (110.0, USD, EUR, 100.0),
+            // cross through usd
+            (100.0, EUR, JPY, 16_500.0),
+            (100.0, GBP, EUR, 125.0 / 1.1),
+            (15_000.0, JPY, GBP, 80.0),
+        ];
+        for (amount, from, to, expected) in cases {
+            assert_close(engine.convert(amount, from, to), expected);
+        }
+    }
+
+    #[test]
+    fn needs_a_chain_and_its_prices() {
+        let mut engine = engine();
+        assert!(engine.has_chain(JPY, JPY));
+        assert!(!engine.has_chain(JPY, EUR));
+        assert_eq!(engine.convert(100.0, JPY, EUR), None);
+        assert_eq!(engine.convert(100.0, EUR, GBP), None);
+
+        // a missing side falls back to the other one
+        engine.quotes.insert(USDJPY, Quote { symbol_id: USDJPY, bid: Some(150.0), ask: None, timestamp: 0 });
+        assert_close(engine.convert(100.0, EUR, JPY), 16_500.0);
+
+        engine.quotes.remove(&USDJPY);
+        assert_eq!(engine.convert(100.0, EUR, JPY), None);
+    }
+
+    #[test]
+    fn spot_updates_keep_the_side_they_do_not_carry() {
+        let mut engine = engine();
+        engine.update_quote(&Quote { symbol_id: EURUSD, bid: Some(1.2), ask: None, timestamp: 5 });
+
+        let quote = engine.quote(EURUSD).unwrap();
+        assert_eq!(quote.bid, Some(1.2));
+        assert_eq!(quote.ask, Some(1.1001));
+        assert_eq!(quote.timestamp, 5);
+    }
+
+    #[test]
+    fn values_positions_in_the_deposit_currency() {
+        let mut engine = engine();
+        engine.set_deposit_asset(ACCOUNT, EUR);
+
+        let symbol = SymbolData {
+            symbol_id: EURUSD as u64,
+            max_volume: None,
+            min_volume: None,
+            step_volume: None,
+            digits: Some(5),
+            pip_position: Some(4),
+            lot_size: Some(10_000_000),
+            leverage_id: None,
+        };
+        // 10 usd per pip on 100k units
+        assert_close(engine.pip_value_per_lot(ACCOUNT, &symbol), 10.0 / 1.1);
+
+        let mut position = Position::new();
+        position.account_id = Some(ACCOUNT);
+        position.symbol_id = Some(EURUSD);
+        position.volume = 10_000_000;
+        position.price = Some(1.0949);
+
+        // longs close at the bid, shorts at the ask
+        let cases = [(TradeSide::Buy, 500.0), (TradeSide::Sell, -520.0)];
+        for (side, usd_pnl) in cases {
+            position.trade_side = Some(side);
+            assert_close(engine.unrealized_pnl(&position), usd_pnl / 1.1);
+        }
+        assert_close(engine.notional_exposure(&position), 100_000.0);
+    }
+}
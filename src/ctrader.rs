@@ -1,10 +1,21 @@
+use crate::config::ReconnectPolicy;
+use crate::conversion::ConversionEngine;
+use crate::leverage::LeverageBook;
+use crate::margin_guard::{GuardAction, MarginGuard, MarginGuardConfig};
+use crate::metrics::{self, Metrics};
+use crate::rate_limit::{RateLimitStats, RateLimiter, RequestClass};
+use crate::recording::{Direction, Recorder};
+use crate::types::{Account, AccountAuthState, AccountFilter, MarginCall, Position};
+use crate::version::{ServerVersion, VersionPolicy};
 use crate::{BarData, Endpoint, StreamEvent, TimeFrame, Order, RelativeBarData, Quote, utilities};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::{Mutex, mpsc};
-use tokio_rustls::client::TlsStream;
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
+use tracing::{debug, error, info, warn};
+use tokio::sync::{Mutex, Notify, mpsc, watch};
+use tokio::time::Instant;
 
 use prost::Message;
 
@@ -14,21 +25,59 @@ use crate::open_api::{
     ProtoOaErrorRes, ProtoOaGetAccountListByAccessTokenReq,
     ProtoOaGetAccountListByAccessTokenRes, ProtoOaGetTrendbarsReq, ProtoOaGetTrendbarsRes,
     ProtoOaPayloadType, ProtoOaSymbolsListReq, ProtoOaSymbolsListRes,
-    ProtoPayloadType, ProtoHeartbeatEvent, ProtoOaSubscribeSpotsReq, ProtoOaUnsubscribeSpotsReq,
-    ProtoOaSubscribeLiveTrendbarReq, ProtoOaUnsubscribeLiveTrendbarReq,
+    ProtoPayloadType, ProtoHeartbeatEvent, ProtoOaSubscribeSpotsReq,
+    ProtoOaSubscribeLiveTrendbarReq,
     ProtoOaSpotEvent, ProtoOaSymbolByIdReq, ProtoOaSymbolByIdRes,
     ProtoOaNewOrderReq, ProtoOaClosePositionReq,
     ProtoOaTradeSide, ProtoOaOrderType, ProtoOaOrderErrorEvent, ProtoOaExecutionEvent,
-    ProtoOaOrderListReq, ProtoOaOrderListRes
+    ProtoOaOrderListReq, ProtoOaGetPositionUnrealizedPnLReq, ProtoOaGetPositionUnrealizedPnLRes,
+    ProtoOaExpectedMarginReq, ProtoOaExpectedMarginRes, ProtoOaAssetListReq, ProtoOaAssetListRes,
+    ProtoOaTraderReq, ProtoOaTraderRes, ProtoOaSymbolsForConversionReq,
+    ProtoOaSymbolsForConversionRes, ProtoOaTraderUpdatedEvent, ProtoOaMarginCallListReq,
+    ProtoOaMarginCallListRes, ProtoOaMarginCallUpdateReq, ProtoOaMarginCallUpdateEvent,
+    ProtoOaMarginCallTriggerEvent, ProtoOaMarginChangedEvent, ProtoOaMarginCall, ProtoOaPositionStatus,
+    ProtoOaGetDynamicLeverageByIdReq, ProtoOaGetDynamicLeverageByIdRes, ProtoOaAccountLogoutReq,
+    ProtoOaAccountLogoutRes, ProtoOaAccountDisconnectEvent, ProtoOaAccountsTokenInvalidatedEvent,
+    ProtoOaClientDisconnectEvent, ProtoOaRefreshTokenReq, ProtoOaRefreshTokenRes,
+    ProtoOaGetCtidProfileByTokenReq, ProtoOaGetCtidProfileByTokenRes, ProtoOaSubscribeSpotsRes,
+    ProtoOaSubscribeLiveTrendbarRes, ProtoOaVersionReq, ProtoOaVersionRes, ProtoOaUnsubscribeSpotsReq,
+    ProtoOaUnsubscribeSpotsRes, ProtoOaUnsubscribeLiveTrendbarReq, ProtoOaUnsubscribeLiveTrendbarRes,
+    ProtoOaCancelOrderReq, ProtoOaReconcileReq, ProtoOaReconcileRes
 };
 
 //the stream builder module
+pub mod account_session;
+pub mod builder;
+pub mod codec;
 pub mod handler_functions;
+pub mod shutdown;
 pub mod stream_builder;
+pub mod token_refresh;
+pub mod transport;
+
+pub use account_session::AccountHandle;
+pub use builder::CtraderClientBuilder;
+pub use codec::{CodecError, OpenApiCodec};
+pub use shutdown::{ClientTasks, ShutdownOptions};
+pub use stream_builder::{ConnectOptions, Proxy, TlsMode};
+pub use token_refresh::TokenCallback;
+pub use transport::{ProtobufTransport, Transport, WebSocketTransport};
 
 pub struct CtraderClient {
-    stream: Arc<Mutex<TlsStream<TcpStream>>>,
-    access_token: String,
+    // the two halves of the connection, so that writing a request never
+    // waits for the read loop to receive something.  both are replaced on
+    // reconnect.
+    reader: Mutex<transport::MessageStream>,
+    writer: Mutex<transport::MessageSink>,
+    settings: builder::ClientSettings,
+    // token buckets every request goes through, unless disabled.
+    rate_limiter: Option<RateLimiter>,
+    // every message sent and received is logged here, when recording.
+    recorder: Option<Recorder>,
+    access_token: Mutex<String>,
+    // when set, invalidated or dropped account sessions are re-authorized
+    // after refreshing the tokens.
+    refresh_token: Mutex<Option<String>>,
     client_secret: String,
     client_id: String,
     event_tx: mpsc::Sender<StreamEvent>,
@@ -46,6 +95,62 @@ pub struct CtraderClient {
     last_bar: Mutex<Option<RelativeBarData>>,
     last_quote: Mutex<Option<Quote>>,
     last_bar_ts: Mutex<Option<u64>>,
+
+    // assets, conversion chains and spot prices used to express pip values
+    // and pnl in the account deposit currency.
+    conversion: Mutex<ConversionEngine>,
+
+    // per account balance / used margin / pnl, checked against the configured
+    // margin level threshold after every update.
+    margin_guard: Mutex<MarginGuard>,
+
+    // dynamic leverage tiers keyed by leverage id, plus the account leverage
+    // that caps them.  used for local margin estimates.
+    leverage: Mutex<LeverageBook>,
+
+    // the accounts of the last AccountsData, used for account selection.
+    accounts: Mutex<Vec<Account>>,
+
+    // authorization state of every account this client tried to authorize.
+    account_states: Mutex<HashMap<i64, AccountAuthState>>,
+    account_tokens: Mutex<HashMap<i64, String>>,
+    // accounts whose events go to their own channel instead of event_tx.
+    account_channels: Mutex<HashMap<i64, mpsc::Sender<StreamEvent>>>,
+
+    // in-protocol token refresh: when the access token expires, the accounts
+    // waiting for the refresh response, and who to tell about new tokens.
+    token_expiry: Mutex<Option<Instant>>,
+    pending_reauth: Mutex<Vec<i64>>,
+    token_refreshed: Notify,
+    token_callback: Mutex<Option<TokenCallback>>,
+
+    // the version the server reported last, checked before order related
+    // requests when the version policy refuses.
+    server_version: Mutex<Option<ServerVersion>>,
+
+    // when each client_msg_id was last sent and with which payload type, to
+    // log and measure the latency of its response.  ids are fixed per kind of
+    // request, so this stays small.
+    sent_at: Mutex<HashMap<String, (Instant, u32)>>,
+
+    // counters and latencies for `metrics` and the metrics listener.
+    pub(crate) metrics: Mutex<Metrics>,
+
+    // the feeds subscribed on the current connection, as (account, symbol)
+    // and (account, symbol, period), removed again by `shutdown`.
+    spot_subscriptions: Mutex<HashSet<(i64, i64)>>,
+    live_bar_subscriptions: Mutex<HashSet<(i64, i64, i32)>>,
+
+    // orders accepted but not filled, cancelled, rejected or expired yet,
+    // by account.
+    pending_orders: Mutex<HashMap<i64, HashSet<i64>>>,
+
+    // accounts waiting for the response to `logout_account`.
+    pending_logouts: Mutex<HashSet<i64>>,
+    logged_out: Notify,
+
+    // set by `shutdown`, ends every background task.
+    stop: watch::Sender<bool>,
 }
 
 impl CtraderClient {
@@ -55,19 +160,55 @@ impl CtraderClient {
         access_token: &str,
         endpoint: Endpoint,
     ) -> Result<(Arc<Self>, mpsc::Receiver<StreamEvent>), Box<dyn std::error::Error>> {
-        let host = match endpoint {
-            Endpoint::Demo => "demo.ctraderapi.com",
-            Endpoint::Live => "live.ctraderapi.com",
-        };
-        let stream_ = stream_builder::initialize_stream(host, 5035)
-            .await
-            .expect("Failed to initialize the stream.");
+        Self::connect_with(client_id, client_secret, access_token, endpoint, ConnectOptions::default()).await
+    }
+
+    /// Like `connect`, with control over TLS and the connect / handshake
+    /// timeouts, e.g. to reach a local mock server over plain TCP.
+    pub async fn connect_with(
+        client_id: &str,
+        client_secret: &str,
+        access_token: &str,
+        endpoint: Endpoint,
+        options: ConnectOptions,
+    ) -> Result<(Arc<Self>, mpsc::Receiver<StreamEvent>), Box<dyn std::error::Error>> {
+        Self::open(client_id, client_secret, access_token, builder::ClientSettings::legacy(endpoint, options)).await
+    }
+
+    /// A builder for everything `connect` does not cover: channel sizes,
+    /// heartbeats, timeouts, reconnects, or a config file.
+    pub fn builder() -> CtraderClientBuilder {
+        CtraderClientBuilder::new()
+    }
+
+    /// Capacity of the event channel this client was built with.
+    pub(crate) fn event_channel_capacity(&self) -> usize {
+        self.settings.event_channel_capacity
+    }
+
+    pub(crate) async fn open(
+        client_id: &str,
+        client_secret: &str,
+        access_token: &str,
+        settings: builder::ClientSettings,
+    ) -> Result<(Arc<Self>, mpsc::Receiver<StreamEvent>), Box<dyn std::error::Error>> {
+        let rate_limiter = settings.rate_limits.map(RateLimiter::new).transpose()?;
+        let recorder = settings.record_file.as_ref().map(Recorder::create).transpose()?;
+        let (reader, writer) = settings
+            .transport
+            .connect(&settings.endpoint, &settings.options, settings.max_frame_size)
+            .await?;
 
-        let (event_tx, event_rx) = mpsc::channel(100);
+        let (event_tx, event_rx) = mpsc::channel(settings.event_channel_capacity);
 
         let client = Self {
-            stream: Arc::new(Mutex::new(stream_)),
-            access_token: access_token.to_string(),
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            settings,
+            rate_limiter,
+            recorder,
+            access_token: Mutex::new(access_token.to_string()),
+            refresh_token: Mutex::new(None),
             client_secret: client_secret.to_string(),
             client_id: client_id.to_string(),
             event_tx,
@@ -77,79 +218,284 @@ impl CtraderClient {
             last_bar: Mutex::new(None),
             last_quote: Mutex::new(None),
             last_bar_ts: Mutex::new(None),
+            conversion: Mutex::new(ConversionEngine::new()),
+            margin_guard: Mutex::new(MarginGuard::new()),
+            leverage: Mutex::new(LeverageBook::new()),
+            accounts: Mutex::new(Vec::new()),
+            account_states: Mutex::new(HashMap::new()),
+            account_tokens: Mutex::new(HashMap::new()),
+            account_channels: Mutex::new(HashMap::new()),
+            token_expiry: Mutex::new(None),
+            pending_reauth: Mutex::new(Vec::new()),
+            token_refreshed: Notify::new(),
+            token_callback: Mutex::new(None),
+            server_version: Mutex::new(None),
+            sent_at: Mutex::new(HashMap::new()),
+            metrics: Mutex::new(Metrics::default()),
+            spot_subscriptions: Mutex::new(HashSet::new()),
+            live_bar_subscriptions: Mutex::new(HashSet::new()),
+            pending_orders: Mutex::new(HashMap::new()),
+            pending_logouts: Mutex::new(HashSet::new()),
+            logged_out: Notify::new(),
+            stop: watch::Sender::new(false),
         };
 
         Ok((Arc::new(client), event_rx))
     }
 
+    #[tracing::instrument(name = "send_message", skip_all, fields(payload_type = payload_type, client_msg_id = client_msg_id.as_deref()))]
     pub async fn send_message<M: Message>(
         &self,
         payload_type: u32,
         payload: M,
         client_msg_id: Option<String>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let payload_encoded = payload.encode_to_vec();
+        if self.is_shut_down() {
+            return Err("the client was shut down".into());
+        }
         let message = ProtoMessage {
             payload_type,
-            payload: Some(payload_encoded),
+            payload: Some(payload.encode_to_vec().into()),
             client_msg_id,
         };
 
-        let mut stream = self.stream.lock().await;
-        let encoded_msg = message.encode_to_vec();
-        let n = encoded_msg.len() as u32;
-
-        stream.write_u32(n).await?;
-        stream.write_all(&encoded_msg).await?;
+        let class = RequestClass::of(payload_type);
+        if class == Some(RequestClass::Trading) {
+            self.check_trading_version().await?;
+        }
+        let delay = match (&self.rate_limiter, class) {
+            (Some(rate_limiter), Some(class)) => rate_limiter.acquire(class).await,
+            _ => Duration::ZERO,
+        };
+        self.record(Direction::Sent, &message);
+        let client_msg_id = message.client_msg_id.clone();
+
+        let write = async { self.writer.lock().await.send(message).await };
+        match self.settings.write_timeout {
+            Some(limit) => tokio::time::timeout(limit, write)
+                .await
+                .map_err(|_| format!("sending payload type {} timed out", payload_type))??,
+            None => write.await?,
+        }
 
+        if self.settings.log_messages {
+            info!(rate_limit_delay_ms = delay.as_millis() as u64, "request sent");
+        } else {
+            debug!(rate_limit_delay_ms = delay.as_millis() as u64, "request sent");
+        }
+        self.metrics.lock().await.message_sent(payload_type);
+        if let Some(client_msg_id) = client_msg_id {
+            self.sent_at.lock().await.insert(client_msg_id, (Instant::now(), payload_type));
+        }
         Ok(())
     }
 
-    pub async fn start(self: &Arc<Self>) {
-        println!("Starting read loop...");
-        let client = Arc::clone(self);
+    /// A copy of the counters and latencies collected so far.
+    pub async fn metrics(&self) -> Metrics {
+        let mut metrics = self.metrics.lock().await.clone();
+        metrics.rate_limits = self.rate_limit_stats().await;
+        metrics
+    }
+
+    /// Serve `metrics` in the prometheus text format at `GET /metrics` on
+    /// `addr` until the client is dropped, returning the address bound, e.g.
+    /// the port picked for port 0.
+    pub async fn serve_metrics(self: &Arc<Self>, addr: SocketAddr) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!(addr = %local_addr, "serving metrics");
+        tokio::spawn(metrics::serve(listener, Arc::downgrade(self), self.stop.subscribe()));
+        Ok(local_addr)
+    }
 
-        tokio::spawn(async move {
-            if let Err(e) = client.read_loop().await {
-                let _ = client
-                    .event_tx
-                    .send(StreamEvent::Error(e.to_string()))
-                    .await;
+    /// How long requests waited for the rate limiter so far, `None` when
+    /// rate limiting is disabled.
+    pub async fn rate_limit_stats(&self) -> Option<RateLimitStats> {
+        match &self.rate_limiter {
+            Some(rate_limiter) => Some(rate_limiter.stats().await),
+            None => None,
+        }
+    }
+
+    /// Spawn the read loop and the heartbeat task, returning their handles.
+    /// Both run until `shutdown`, the read loop ends earlier when the
+    /// connection is lost for good.
+    pub async fn start(self: &Arc<Self>) -> ClientTasks {
+        debug!("starting the read loop");
+        let client = Arc::clone(self);
+        let mut stop = self.stop.subscribe();
+
+        let read_loop = tokio::spawn(async move {
+            tokio::select! {
+                result = client.read_loop() => {
+                    if let Err(e) = result {
+                        let _ = client
+                            .event_tx
+                            .send(StreamEvent::Error(e.to_string()))
+                            .await;
+                    }
+                }
+                _ = shutdown::stopped(&mut stop) => debug!("read loop stopped"),
             }
         });
+
+        if self.settings.version_check != VersionPolicy::Off
+            && let Err(e) = self.get_server_version().await
+        {
+            warn!(error = %e, "could not request the server version");
+        }
+
+        let heartbeat = self.settings.heartbeat_interval.map(|interval| {
+            // holds a weak reference so the task ends with the client
+            let client = Arc::downgrade(self);
+            let mut stop = self.stop.subscribe();
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep(interval) => {}
+                        _ = shutdown::stopped(&mut stop) => break,
+                    }
+                    let Some(client) = client.upgrade() else { break };
+                    // the lost connection itself is handled by the read loop
+                    if let Err(e) = client.keep_alive().await {
+                        warn!(error = %e, "could not send the heartbeat");
+                    }
+                }
+            })
+        });
+
+        ClientTasks { read_loop, heartbeat }
     }
 
     async fn read_loop(&self) -> anyhow::Result<()> {
         loop {
             match self.read_proto_message().await {
                 Ok(msg) => {
+                    let request = match &msg.client_msg_id {
+                        Some(client_msg_id) => self.sent_at.lock().await.remove(client_msg_id),
+                        None => None,
+                    };
+                    let latency = request.map(|(sent, _)| sent.elapsed());
+                    {
+                        let mut metrics = self.metrics.lock().await;
+                        metrics.message_received(msg.payload_type);
+                        if let (Some((_, request_type)), Some(latency)) = (request, latency) {
+                            metrics.response_received(request_type, latency);
+                        }
+                    }
+                    let latency_ms = latency.map(|latency| latency.as_secs_f64() * 1000.0);
+                    if self.settings.log_messages {
+                        info!(
+                            payload_type = msg.payload_type,
+                            client_msg_id = msg.client_msg_id.as_deref(),
+                            latency_ms,
+                            "message received"
+                        );
+                    } else {
+                        debug!(
+                            payload_type = msg.payload_type,
+                            client_msg_id = msg.client_msg_id.as_deref(),
+                            latency_ms,
+                            "message received"
+                        );
+                    }
                     if let Err(e) = self.handle_proto_message(msg).await {
-                        println!("Error handling proto message: {}", e);
+                        error!(error = %e, "could not handle the message");
                         return Err(anyhow::anyhow!(e.to_string()));
                     }
                 }
                 Err(e) => {
-                    println!("Error reading proto message: {}", e);
-                    // EOF or connection error → exit loop
-                    return Err(anyhow::anyhow!(e.to_string()));
+                    warn!(error = %e, "could not read the next message");
+                    // EOF or connection error → reconnect when allowed, else exit loop
+                    let Some(policy) = self.settings.reconnect else {
+                        return Err(anyhow::anyhow!(e.to_string()));
+                    };
+                    self.reconnect(policy).await?;
                 }
             }
         }
     }
 
-    async fn read_proto_message(&self) -> anyhow::Result<ProtoMessage> {
-        let mut stream = self.stream.lock().await;
-        let len = stream.read_u32().await?;
+    // opens a new connection with the same settings, then authorizes the
+    // application and every account that was authorized before
+    #[tracing::instrument(skip_all)]
+    async fn reconnect(&self, policy: ReconnectPolicy) -> anyhow::Result<()> {
+        let endpoint = &self.settings.endpoint;
+        for attempt in 1..=policy.max_attempts {
+            tokio::time::sleep(policy.delay(attempt)).await;
+            self.metrics.lock().await.reconnect_attempts += 1;
+            self.emit(None, StreamEvent::Reconnecting(attempt)).await?;
+            info!(host = endpoint.host(), port = endpoint.port(), attempt, "reconnecting");
+
+            let connection = self
+                .settings
+                .transport
+                .connect(endpoint, &self.settings.options, self.settings.max_frame_size)
+                .await
+                .map_err(|e| e.to_string());
+            let (reader, writer) = match connection {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!(attempt, error = %e, "reconnect attempt failed");
+                    continue;
+                }
+            };
+            *self.reader.lock().await = reader;
+            *self.writer.lock().await = writer;
+
+            // spot subscriptions did not survive the old connection
+            self.conversion.lock().await.clear_subscriptions();
+            self.spot_subscriptions.lock().await.clear();
+            self.live_bar_subscriptions.lock().await.clear();
+
+            let accounts: Vec<i64> = self
+                .account_states
+                .lock()
+                .await
+                .iter()
+                .filter(|(_, state)| matches!(state, AccountAuthState::Authorized | AccountAuthState::Authorizing))
+                .map(|(account_id, _)| *account_id)
+                .collect();
+            self.authorize_application().await.map_err(|e| anyhow::anyhow!(e.to_string()))?;
+            self.authorize_accounts(&accounts).await.map_err(|e| anyhow::anyhow!(e.to_string()))?;
+            // the new connection may have reached another server
+            if self.settings.version_check != VersionPolicy::Off {
+                *self.server_version.lock().await = None;
+                self.get_server_version().await.map_err(|e| anyhow::anyhow!(e.to_string()))?;
+            }
 
-        let mut buf = vec![0u8; len as usize];
-        stream.read_exact(&mut buf).await?;
+            info!(attempt, "reconnected");
+            self.metrics.lock().await.reconnects += 1;
+            self.emit(None, StreamEvent::Reconnected).await?;
+            return Ok(());
+        }
+
+        Err(anyhow::anyhow!("gave up reconnecting after {} attempts", policy.max_attempts))
+    }
 
-        let msg = ProtoMessage::decode(&buf[..])?;
+    async fn read_proto_message(&self) -> anyhow::Result<ProtoMessage> {
+        let msg = self
+            .reader
+            .lock()
+            .await
+            .next()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("connection closed by the server"))??;
+        self.record(Direction::Received, &msg);
         Ok(msg)
     }
 
+    // a recording that cannot be written must not take the connection down
+    fn record(&self, direction: Direction, message: &ProtoMessage) {
+        if let Some(recorder) = &self.recorder
+            && let Err(e) = recorder.record(direction, message)
+        {
+            warn!(error = %e, "could not record the message");
+        }
+    }
+
     pub async fn authorize_application(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Authorizing application...");
+        debug!("authorizing the application");
         let req = ProtoOaApplicationAuthReq {
             payload_type: Some(ProtoOaPayloadType::ProtoOaApplicationAuthReq as i32),
             client_id: self.client_id.clone(),
@@ -166,10 +512,10 @@ impl CtraderClient {
     }
 
     pub async fn get_accounts(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Getting accounts...");
+        debug!("getting the accounts of the access token");
         let req = ProtoOaGetAccountListByAccessTokenReq {
             payload_type: Some(ProtoOaPayloadType::ProtoOaGetAccountsByAccessTokenReq as i32),
-            access_token: self.access_token.clone(),
+            access_token: self.access_token.lock().await.clone(),
         };
 
         self.send_message(
@@ -181,15 +527,107 @@ impl CtraderClient {
         Ok(())
     }
 
+    /// The accounts of the last `get_accounts` answer matching `filter`.
+    pub async fn accounts(&self, filter: &AccountFilter) -> Vec<Account> {
+        filter.apply(&self.accounts.lock().await)
+    }
+
+    /// Request the ctid profile of the access token owner.  The answer is
+    /// emitted as `StreamEvent::CtidProfileData`.
+    pub async fn get_ctid_profile(&self) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("getting the ctid profile");
+        let req = ProtoOaGetCtidProfileByTokenReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaGetCtidProfileByTokenReq as i32),
+            access_token: self.access_token.lock().await.clone(),
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaGetCtidProfileByTokenReq as u32,
+            req,
+            Some(String::from("get ctid profile by token")),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Request the version of the server.  The answer is emitted as
+    /// `StreamEvent::ServerVersionData` and kept for `server_version`.
+    pub async fn get_server_version(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let req = ProtoOaVersionReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaVersionReq as i32),
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaVersionReq as u32,
+            req,
+            Some(String::from("get server version")),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The server version of the last `get_server_version` answer.
+    pub async fn server_version(&self) -> Option<ServerVersion> {
+        self.server_version.lock().await.clone()
+    }
+
+    // stores the version the server reported, reporting a mismatch unless
+    // the version check is off
+    pub(crate) async fn set_server_version(&self, version: ServerVersion) -> Result<(), mpsc::error::SendError<StreamEvent>> {
+        *self.server_version.lock().await = Some(version.clone());
+        self.emit(None, StreamEvent::ServerVersionData(version.clone())).await?;
+
+        if self.settings.version_check != VersionPolicy::Off && version.matches != crate::version::VersionMatch::Same {
+            let message = format!(
+                "server version {} does not match the compiled messages of version {} ({:?})",
+                version.server, version.compiled, version.matches
+            );
+            warn!(server = %version.server, compiled = version.compiled, matches = ?version.matches, "server version mismatch");
+            if !version.matches.is_compatible() {
+                self.emit(None, StreamEvent::Error(message)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // order related requests are refused while the server is known to be
+    // incompatible, when the policy says so
+    async fn check_trading_version(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.settings.version_check != VersionPolicy::Refuse {
+            return Ok(());
+        }
+        match &*self.server_version.lock().await {
+            Some(version) if !version.matches.is_compatible() => Err(format!(
+                "trading refused: server version {} is not compatible with the compiled messages of version {}",
+                version.server, version.compiled
+            )
+            .into()),
+            Some(_) => Ok(()),
+            // before the first answer, and again while reconnecting
+            None => Err("trading refused: the server version is not known yet".into()),
+        }
+    }
+
     pub async fn authorize_account(
         &self,
         account_id: i64,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Authorizing account with ID: {}...", account_id);
+        debug!(account_id, "authorizing account");
+        self.account_states
+            .lock()
+            .await
+            .insert(account_id, AccountAuthState::Authorizing);
+        let access_token = self.access_token.lock().await.clone();
+        // remembered so that a session invalidated by our own refresh is
+        // re-authorized without refreshing again
+        self.account_tokens
+            .lock()
+            .await
+            .insert(account_id, access_token.clone());
         let req = ProtoOaAccountAuthReq {
             payload_type: Some(ProtoOaPayloadType::ProtoOaAccountAuthReq as i32),
             ctid_trader_account_id: account_id,
-            access_token: self.access_token.clone(),
+            access_token,
         };
 
         self.send_message(
@@ -206,7 +644,7 @@ impl CtraderClient {
         account_id: i64,
         include_archived: bool,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Getting symbols...");
+        debug!(account_id, include_archived, "getting symbols");
         let req = ProtoOaSymbolsListReq {
             payload_type: Some(ProtoOaPayloadType::ProtoOaSymbolsListReq as i32),
             ctid_trader_account_id: account_id,
@@ -230,13 +668,13 @@ impl CtraderClient {
         from_timestamp: i64,
         to_timestamp: i64,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Getting historical trend bar data...");
+        debug!(account_id, symbol_id, ?period, from_timestamp, to_timestamp, "getting trend bars");
 
         let period = period.change_proto_trendbar_period();
 
         let req = ProtoOaGetTrendbarsReq {
             payload_type: Some(ProtoOaPayloadType::ProtoOaGetTrendbarsReq as i32),
-            symbol_id: symbol_id,
+            symbol_id,
             period: period as i32,
             ctid_trader_account_id: account_id,
             from_timestamp: Some(from_timestamp),
@@ -277,13 +715,39 @@ impl CtraderClient {
             Some(String::from("subscribe to spot events")),
         )
         .await?;
+        self.spot_subscriptions.lock().await.insert((account_id, symbol_id));
+        Ok(())
+    }
+
+    pub async fn unsubscribe_spot(&self, account_id: i64, symbol_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.unsubscribe_spots(account_id, vec![symbol_id]).await
+    }
+
+    async fn unsubscribe_spots(&self, account_id: i64, symbol_ids: Vec<i64>) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, ?symbol_ids, "unsubscribing from spot events");
+        let req = ProtoOaUnsubscribeSpotsReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaUnsubscribeSpotsReq as i32),
+            ctid_trader_account_id: account_id,
+            symbol_id: symbol_ids.clone(),
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaUnsubscribeSpotsReq as u32,
+            req,
+            Some(String::from("unsubscribe from spot events")),
+        )
+        .await?;
+        let mut subscriptions = self.spot_subscriptions.lock().await;
+        for symbol_id in symbol_ids {
+            subscriptions.remove(&(account_id, symbol_id));
+        }
         Ok(())
     }
 
     // note: we accept `self: &Arc<Self>` so that a cloned handle can be moved
     // into the spawned task without borrowing `&self` itself.
     pub async fn subscribe_live_bars(self: &Arc<Self>, account_id: i64, symbol_id: i64, timeframe: TimeFrame) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Subscribing to live bars for symbol ID: {} and timeframe: {:?}...", symbol_id, timeframe);
+        debug!(account_id, symbol_id, ?timeframe, "subscribing to live bars");
         self.subscribe_spot(account_id, symbol_id).await?;
 
         // ensure we have the data cached; only ask once per symbol. spawn a
@@ -302,12 +766,11 @@ impl CtraderClient {
                 None
             }
         };
-        println!("Sending live bar subscription message...");
 
         let req = ProtoOaSubscribeLiveTrendbarReq {
             payload_type: Some(ProtoOaPayloadType::ProtoOaSubscribeLiveTrendbarReq as i32),
             ctid_trader_account_id: account_id,
-            symbol_id: symbol_id,
+            symbol_id,
             period: timeframe.change_proto_trendbar_period() as i32,
         };
 
@@ -317,26 +780,68 @@ impl CtraderClient {
             Some(String::from("subscribe to live bars")),
         )
         .await?;
-
-        println!("Live bar subscription message sent successfully.");
+        self.live_bar_subscriptions
+            .lock()
+            .await
+            .insert((account_id, symbol_id, timeframe.change_proto_trendbar_period() as i32));
 
         if let Some(handle) = maybe_task {
             // propagate any error from the spawned symbol request
             handle.await??;
         }
-        println!("Live bar subscription setup complete.");
+        Ok(())
+    }
+
+    /// Stop the live bars of one timeframe; the spot subscription of the
+    /// symbol is kept.
+    pub async fn unsubscribe_live_bars(&self, account_id: i64, symbol_id: i64, timeframe: TimeFrame) -> Result<(), Box<dyn std::error::Error>> {
+        self.unsubscribe_live_bar_period(account_id, symbol_id, timeframe.change_proto_trendbar_period() as i32).await
+    }
 
+    async fn unsubscribe_live_bar_period(&self, account_id: i64, symbol_id: i64, period: i32) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, symbol_id, period, "unsubscribing from live bars");
+        let req = ProtoOaUnsubscribeLiveTrendbarReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaUnsubscribeLiveTrendbarReq as i32),
+            ctid_trader_account_id: account_id,
+            period,
+            symbol_id,
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaUnsubscribeLiveTrendbarReq as u32,
+            req,
+            Some(String::from("unsubscribe from live bars")),
+        )
+        .await?;
+        self.live_bar_subscriptions.lock().await.remove(&(account_id, symbol_id, period));
         Ok(())
     }
 
     pub async fn new_order(&self, order: Order) -> Result<(), Box<dyn std::error::Error>> {
+        let state = self.account_state(order.account_id as i64).await;
+        if state.is_some_and(|state| state != AccountAuthState::Authorized) {
+            return Err(format!("account {} is not authorized ({:?})", order.account_id, state.unwrap()).into());
+        }
+        if self.margin_guard.lock().await.is_blocked(order.account_id as i64) {
+            return Err(format!(
+                "new orders are blocked for account {}: margin level under the guard threshold",
+                order.account_id
+            )
+            .into());
+        }
+
+        // the server does not take absolute levels on market orders, fail
+        // here instead of having them ignored
+        if matches!(order.order_type, crate::types::OrderType::Market)
+            && (order.stop_loss.is_some() || order.take_profit.is_some())
+        {
+            return Err("market orders take a relative stop loss and take profit, not absolute prices".into());
+        }
+
         // Ensure we have the symbol data cached
-        let symbol_id_u64 = order.symbol_id;
         let account_id = order.account_id as i64;
         let symbol_id = order.symbol_id as i64;
 
-        
-
         // Compute volume in protocol units (0.01 of a unit)
         let volume = utilities::lots_to_protocol_std_volume(self, symbol_id, account_id, order.lotsize).await?;
 
@@ -359,8 +864,8 @@ impl CtraderClient {
             stop_price: order.stop_price,
             time_in_force: None, // You can map order.time_in_force if needed
             expiration_timestamp: order.expiration_timestamp,
-            stop_loss: order.relative_stop_loss.map(|x| x as f64),
-            take_profit: order.relative_take_profit.map(|x| x as f64),
+            stop_loss: order.stop_loss,
+            take_profit: order.take_profit,
             comment: order.comment.clone(),
             base_slippage_price: None,
             slippage_in_points: order.slippage_in_points,
@@ -374,14 +879,20 @@ impl CtraderClient {
             stop_trigger_method: None,
         };
 
-        println!("Sending new order request...");
+        debug!(
+            account_id,
+            symbol_id,
+            volume,
+            order_type = ?order.order_type,
+            trade_side = ?order.trade_side,
+            "sending new order"
+        );
         self.send_message(
             ProtoOaPayloadType::ProtoOaNewOrderReq as u32,
             new_order_request,
             Some(String::from("new order request")),
         )
         .await?;
-        println!("New order request sent successfully.");
         Ok(())
     }
 
@@ -391,9 +902,7 @@ impl CtraderClient {
         position_id: i64,
         volume: i64,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Closing position {}...", position_id);
-
-
+        debug!(account_id, position_id, volume, "closing position");
 
         let req = ProtoOaClosePositionReq {
             payload_type: Some(ProtoOaPayloadType::ProtoOaClosePositionReq as i32),
@@ -409,15 +918,46 @@ impl CtraderClient {
         )
         .await?;
 
-        println!("Close position request sent successfully.");
         Ok(())
     }
 
+    /// Cancel a pending order, answered with an `OrderCancelled` execution.
+    pub async fn cancel_order(&self, account_id: i64, order_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, order_id, "cancelling order");
+        let req = ProtoOaCancelOrderReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaCancelOrderReq as i32),
+            ctid_trader_account_id: account_id,
+            order_id,
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaCancelOrderReq as u32,
+            req,
+            Some(format!("cancel order {}", order_id)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The ids of the orders of an account that were accepted and are still
+    /// waiting to be filled, as seen in the execution events.
+    pub async fn pending_orders(&self, account_id: i64) -> Vec<i64> {
+        let mut orders: Vec<i64> = self
+            .pending_orders
+            .lock()
+            .await
+            .get(&account_id)
+            .map(|orders| orders.iter().copied().collect())
+            .unwrap_or_default();
+        orders.sort_unstable();
+        orders
+    }
+
     //getting the open positions for an account
     pub async fn get_open_positions(&self, account_id: i64) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Getting open positions for account ID: {}...", account_id);
+        debug!(account_id, "getting open positions");
 
-        ///send the order list request message to get the open positions for the account
+        //send the order list request message to get the open positions for the account
         let req = ProtoOaOrderListReq {
             payload_type: Some(ProtoOaPayloadType::ProtoOaOrderListReq as i32),
             ctid_trader_account_id: account_id,
@@ -435,7 +975,7 @@ impl CtraderClient {
     }
 
     pub async fn get_symbol_by_id(&self, account_id: i64, symbol_id: i64, client_msg: Option<String>) -> anyhow::Result<()> {
-        println!("Getting symbol by ID...");
+        debug!(account_id, symbol_id, "getting symbol by id");
         let req = ProtoOaSymbolByIdReq {
             payload_type: Some(ProtoOaPayloadType::ProtoOaSymbolByIdReq as i32),
             ctid_trader_account_id: account_id,
@@ -448,27 +988,386 @@ impl CtraderClient {
             req,
             if client_msg.is_some() { client_msg} else {Some(String::from("get symbol by id"))},
         ).await {
-                Ok(()) => {
-
-                        println!("the symbol request sent successfully");
-                        return Ok(());
-                        
-                    }
-                
-                Err(e) => {
-                    println!("Error reading proto message");
-
-                    return Err(anyhow::format_err!(e.to_string()));
-                    
-                }
+                Ok(()) => Ok(()),
+                Err(e) => Err(anyhow::format_err!(e.to_string())),
             }
         
     }
 
+    /// Ask the server for the unrealized PnL of every open position on the
+    /// account.  The result is emitted as `StreamEvent::PositionUnrealizedPnlData`.
+    pub async fn get_position_unrealized_pnl(&self, account_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, "getting unrealized pnl");
+        let req = ProtoOaGetPositionUnrealizedPnLReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaGetPositionUnrealizedPnlReq as i32),
+            ctid_trader_account_id: account_id,
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaGetPositionUnrealizedPnlReq as u32,
+            req,
+            Some(String::from("get position unrealized pnl")),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Ask the server how much margin opening each of `volumes` (protocol
+    /// units, 0.01 of a unit) of the symbol would use.  The estimates are
+    /// emitted as `StreamEvent::ExpectedMarginData` so they can be checked
+    /// before calling `new_order`.
+    pub async fn expected_margin(
+        &self,
+        account_id: i64,
+        symbol_id: i64,
+        volumes: Vec<i64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, symbol_id, "getting expected margin");
+        let req = ProtoOaExpectedMarginReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaExpectedMarginReq as i32),
+            ctid_trader_account_id: account_id,
+            symbol_id,
+            volume: volumes,
+        };
+
+        // the response does not echo the symbol, so it travels in the message id
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaExpectedMarginReq as u32,
+            req,
+            Some(format!("expected margin {}", symbol_id)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Log the account session out.  The server answers with
+    /// `StreamEvent::AccountLoggedOut` followed by `AccountDisconnected`.
+    pub async fn logout_account(&self, account_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, "logging out account");
+        self.account_states
+            .lock()
+            .await
+            .insert(account_id, AccountAuthState::LoggingOut);
+        self.pending_logouts.lock().await.insert(account_id);
+
+        let req = ProtoOaAccountLogoutReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaAccountLogoutReq as i32),
+            ctid_trader_account_id: account_id,
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaAccountLogoutReq as u32,
+            req,
+            Some(format!("account logout {}", account_id)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Authorization state of the account, `None` if it was never authorized
+    /// through this client.
+    pub async fn account_state(&self, account_id: i64) -> Option<AccountAuthState> {
+        self.account_states.lock().await.get(&account_id).copied()
+    }
+
+    pub(crate) async fn set_account_state(&self, account_ids: &[i64], state: AccountAuthState) {
+        let mut states = self.account_states.lock().await;
+        for account_id in account_ids {
+            states.insert(*account_id, state);
+        }
+    }
+
     /// Return a clone of the cached symbol metadata, if available.
     pub async fn symbol_data(&self, symbol_id: u64) -> Option<crate::types::SymbolData> {
         self.symbol_data.lock().await.get(&symbol_id).cloned()
     }
 
+    pub async fn get_assets(&self, account_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, "getting assets");
+        let req = ProtoOaAssetListReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaAssetListReq as i32),
+            ctid_trader_account_id: account_id,
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaAssetListReq as u32,
+            req,
+            Some(String::from("get asset list")),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Request the trader entity (balance, deposit asset, leverage) of an
+    /// account.  The answer is emitted as `StreamEvent::TraderData`.
+    pub async fn get_trader(&self, account_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, "getting trader");
+        let req = ProtoOaTraderReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaTraderReq as i32),
+            ctid_trader_account_id: account_id,
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaTraderReq as u32,
+            req,
+            Some(String::from("get trader")),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Request the chain of symbols converting `from_asset_id` into
+    /// `to_asset_id`.  Once it arrives the chain is cached, its spot feeds are
+    /// subscribed and `StreamEvent::ConversionChainData` is emitted.
+    pub async fn get_conversion_chain(
+        &self,
+        account_id: i64,
+        from_asset_id: i64,
+        to_asset_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, from_asset_id, to_asset_id, "getting conversion chain");
+        let req = ProtoOaSymbolsForConversionReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaSymbolsForConversionReq as i32),
+            ctid_trader_account_id: account_id,
+            first_asset_id: from_asset_id,
+            last_asset_id: to_asset_id,
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaSymbolsForConversionReq as u32,
+            req,
+            Some(format!("conversion chain {} {}", from_asset_id, to_asset_id)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Make sure the quote->deposit and base->deposit chains of a symbol are
+    /// available.  Requires `get_symbols` and `get_trader` to have been answered
+    /// for the account so that the symbol assets and the deposit asset are
+    /// known.
+    pub async fn watch_symbol_conversion(
+        &self,
+        account_id: i64,
+        symbol_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let missing = {
+            let conversion = self.conversion.lock().await;
+            let (base_asset, quote_asset) = conversion
+                .symbol_assets(symbol_id)
+                .ok_or_else(|| format!("unknown assets for symbol {}, call get_symbols first", symbol_id))?;
+            let deposit_asset = conversion
+                .deposit_asset(account_id)
+                .ok_or_else(|| format!("unknown deposit asset for account {}, call get_trader first", account_id))?;
+
+            let mut needed = vec![(quote_asset, deposit_asset), (base_asset, deposit_asset)];
+            // the dynamic leverage tiers are expressed in usd
+            if let Some(usd_asset) = conversion.asset_id_by_name("USD") {
+                needed.push((base_asset, usd_asset));
+            }
+
+            needed
+                .into_iter()
+                .filter(|(from, to)| !conversion.has_chain(*from, *to))
+                .collect::<Vec<_>>()
+        };
+
+        for (from_asset_id, to_asset_id) in missing {
+            self.get_conversion_chain(account_id, from_asset_id, to_asset_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Request the margin call thresholds of the account.  The response is
+    /// emitted as `StreamEvent::MarginCallsData`.
+    pub async fn get_margin_calls(&self, account_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, "getting margin calls");
+        let req = ProtoOaMarginCallListReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaMarginCallListReq as i32),
+            ctid_trader_account_id: account_id,
+        };
+
+        // the response does not carry the account id, so it travels in the message id
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaMarginCallListReq as u32,
+            req,
+            Some(format!("margin call list {}", account_id)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Change the threshold of one of the account's margin calls.  The server
+    /// confirms with a `StreamEvent::MarginCallUpdated`.
+    pub async fn update_margin_call(&self, margin_call: MarginCall) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(
+            account_id = margin_call.account_id,
+            margin_call_type = ?margin_call.margin_call_type,
+            "updating margin call"
+        );
+        let req = ProtoOaMarginCallUpdateReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaMarginCallUpdateReq as i32),
+            ctid_trader_account_id: margin_call.account_id,
+            margin_call: ProtoOaMarginCall {
+                margin_call_type: margin_call.margin_call_type.to_proto() as i32,
+                margin_level_threshold: margin_call.margin_level_threshold,
+                utc_last_update_timestamp: None,
+            },
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaMarginCallUpdateReq as u32,
+            req,
+            Some(String::from("update margin call")),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Guard an account locally: once its margin level (equity / used margin)
+    /// drops under `threshold` percent, `action` is taken and
+    /// `StreamEvent::MarginGuardTriggered` is emitted.  Pass `None` to remove
+    /// the guard.  The equity uses the server pnl, so keep it fresh with
+    /// `get_position_unrealized_pnl` and load the balance with `get_trader`.
+    pub async fn set_margin_guard(&self, account_id: i64, threshold: Option<f64>, action: GuardAction) {
+        let config = threshold.map(|threshold| MarginGuardConfig { threshold, action });
+        self.margin_guard.lock().await.configure(account_id, config);
+    }
+
+    /// Current margin level of the account in percent as seen by the guard.
+    pub async fn margin_level(&self, account_id: i64) -> Option<f64> {
+        self.margin_guard.lock().await.margin_level(account_id)
+    }
+
+    /// Request the open positions and pending orders of an account.  The
+    /// margin guard is seeded from the answer, so positions opened before
+    /// this client connected count towards the margin level.  Sent
+    /// automatically whenever an account is authorized, also after a
+    /// reconnect.
+    pub async fn reconcile(&self, account_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, "reconciling account");
+        let req = ProtoOaReconcileReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaReconcileReq as i32),
+            ctid_trader_account_id: account_id,
+            return_protection_orders: None,
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaReconcileReq as u32,
+            req,
+            Some(String::from("reconcile")),
+        )
+        .await?;
+        Ok(())
+    }
+
+    // a request made while handling a message can fail (refused by the
+    // version policy, shutting down, a failed write).  that must not end the
+    // read loop, so the failure is logged and reported on the account's
+    // stream instead.
+    pub(crate) async fn report_failure(&self, account_id: Option<i64>, what: &str, error: &str) {
+        warn!(account_id, error = %error, "{}", what);
+        let _ = self
+            .emit(account_id, crate::StreamEvent::Error(format!("{}: {}", what, error)))
+            .await;
+    }
+
+    // re-evaluates the margin guard after an update and acts on an alert
+    async fn check_margin_guard(&self, account_id: i64) {
+        let (alert, positions) = {
+            let mut guard = self.margin_guard.lock().await;
+            match guard.evaluate(account_id) {
+                Some(alert) => (alert, guard.open_positions(account_id)),
+                None => return,
+            }
+        };
+
+        warn!(
+            account_id,
+            margin_level = alert.margin_level,
+            threshold = alert.threshold,
+            action = ?alert.action,
+            "margin level under the guard threshold"
+        );
+        if alert.action == GuardAction::Flatten {
+            for (position_id, volume) in positions {
+                let closed = self.close_position(account_id, position_id, volume).await;
+                if let Err(e) = closed.map_err(|e| e.to_string()) {
+                    let what = format!("could not close position {} for the margin guard", position_id);
+                    self.report_failure(Some(account_id), &what, &e).await;
+                }
+            }
+        }
+
+        let _ = self
+            .emit(Some(account_id), crate::StreamEvent::MarginGuardTriggered(alert))
+            .await;
+    }
+
+    /// Request the dynamic leverage tiers referenced by a symbol's
+    /// `leverage_id`.  They are cached and emitted as
+    /// `StreamEvent::DynamicLeverageData`.  Symbols fetched with
+    /// `get_symbol_by_id` load their tiers automatically.
+    pub async fn get_dynamic_leverage(&self, account_id: i64, leverage_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        debug!(account_id, leverage_id, "getting dynamic leverage");
+        let req = ProtoOaGetDynamicLeverageByIdReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaGetDynamicLeverageReq as i32),
+            ctid_trader_account_id: account_id,
+            leverage_id,
+        };
+
+        self.send_message(
+            ProtoOaPayloadType::ProtoOaGetDynamicLeverageReq as u32,
+            req,
+            Some(String::from("get dynamic leverage")),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Local estimate of the margin, in the deposit currency, required to open
+    /// `volume` (protocol units) of the symbol, following the symbol's dynamic
+    /// leverage tiers capped by the account leverage.  Needs the symbol data,
+    /// the trader and the conversion chains (`watch_symbol_conversion`).
+    pub async fn estimate_margin(&self, account_id: i64, symbol_id: i64, volume: i64) -> Option<f64> {
+        let symbol = self.symbol_data(symbol_id as u64).await?;
+        let units = volume as f64 / 100.0;
+
+        let (deposit_notional, usd_notional) = {
+            let conversion = self.conversion.lock().await;
+            let (base_asset, _) = conversion.symbol_assets(symbol_id)?;
+            let deposit_asset = conversion.deposit_asset(account_id)?;
+            let deposit_notional = conversion.convert(units, base_asset, deposit_asset)?;
+            // without a usd chain the deposit currency stands in for usd
+            let usd_notional = conversion
+                .asset_id_by_name("USD")
+                .and_then(|usd_asset| conversion.convert(units, base_asset, usd_asset))
+                .unwrap_or(deposit_notional);
+            (deposit_notional, usd_notional)
+        };
+
+        self.leverage
+            .lock()
+            .await
+            .margin_required(account_id, symbol.leverage_id, usd_notional, deposit_notional)
+    }
+
+    /// Value of one pip for one lot of the symbol in the deposit currency.
+    /// `None` until the symbol data, trader and conversion prices are known.
+    pub async fn pip_value_per_lot(&self, account_id: i64, symbol_id: i64) -> Option<f64> {
+        let symbol = self.symbol_data(symbol_id as u64).await?;
+        self.conversion.lock().await.pip_value_per_lot(account_id, &symbol)
+    }
+
+    /// Locally computed unrealized PnL of a position in the deposit currency.
+    pub async fn local_unrealized_pnl(&self, position: &Position) -> Option<f64> {
+        self.conversion.lock().await.unrealized_pnl(position)
+    }
+
+    /// Notional exposure of a position in the deposit currency.
+    pub async fn notional_exposure(&self, position: &Position) -> Option<f64> {
+        self.conversion.lock().await.notional_exposure(position)
+    }
+
 
 }
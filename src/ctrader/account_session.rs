@@ -0,0 +1,243 @@
+// multi-account sessions.  any number of ctid trader accounts can be
+// authorized on the one connection; `CtraderClient::account` hands out an
+// `AccountHandle` bound to one of them so the account id does not have to be
+// repeated on every call, and `AccountHandle::events` moves the events of
+// that account from the shared stream to a channel of its own.
+
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+use crate::sizing::OrderBuilder;
+use crate::margin_guard::GuardAction;
+use crate::types::{AccountAuthState, MarginCall, Order, StreamEvent, TimeFrame, TradeSide};
+
+use super::CtraderClient;
+
+impl CtraderClient {
+    /// A handle on `account_id` sharing this client's connection.
+    pub fn account(self: &Arc<Self>, account_id: i64) -> AccountHandle {
+        AccountHandle {
+            client: Arc::clone(self),
+            account_id,
+        }
+    }
+
+    /// Send a ProtoOAAccountAuthReq for every account.  Each one is
+    /// confirmed with its own `StreamEvent::AccountAuthorized`.
+    pub async fn authorize_accounts(&self, account_ids: &[i64]) -> Result<(), Box<dyn std::error::Error>> {
+        for account_id in account_ids {
+            self.authorize_account(*account_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Accounts whose session is currently authorized on this connection.
+    pub async fn authorized_accounts(&self) -> Vec<i64> {
+        let mut accounts: Vec<i64> = self
+            .account_states
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, state)| **state == AccountAuthState::Authorized)
+            .map(|(account_id, _)| *account_id)
+            .collect();
+        accounts.sort_unstable();
+        accounts
+    }
+
+    /// Route the events of `account_id` to a dedicated channel instead of
+    /// the shared one returned by `connect`.  Subscribing again replaces the
+    /// previous channel; once the receiver is dropped the events go back to
+    /// the shared stream.
+    pub async fn account_events(&self, account_id: i64) -> mpsc::Receiver<StreamEvent> {
+        let (tx, rx) = mpsc::channel(self.settings.account_channel_capacity);
+        self.account_channels.lock().await.insert(account_id, tx);
+        rx
+    }
+
+    // sends an event to the channel of its account when one is registered,
+    // to the shared stream otherwise
+    pub(crate) async fn emit(
+        &self,
+        account_id: Option<i64>,
+        mut event: StreamEvent,
+    ) -> Result<(), mpsc::error::SendError<StreamEvent>> {
+        if let Some(account_id) = account_id {
+            let sender = self.account_channels.lock().await.get(&account_id).cloned();
+            if let Some(sender) = sender {
+                match sender.send(event).await {
+                    Ok(()) => return Ok(()),
+                    Err(mpsc::error::SendError(unsent)) => {
+                        self.account_channels.lock().await.remove(&account_id);
+                        event = unsent;
+                    }
+                }
+            }
+        }
+        let sent = self.event_tx.send(event).await;
+        if sent.is_err() {
+            self.metrics.lock().await.dropped_events += 1;
+        }
+        sent
+    }
+}
+
+/// One trading account on a shared `CtraderClient` connection.  Cheap to
+/// clone; every method forwards to the client with the bound account id.
+#[derive(Clone)]
+pub struct AccountHandle {
+    client: Arc<CtraderClient>,
+    account_id: i64,
+}
+
+impl AccountHandle {
+    pub fn id(&self) -> i64 {
+        self.account_id
+    }
+
+    pub fn client(&self) -> &Arc<CtraderClient> {
+        &self.client
+    }
+
+    pub async fn authorize(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.authorize_account(self.account_id).await
+    }
+
+    pub async fn logout(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.logout_account(self.account_id).await
+    }
+
+    pub async fn state(&self) -> Option<AccountAuthState> {
+        self.client.account_state(self.account_id).await
+    }
+
+    pub async fn is_authorized(&self) -> bool {
+        self.state().await == Some(AccountAuthState::Authorized)
+    }
+
+    /// The events of this account, see `CtraderClient::account_events`.
+    pub async fn events(&self) -> mpsc::Receiver<StreamEvent> {
+        self.client.account_events(self.account_id).await
+    }
+
+    pub async fn get_symbols(&self, include_archived: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.get_symbols(self.account_id, include_archived).await
+    }
+
+    pub async fn get_symbol_by_id(&self, symbol_id: i64) -> anyhow::Result<()> {
+        self.client.get_symbol_by_id(self.account_id, symbol_id, None).await
+    }
+
+    pub async fn get_trend_bar_data(
+        &self,
+        symbol_id: i64,
+        period: TimeFrame,
+        from_timestamp: i64,
+        to_timestamp: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.client
+            .get_trend_bar_data(symbol_id, period, self.account_id, from_timestamp, to_timestamp)
+            .await
+    }
+
+    pub async fn subscribe_spot(&self, symbol_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.subscribe_spot(self.account_id, symbol_id).await
+    }
+
+    pub async fn subscribe_live_bars(&self, symbol_id: i64, timeframe: TimeFrame) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.subscribe_live_bars(self.account_id, symbol_id, timeframe).await
+    }
+
+    pub async fn unsubscribe_spot(&self, symbol_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.unsubscribe_spot(self.account_id, symbol_id).await
+    }
+
+    pub async fn unsubscribe_live_bars(&self, symbol_id: i64, timeframe: TimeFrame) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.unsubscribe_live_bars(self.account_id, symbol_id, timeframe).await
+    }
+
+    /// An `OrderBuilder` for this account.
+    pub fn order(&self, symbol_id: u64, trade_side: TradeSide) -> OrderBuilder {
+        Order::builder(self.account_id as u64, symbol_id, trade_side)
+    }
+
+    /// Place an order on this account, whatever `order.account_id` says.
+    pub async fn new_order(&self, mut order: Order) -> Result<(), Box<dyn std::error::Error>> {
+        order.account_id = self.account_id as u64;
+        self.client.new_order(order).await
+    }
+
+    pub async fn close_position(&self, position_id: i64, volume: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.close_position(self.account_id, position_id, volume).await
+    }
+
+    pub async fn cancel_order(&self, order_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.cancel_order(self.account_id, order_id).await
+    }
+
+    pub async fn pending_orders(&self) -> Vec<i64> {
+        self.client.pending_orders(self.account_id).await
+    }
+
+    pub async fn get_open_positions(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.get_open_positions(self.account_id).await
+    }
+
+    pub async fn get_position_unrealized_pnl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.get_position_unrealized_pnl(self.account_id).await
+    }
+
+    pub async fn expected_margin(&self, symbol_id: i64, volumes: Vec<i64>) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.expected_margin(self.account_id, symbol_id, volumes).await
+    }
+
+    pub async fn get_assets(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.get_assets(self.account_id).await
+    }
+
+    pub async fn get_trader(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.get_trader(self.account_id).await
+    }
+
+    pub async fn get_conversion_chain(&self, from_asset_id: i64, to_asset_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.client
+            .get_conversion_chain(self.account_id, from_asset_id, to_asset_id)
+            .await
+    }
+
+    pub async fn watch_symbol_conversion(&self, symbol_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.watch_symbol_conversion(self.account_id, symbol_id).await
+    }
+
+    pub async fn pip_value_per_lot(&self, symbol_id: i64) -> Option<f64> {
+        self.client.pip_value_per_lot(self.account_id, symbol_id).await
+    }
+
+    pub async fn get_margin_calls(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.get_margin_calls(self.account_id).await
+    }
+
+    /// Change a margin call threshold of this account, whatever
+    /// `margin_call.account_id` says.
+    pub async fn update_margin_call(&self, mut margin_call: MarginCall) -> Result<(), Box<dyn std::error::Error>> {
+        margin_call.account_id = self.account_id;
+        self.client.update_margin_call(margin_call).await
+    }
+
+    pub async fn set_margin_guard(&self, threshold: Option<f64>, action: GuardAction) {
+        self.client.set_margin_guard(self.account_id, threshold, action).await
+    }
+
+    pub async fn margin_level(&self) -> Option<f64> {
+        self.client.margin_level(self.account_id).await
+    }
+
+    pub async fn get_dynamic_leverage(&self, leverage_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.client.get_dynamic_leverage(self.account_id, leverage_id).await
+    }
+
+    pub async fn estimate_margin(&self, symbol_id: i64, volume: i64) -> Option<f64> {
+        self.client.estimate_margin(self.account_id, symbol_id, volume).await
+    }
+}
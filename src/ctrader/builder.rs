@@ -0,0 +1,350 @@
+// the client builder.  `CtraderClient::connect` keeps working with its four
+// arguments; the builder covers everything else the connection can be told
+// and can start from a `CtraderConfig` read from a file or the environment.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use crate::config::{CtraderConfig, ReconnectPolicy, TransportKind};
+use crate::rate_limit::RateLimits;
+use crate::types::{Endpoint, StreamEvent};
+use crate::version::VersionPolicy;
+
+use super::stream_builder::{self, ConnectOptions, Proxy, TlsMode};
+use super::transport::{ProtobufTransport, Transport, WebSocketTransport};
+use super::CtraderClient;
+
+/// Everything about a connection that is not a credential.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientSettings {
+    pub endpoint: Endpoint,
+    pub transport: Arc<dyn Transport>,
+    pub options: ConnectOptions,
+    pub event_channel_capacity: usize,
+    pub account_channel_capacity: usize,
+    pub max_frame_size: usize,
+    pub heartbeat_interval: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub reconnect: Option<ReconnectPolicy>,
+    pub rate_limits: Option<RateLimits>,
+    pub record_file: Option<PathBuf>,
+    pub version_check: VersionPolicy,
+    pub metrics_addr: Option<SocketAddr>,
+    pub log_messages: bool,
+}
+
+impl ClientSettings {
+    pub fn new(endpoint: Endpoint, options: ConnectOptions) -> Self {
+        let defaults = CtraderConfig::default();
+        Self {
+            endpoint,
+            transport: Arc::new(ProtobufTransport),
+            options,
+            event_channel_capacity: defaults.event_channel_capacity,
+            account_channel_capacity: defaults.account_channel_capacity,
+            max_frame_size: defaults.max_frame_size,
+            heartbeat_interval: defaults.heartbeat_interval_secs.map(Duration::from_secs),
+            write_timeout: defaults.write_timeout_secs.map(Duration::from_secs),
+            reconnect: defaults.reconnect,
+            rate_limits: defaults.rate_limits,
+            record_file: defaults.record_file,
+            version_check: defaults.version_check,
+            metrics_addr: defaults.metrics_addr,
+            log_messages: defaults.log_messages,
+        }
+    }
+
+    // what `CtraderClient::connect` always did: no heartbeats and no rate
+    // limiting
+    pub fn legacy(endpoint: Endpoint, options: ConnectOptions) -> Self {
+        Self {
+            heartbeat_interval: None,
+            rate_limits: None,
+            ..Self::new(endpoint, options)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CtraderClientBuilder {
+    client_id: String,
+    client_secret: String,
+    access_token: String,
+    refresh_token: Option<String>,
+    settings: ClientSettings,
+}
+
+impl Default for CtraderClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CtraderClientBuilder {
+    pub fn new() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            access_token: String::new(),
+            refresh_token: None,
+            settings: ClientSettings::new(Endpoint::Demo, ConnectOptions::default()),
+        }
+    }
+
+    /// A builder holding everything set in `config`.
+    pub fn from_config(config: CtraderConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let tls = if config.plain_tcp {
+            TlsMode::Plain
+        } else if let Some(ca_file) = &config.ca_file {
+            TlsMode::CustomRoots(stream_builder::root_store_from_pem(ca_file)?)
+        } else {
+            TlsMode::WebPkiRoots
+        };
+        let proxy = config.proxy.as_deref().map(Proxy::from_url).transpose()?;
+        let transport: Arc<dyn Transport> = match config.transport {
+            TransportKind::Protobuf => Arc::new(ProtobufTransport),
+            TransportKind::WebSocket => Arc::new(WebSocketTransport),
+        };
+
+        Ok(Self {
+            client_id: config.client_id,
+            client_secret: config.client_secret,
+            access_token: config.access_token,
+            refresh_token: config.refresh_token,
+            settings: ClientSettings {
+                endpoint: config.endpoint,
+                transport,
+                options: ConnectOptions {
+                    tls,
+                    proxy,
+                    connect_timeout: Duration::from_secs(config.connect_timeout_secs),
+                    handshake_timeout: Duration::from_secs(config.handshake_timeout_secs),
+                },
+                event_channel_capacity: config.event_channel_capacity,
+                account_channel_capacity: config.account_channel_capacity,
+                max_frame_size: config.max_frame_size,
+                heartbeat_interval: config.heartbeat_interval_secs.map(Duration::from_secs),
+                write_timeout: config.write_timeout_secs.map(Duration::from_secs),
+                reconnect: config.reconnect,
+                rate_limits: config.rate_limits,
+                record_file: config.record_file,
+                version_check: config.version_check,
+                metrics_addr: config.metrics_addr,
+                log_messages: config.log_messages,
+            },
+        })
+    }
+
+    /// A builder from a TOML or JSON file, overridden by the `CTRADER_*`
+    /// environment variables.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = CtraderConfig::from_file(path)?;
+        config.apply_env()?;
+        Self::from_config(config)
+    }
+
+    /// A builder from the `CTRADER_*` environment variables only.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_config(CtraderConfig::from_env()?)
+    }
+
+    pub fn credentials(mut self, client_id: &str, client_secret: &str, access_token: &str) -> Self {
+        self.client_id = client_id.to_string();
+        self.client_secret = client_secret.to_string();
+        self.access_token = access_token.to_string();
+        self
+    }
+
+    pub fn access_token(mut self, access_token: &str) -> Self {
+        self.access_token = access_token.to_string();
+        self
+    }
+
+    /// Used for in-protocol token refreshes, see `set_refresh_token`.
+    pub fn refresh_token(mut self, refresh_token: &str) -> Self {
+        self.refresh_token = Some(refresh_token.to_string());
+        self
+    }
+
+    pub fn endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.settings.endpoint = endpoint;
+        self
+    }
+
+    /// How messages are exchanged, `ProtobufTransport` unless set.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.settings.transport = Arc::new(transport);
+        self
+    }
+
+    pub fn connect_options(mut self, options: ConnectOptions) -> Self {
+        self.settings.options = options;
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsMode) -> Self {
+        self.settings.options.tls = tls;
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.settings.options.proxy = Some(proxy);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.options.connect_timeout = timeout;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.options.handshake_timeout = timeout;
+        self
+    }
+
+    /// Limit for writing one request to the connection.  The response is
+    /// not waited for, it arrives as an event.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.settings.write_timeout = timeout;
+        self
+    }
+
+    /// How often `start` sends a heartbeat, `None` to never send one.
+    pub fn heartbeat_interval(mut self, interval: Option<Duration>) -> Self {
+        self.settings.heartbeat_interval = interval;
+        self
+    }
+
+    /// Capacity of the shared event channel returned by `build`.
+    pub fn event_channel_capacity(mut self, capacity: usize) -> Self {
+        self.settings.event_channel_capacity = capacity;
+        self
+    }
+
+    /// Capacity of the channels returned by `account_events`.
+    pub fn account_channel_capacity(mut self, capacity: usize) -> Self {
+        self.settings.account_channel_capacity = capacity;
+        self
+    }
+
+    /// Largest message accepted from the server; a longer frame is treated
+    /// as a broken connection instead of being allocated.
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.settings.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Reconnect after the connection drops, `None` to stop the read loop.
+    pub fn reconnect(mut self, policy: Option<ReconnectPolicy>) -> Self {
+        self.settings.reconnect = policy;
+        self
+    }
+
+    /// Requests per second allowed before `send_message` starts queuing,
+    /// `None` to send everything right away.
+    pub fn rate_limits(mut self, limits: Option<RateLimits>) -> Self {
+        self.settings.rate_limits = limits;
+        self
+    }
+
+    /// Record every message sent and received to `path`, to be read with
+    /// `read_recording` or replayed with `ReplayTransport`.
+    pub fn record_to(mut self, path: impl AsRef<Path>) -> Self {
+        self.settings.record_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Request the server version when the read loop starts and after every
+    /// reconnect, and report or refuse trading on a mismatch.
+    pub fn version_check(mut self, policy: VersionPolicy) -> Self {
+        self.settings.version_check = policy;
+        self
+    }
+
+    /// Serve the client metrics in the prometheus text format at
+    /// `GET /metrics` on `addr`, see `CtraderClient::serve_metrics`.
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.settings.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Log every message sent and received at info level instead of debug,
+    /// with its payload type and `client_msg_id` but not its payload.
+    pub fn log_messages(mut self, enabled: bool) -> Self {
+        self.settings.log_messages = enabled;
+        self
+    }
+
+    // the live half of a `ConnectionPool` opened from one builder.  the demo
+    // half keeps the metrics address and the recording file, this one records
+    // next to it, `session.rec` becoming `session.live.rec`
+    pub(crate) fn live_half(mut self) -> Self {
+        self.settings.endpoint = Endpoint::Live;
+        self.settings.metrics_addr = None;
+        self.settings.record_file = self.settings.record_file.map(|path| {
+            let extension = match path.extension() {
+                Some(extension) => format!("live.{}", extension.to_string_lossy()),
+                None => "live".to_string(),
+            };
+            path.with_extension(extension)
+        });
+        self
+    }
+
+    /// Open the connection.
+    pub async fn build(self) -> Result<(Arc<CtraderClient>, mpsc::Receiver<StreamEvent>), Box<dyn std::error::Error>> {
+        if self.client_id.is_empty() || self.client_secret.is_empty() {
+            return Err("the client id and client secret are required".into());
+        }
+        if self.settings.event_channel_capacity == 0 || self.settings.account_channel_capacity == 0 {
+            return Err("channel capacities must be greater than 0".into());
+        }
+        if self.settings.max_frame_size == 0 {
+            return Err("the maximum frame size must be greater than 0".into());
+        }
+
+        let (client, event_rx) = CtraderClient::open(
+            &self.client_id,
+            &self.client_secret,
+            &self.access_token,
+            self.settings,
+        )
+        .await?;
+        if let Some(refresh_token) = &self.refresh_token {
+            client.set_refresh_token(refresh_token).await;
+        }
+        if let Some(addr) = client.settings.metrics_addr {
+            client.serve_metrics(addr).await?;
+        }
+
+        Ok((client, event_rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_half_of_a_pool_records_and_serves_nothing_twice() {
+        let builder = CtraderClientBuilder::new()
+            .record_to("/tmp/session.rec")
+            .metrics_addr("127.0.0.1:9184".parse().unwrap())
+            .heartbeat_interval(Some(Duration::from_secs(3)));
+
+        let live = builder.clone().live_half();
+        assert_eq!(live.settings.endpoint, Endpoint::Live);
+        assert_eq!(live.settings.record_file, Some(PathBuf::from("/tmp/session.live.rec")));
+        assert_eq!(live.settings.metrics_addr, None);
+        assert_eq!(live.settings.heartbeat_interval, Some(Duration::from_secs(3)));
+        // the demo half keeps them
+        assert_eq!(builder.settings.metrics_addr, "127.0.0.1:9184".parse().ok());
+
+        let unnamed = CtraderClientBuilder::new().record_to("/tmp/session").live_half();
+        assert_eq!(unnamed.settings.record_file, Some(PathBuf::from("/tmp/session.live")));
+    }
+}
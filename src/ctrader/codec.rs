@@ -0,0 +1,229 @@
+// the framing of the open api: every `ProtoMessage` is preceded by its length
+// as a big endian u32.  `OpenApiCodec` turns a byte stream into messages and
+// back for `FramedRead` / `FramedWrite`.  a frame is decoded straight from the
+// read buffer, and the payload of the message is a slice of that buffer, so
+// receiving a spot event costs no allocation beyond the message itself.
+
+use std::fmt;
+
+use bytes::{Buf, BufMut, BytesMut};
+use prost::Message;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::open_api::ProtoMessage;
+
+/// Default limit for one frame, far above the largest symbol or deal list.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+const LENGTH_SIZE: usize = 4;
+
+#[derive(Debug)]
+pub enum CodecError {
+    /// the length prefix announces more than the configured maximum, the
+    /// stream is most likely corrupt
+    FrameTooLarge { len: usize, max: usize },
+    Decode(prost::DecodeError),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the maximum of {} bytes", len, max)
+            }
+            CodecError::Decode(e) => write!(f, "invalid protobuf message: {}", e),
+            CodecError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<prost::DecodeError> for CodecError {
+    fn from(e: prost::DecodeError) -> Self {
+        CodecError::Decode(e)
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(e: std::io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+impl From<CodecError> for std::io::Error {
+    fn from(e: CodecError) -> Self {
+        match e {
+            CodecError::Io(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OpenApiCodec {
+    max_frame_size: usize,
+}
+
+impl Default for OpenApiCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl OpenApiCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+}
+
+impl Decoder for OpenApiCodec {
+    type Item = ProtoMessage;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ProtoMessage>, CodecError> {
+        if src.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if len > self.max_frame_size {
+            return Err(CodecError::FrameTooLarge {
+                len,
+                max: self.max_frame_size,
+            });
+        }
+        if src.len() < LENGTH_SIZE + len {
+            // room for the rest of the frame, read in one go
+            src.reserve(LENGTH_SIZE + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_SIZE);
+        let frame = src.split_to(len).freeze();
+        Ok(Some(ProtoMessage::decode(frame)?))
+    }
+}
+
+impl Encoder<ProtoMessage> for OpenApiCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, message: ProtoMessage, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let len = message.encoded_len();
+        if len > self.max_frame_size {
+            return Err(CodecError::FrameTooLarge {
+                len,
+                max: self.max_frame_size,
+            });
+        }
+
+        dst.reserve(LENGTH_SIZE + len);
+        dst.put_u32(len as u32);
+        message
+            .encode(dst)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spot() -> ProtoMessage {
+        ProtoMessage {
+            payload_type: 2131,
+            payload: Some(vec![8, 1, 16, 2].into()),
+            client_msg_id: Some("spot".to_string()),
+        }
+    }
+
+    fn encoded(message: ProtoMessage) -> BytesMut {
+        let mut buf = BytesMut::new();
+        OpenApiCodec::default().encode(message, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trips_a_message() {
+        let mut buf = encoded(spot());
+        assert_eq!(OpenApiCodec::default().decode(&mut buf).unwrap(), Some(spot()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_whole_length_prefix() {
+        let frame = encoded(spot());
+        let mut codec = OpenApiCodec::default();
+        let mut buf = BytesMut::from(&frame[..3]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        // nothing is consumed until the frame is complete
+        assert_eq!(buf.len(), 3);
+
+        buf.extend_from_slice(&frame[3..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(spot()));
+    }
+
+    #[test]
+    fn waits_for_a_split_payload() {
+        let frame = encoded(spot());
+        let mut codec = OpenApiCodec::default();
+        let mut buf = BytesMut::new();
+
+        for chunk in frame[..frame.len() - 1].chunks(2) {
+            buf.extend_from_slice(chunk);
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        }
+        buf.extend_from_slice(&frame[frame.len() - 1..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(spot()));
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames() {
+        let mut buf = encoded(spot());
+        buf.extend_from_slice(&encoded(ProtoMessage::default()));
+        let mut codec = OpenApiCodec::default();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(spot()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(ProtoMessage::default()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_a_frame_over_the_maximum() {
+        let mut codec = OpenApiCodec::new(16);
+        // only the prefix has arrived, the frame is refused before buffering it
+        let mut buf = BytesMut::from(&(1024u32 * 1024 * 1024).to_be_bytes()[..]);
+
+        match codec.decode(&mut buf) {
+            Err(CodecError::FrameTooLarge { len, max }) => {
+                assert_eq!(len, 1024 * 1024 * 1024);
+                assert_eq!(max, 16);
+            }
+            other => panic!("expected FrameTooLarge, got {:?}", other),
+        }
+        assert!(buf.capacity() < 1024);
+
+        let mut out = BytesMut::new();
+        let large = ProtoMessage {
+            payload: Some(vec![0; 64].into()),
+            ..spot()
+        };
+        assert!(matches!(codec.encode(large, &mut out), Err(CodecError::FrameTooLarge { .. })));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn accepts_a_frame_of_exactly_the_maximum() {
+        let frame = encoded(spot());
+        let mut codec = OpenApiCodec::new(frame.len() - LENGTH_SIZE);
+        let mut buf = frame;
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(spot()));
+    }
+}
@@ -1,9 +1,21 @@
-use crate::types::{Account, BarData, Position, Quote, RelativeBarData, Scope, StreamEvent, Symbol};
+use crate::types::{
+    AccountAuthState, Account, Asset, CtidProfile, ExpectedMargin, Position, PositionUnrealizedPnl, Quote, RelativeBarData, Scope,
+    StreamEvent, Symbol, TradeSide, Trader, MarginCall, MarginCallType, Tokens,
+};
+use crate::conversion::{ConversionChain, ConversionLeg};
+use crate::leverage::{DynamicLeverage, LeverageTier};
+use crate::version::ServerVersion;
 use prost::Message;
+use tracing::{debug, trace, warn};
 
-use crate::utilities::handle_option_value;
+use crate::utilities::{handle_option_value, scale_money};
 
 impl super::CtraderClient {
+    #[tracing::instrument(
+        name = "handle_message",
+        skip_all,
+        fields(payload_type = msg.payload_type, client_msg_id = msg.client_msg_id.as_deref())
+    )]
     pub async fn handle_proto_message(
         &self,
         msg: super::ProtoMessage,
@@ -17,21 +29,28 @@ impl super::CtraderClient {
         match msg.payload_type as i32 {
             //this handles the reponse the ProtoOaApplicationAuthReq
             x if x == super::ProtoOaPayloadType::ProtoOaApplicationAuthRes as i32 => {
-                self.event_tx
-                    .send(StreamEvent::ApplicationAuthorized(String::from(
-                        "Application authorized successfully.",
-                    )))
-                    .await?;
+                self.emit(
+                    None,
+                    StreamEvent::ApplicationAuthorized(String::from("Application authorized successfully.")),
+                )
+                .await?;
             }
 
             //this handles the response from the ProtoOaAccountAuthReq
             x if x == super::ProtoOaPayloadType::ProtoOaAccountAuthRes as i32 => {
                 let decoded_msg = super::ProtoOaAccountAuthRes::decode(&msg.payload.unwrap()[..])?;
-                self.event_tx
-                    .send(StreamEvent::AccountAuthorized(String::from(
-                        "Account authorized successfully.",
-                    )))
-                    .await?;
+                self.set_account_state(&[decoded_msg.ctid_trader_account_id], AccountAuthState::Authorized)
+                    .await;
+                self.emit(
+                    Some(decoded_msg.ctid_trader_account_id),
+                    StreamEvent::AccountAuthorized(String::from("Account authorized successfully.")),
+                )
+                .await?;
+                // seed the margin guard with the positions that are already open
+                let account_id = decoded_msg.ctid_trader_account_id;
+                if let Err(e) = self.reconcile(account_id).await.map_err(|e| e.to_string()) {
+                    self.report_failure(Some(account_id), "could not reconcile the account", &e).await;
+                }
             }
 
             //this handles the response from the ProtoOaGetAccountsListByAccessTokenReq
@@ -43,20 +62,44 @@ impl super::CtraderClient {
                 for account in &res.ctid_trader_account {
                     let acc: Account = Account {
                         id: account.ctid_trader_account_id,
-                        broker: account.broker_title_short.clone().unwrap(),
-                        is_live: account.is_live.unwrap(),
+                        broker: account.broker_title_short.clone().unwrap_or_default(),
+                        is_live: account.is_live.unwrap_or_default(),
                         scope: if res.permission_scope == Some(1_i32) {
                             Scope::Trading
                         } else {
                             Scope::Accounts
                         },
+                        trader_login: account.trader_login,
+                        last_closing_deal_timestamp: account.last_closing_deal_timestamp,
+                        last_balance_update_timestamp: account.last_balance_update_timestamp,
                     };
                     accounts.push(acc);
                 }
 
-                self.event_tx
-                    .send(StreamEvent::AccountsData(accounts))
-                    .await?;
+                *self.accounts.lock().await = accounts.clone();
+
+                self.emit(None, StreamEvent::AccountsData(accounts)).await?;
+            }
+
+            //this handles the response from the ProtoOaGetCtidProfileByTokenReq
+            x if x == super::ProtoOaPayloadType::ProtoOaGetCtidProfileByTokenRes as i32 => {
+                let data = msg.payload.unwrap();
+                let res = super::ProtoOaGetCtidProfileByTokenRes::decode(&data[..])?;
+
+                self.emit(
+                    None,
+                    StreamEvent::CtidProfileData(CtidProfile {
+                        user_id: res.profile.user_id,
+                    }),
+                )
+                .await?;
+            }
+
+            //this handles the response from the ProtoOaVersionReq
+            x if x == super::ProtoOaPayloadType::ProtoOaVersionRes as i32 => {
+                let data = msg.payload.unwrap();
+                let version_res = super::ProtoOaVersionRes::decode(&data[..])?;
+                self.set_server_version(ServerVersion::new(&version_res.version)).await?;
             }
 
             //this handles the response from the ProtoOaGetSymbolsReq
@@ -64,17 +107,26 @@ impl super::CtraderClient {
                 let mut symbols = Vec::<Symbol>::new();
                 let data = msg.payload.unwrap();
                 let symbols_res = super::ProtoOaSymbolsListRes::decode(&data[..])?;
+                let mut conversion = self.conversion.lock().await;
                 for symbol in &symbols_res.symbol {
                     let sym = Symbol {
                         symbol_name: symbol.symbol_name.clone().unwrap(),
                         symbol_id: symbol.symbol_id as u64,
+                        base_asset_id: symbol.base_asset_id,
+                        quote_asset_id: symbol.quote_asset_id,
                     };
+                    if let (Some(base), Some(quote)) = (sym.base_asset_id, sym.quote_asset_id) {
+                        conversion.set_symbol_assets(symbol.symbol_id, base, quote);
+                    }
                     symbols.push(sym);
                 }
+                drop(conversion);
 
-                self.event_tx
-                    .send(StreamEvent::SymbolsData(symbols))
-                    .await?;
+                self.emit(
+                    Some(symbols_res.ctid_trader_account_id),
+                    StreamEvent::SymbolsData(symbols),
+                )
+                .await?;
             }
 
             //this handles the response from the ProtoOaGetHistoricalTrendbarsReq
@@ -98,46 +150,87 @@ impl super::CtraderClient {
                 }
 
                 //you can send trendbars via event channel if needed
-                self.event_tx
-                    .send(StreamEvent::TrendbarsData(trendbars))
-                    .await?;
+                self.emit(
+                    Some(trendbars_res.ctid_trader_account_id),
+                    StreamEvent::TrendbarsData(trendbars),
+                )
+                .await?;
             }
 
             //this handles any error response from the stream
             x if x == super::ProtoOaPayloadType::ProtoOaErrorRes as i32 => {
                 let data = msg.payload.unwrap();
                 let err_res = super::ProtoOaErrorRes::decode(&data[..])?;
+                warn!(
+                    account_id = err_res.ctid_trader_account_id,
+                    error_code = %err_res.error_code,
+                    description = err_res.description.as_deref(),
+                    "error response"
+                );
                 let err_msg = err_res.description.unwrap_or_default();
-                let _ = self.event_tx.send(StreamEvent::Error(err_msg)).await;
+                let _ = self
+                    .emit(err_res.ctid_trader_account_id, StreamEvent::Error(err_msg))
+                    .await;
             }
 
             //this handles heartbeat messages from the server
             x if x == super::ProtoPayloadType::HeartbeatEvent as i32 => {
-                println!("Heartbeat from the server received.");
+                trace!("heartbeat from the server");
             }
 
             //this handles any generic error response from the server
             x if x == super::ProtoPayloadType::ErrorRes as i32 => {
-                println!("Error response received from server.");
-                println!("Full message: {:#?}", msg);
+                let data = msg.payload.unwrap_or_default();
+                let err_res = crate::open_api::ProtoErrorRes::decode(&data[..])?;
+                warn!(
+                    error_code = %err_res.error_code,
+                    description = err_res.description.as_deref(),
+                    "error response"
+                );
             }
 
             //this handles spot data updates if you have subscribed to them
             x if x == super::ProtoOaPayloadType::ProtoOaSubscribeSpotsRes as i32 => {
-                self.event_tx
-                    .send(StreamEvent::SubscribeSpotsData(String::from(
-                        "Subscribed to spot data successfully.",
-                    )))
-                    .await?;
+                let data = msg.payload.unwrap();
+                let subscribe_res = super::ProtoOaSubscribeSpotsRes::decode(&data[..])?;
+                self.emit(
+                    Some(subscribe_res.ctid_trader_account_id),
+                    StreamEvent::SubscribeSpotsData(String::from("Subscribed to spot data successfully.")),
+                )
+                .await?;
             }
 
             //this handles the live_bars subscribed response
             x if x == super::ProtoOaPayloadType::ProtoOaSubscribeLiveTrendbarRes as i32 => {
-                self.event_tx
-                    .send(StreamEvent::SubscribeLiveBarsData(String::from(
-                        "Subscribed to live bars data successfully.",
-                    )))
-                    .await?;
+                let data = msg.payload.unwrap();
+                let subscribe_res = super::ProtoOaSubscribeLiveTrendbarRes::decode(&data[..])?;
+                self.emit(
+                    Some(subscribe_res.ctid_trader_account_id),
+                    StreamEvent::SubscribeLiveBarsData(String::from("Subscribed to live bars data successfully.")),
+                )
+                .await?;
+            }
+
+            //this handles the response to unsubscribing from spot data
+            x if x == super::ProtoOaPayloadType::ProtoOaUnsubscribeSpotsRes as i32 => {
+                let data = msg.payload.unwrap();
+                let unsubscribe_res = super::ProtoOaUnsubscribeSpotsRes::decode(&data[..])?;
+                self.emit(
+                    Some(unsubscribe_res.ctid_trader_account_id),
+                    StreamEvent::UnsubscribeSpotsData(String::from("Unsubscribed from spot data successfully.")),
+                )
+                .await?;
+            }
+
+            //this handles the response to unsubscribing from live bars
+            x if x == super::ProtoOaPayloadType::ProtoOaUnsubscribeLiveTrendbarRes as i32 => {
+                let data = msg.payload.unwrap();
+                let unsubscribe_res = super::ProtoOaUnsubscribeLiveTrendbarRes::decode(&data[..])?;
+                self.emit(
+                    Some(unsubscribe_res.ctid_trader_account_id),
+                    StreamEvent::UnsubscribeLiveBarsData(String::from("Unsubscribed from live bars data successfully.")),
+                )
+                .await?;
             }
 
             //this handles the ProtoSpotEvent
@@ -145,6 +238,7 @@ impl super::CtraderClient {
                 self.keep_alive().await?; //send a keep-alive message to prevent disconnection due to inactivity
                 let data = msg.payload.unwrap();
                 let spot_event = super::ProtoOaSpotEvent::decode(&data[..])?;
+                self.metrics.lock().await.spot_received(spot_event.symbol_id);
                 let quote = Quote {
                     symbol_id: spot_event.symbol_id,
                     bid: handle_option_value(spot_event.bid.map(|v| v as f64)),
@@ -153,6 +247,9 @@ impl super::CtraderClient {
                 }
                 .change_to_actual_quote_price();
 
+                // keep the conversion engine's prices current
+                self.conversion.lock().await.update_quote(&quote);
+
                 //give me a bar that has data with zeros
 
                 // if there are trendbars attached, check timestamp changes and handle closed candle
@@ -187,60 +284,174 @@ impl super::CtraderClient {
                         *last_quote_guard = Some(quote.clone());
                         *last_bar_ts_guard = Some(bar_data.timestamp);
 
-                        self.event_tx
-                            .send(StreamEvent::LiveData((
-                                Some(quote),
-                                Some(real_bar),
-                                Some(last_closed_bar),
-                            )))
-                            .await?;
+                        self.emit(
+                            Some(spot_event.ctid_trader_account_id),
+                            StreamEvent::LiveData((Some(quote), Some(real_bar), Some(last_closed_bar))),
+                        )
+                        .await?;
                     } else {
                         *last_bar_guard = Some(bar_data.clone());
                         *last_quote_guard = Some(quote.clone());
                         
 
-                        self.event_tx
-                            .send(StreamEvent::LiveData((Some(quote), Some(real_bar), None)))
-                            .await?;
+                        self.emit(
+                            Some(spot_event.ctid_trader_account_id),
+                            StreamEvent::LiveData((Some(quote), Some(real_bar), None)),
+                        )
+                        .await?;
                     }
                 } else {
-                    self.event_tx
-                        .send(StreamEvent::LiveData((Some(quote), None, None)))
-                        .await?;
+                    self.emit(
+                        Some(spot_event.ctid_trader_account_id),
+                        StreamEvent::LiveData((Some(quote), None, None)),
+                    )
+                    .await?;
                 }
             }
 
             //this handles the trade response from the server after placing a new order or closing a position
 
-            //this handles disconnection notifications
+            //this handles disconnection notifications, every account session is gone
             x if x == super::ProtoOaPayloadType::ProtoOaClientDisconnectEvent as i32 => {
-                println!("Disconnection notification received from server.");
-                println!("Full message: {:#?}", msg);
+                let data = msg.payload.unwrap();
+                let disconnect_event = super::ProtoOaClientDisconnectEvent::decode(&data[..])?;
+                warn!(reason = disconnect_event.reason.as_deref(), "client disconnected by the server");
+
+                let account_ids: Vec<i64> = self.account_states.lock().await.keys().copied().collect();
+                self.set_account_state(&account_ids, AccountAuthState::Unauthorized).await;
+                self.emit(None, StreamEvent::ClientDisconnected(disconnect_event.reason))
+                    .await?;
+            }
+
+            //this handles the response from the ProtoOaAccountLogoutReq
+            x if x == super::ProtoOaPayloadType::ProtoOaAccountLogoutRes as i32 => {
+                let data = msg.payload.unwrap();
+                let logout_res = super::ProtoOaAccountLogoutRes::decode(&data[..])?;
+                self.pending_logouts.lock().await.remove(&logout_res.ctid_trader_account_id);
+                self.logged_out.notify_waiters();
+                self.emit(
+                    Some(logout_res.ctid_trader_account_id),
+                    StreamEvent::AccountLoggedOut(logout_res.ctid_trader_account_id),
+                )
+                .await?;
+            }
+
+            //this handles an account session dropped by the server
+            x if x == super::ProtoOaPayloadType::ProtoOaAccountDisconnectEvent as i32 => {
+                let data = msg.payload.unwrap();
+                let disconnect_event = super::ProtoOaAccountDisconnectEvent::decode(&data[..])?;
+                let account_id = disconnect_event.ctid_trader_account_id;
+                let logged_out = self.account_state(account_id).await == Some(AccountAuthState::LoggingOut);
+
+                self.set_account_state(&[account_id], AccountAuthState::Unauthorized).await;
+                self.emit(Some(account_id), StreamEvent::AccountDisconnected(account_id))
+                    .await?;
+
+                // a logout we asked for must not be undone
+                if !logged_out {
+                    self.reauthorize_accounts(vec![account_id]).await;
+                }
+            }
+
+            //this handles the response from the ProtoOaRefreshTokenReq
+            x if x == super::ProtoOaPayloadType::ProtoOaRefreshTokenRes as i32 => {
+                let data = msg.payload.unwrap();
+                let refresh_res = super::ProtoOaRefreshTokenRes::decode(&data[..])?;
+                self.apply_refreshed_tokens(Tokens::new(
+                    &refresh_res.access_token,
+                    &refresh_res.refresh_token,
+                    refresh_res.expires_in,
+                ))
+                .await?;
+            }
+
+            //this handles the accounts whose token was invalidated
+            x if x == super::ProtoOaPayloadType::ProtoOaAccountsTokenInvalidatedEvent as i32 => {
+                let data = msg.payload.unwrap();
+                let invalidated_event = super::ProtoOaAccountsTokenInvalidatedEvent::decode(&data[..])?;
+                let account_ids = invalidated_event.ctid_trader_account_ids;
+
+                self.set_account_state(&account_ids, AccountAuthState::Unauthorized).await;
+                self.emit(
+                    None,
+                    StreamEvent::AccountsTokenInvalidated((account_ids.clone(), invalidated_event.reason)),
+                )
+                .await?;
+                self.reauthorize_accounts(account_ids).await;
             }
 
             //this handles the order event errors
             x if x == super::ProtoOaPayloadType::ProtoOaOrderErrorEvent as i32 => {
                 let data = msg.payload.unwrap();
                 let err_event = super::ProtoOaOrderErrorEvent::decode(&data[..])?;
-                self.event_tx
-                    .send(StreamEvent::Error(format!(
+                self.metrics.lock().await.order_rejected(&err_event.error_code);
+                warn!(
+                    account_id = err_event.ctid_trader_account_id,
+                    error_code = %err_event.error_code,
+                    order_id = err_event.order_id,
+                    position_id = err_event.position_id,
+                    description = err_event.description.as_deref(),
+                    "order error"
+                );
+                self.emit(
+                    Some(err_event.ctid_trader_account_id),
+                    StreamEvent::Error(format!(
                         "Order error: {}",
                         err_event.description.unwrap_or_default()
-                    )))
-                    .await?;
+                    )),
+                )
+                .await?;
                 }
 
             //handles the execution event
             x if x == super::ProtoOaPayloadType::ProtoOaExecutionEvent as i32 => {
                 let data = msg.payload.unwrap();
                 let execution_event = super::ProtoOaExecutionEvent::decode(&data[..])?;
+                if execution_event.execution_type == crate::open_api::ProtoOaExecutionType::OrderRejected as i32 {
+                    let error_code = execution_event.error_code.as_deref().unwrap_or("ORDER_REJECTED");
+                    self.metrics.lock().await.order_rejected(error_code);
+                }
+                // remember the orders waiting to be filled, for `shutdown`
+                if let Some(order) = &execution_event.order
+                    && order.order_type != super::ProtoOaOrderType::Market as i32
+                {
+                    let mut pending = self.pending_orders.lock().await;
+                    let orders = pending.entry(execution_event.ctid_trader_account_id).or_default();
+                    if order.order_status == crate::open_api::ProtoOaOrderStatus::OrderStatusAccepted as i32 {
+                        orders.insert(order.order_id);
+                    } else {
+                        orders.remove(&order.order_id);
+                    }
+                }
                 let mut _position = Position::new();
                 if let Some(position) = execution_event.position{
                     _position.id = position.position_id;
                     _position.status = position.position_status;
                     _position.stop_loss = position.stop_loss;
                     _position.take_profit = position.take_profit;
-
+                    _position.price = position.price;
+                    _position.symbol_id = Some(position.trade_data.symbol_id);
+                    _position.trade_side = if position.trade_data.trade_side == super::ProtoOaTradeSide::Buy as i32 {
+                        Some(TradeSide::Buy)
+                    } else {
+                        Some(TradeSide::Sell)
+                    };
+                    _position.used_margin = position
+                        .used_margin
+                        .map(|margin| scale_money(margin as i64, position.money_digits.unwrap_or(2)));
+
+                    // keep the margin guard's view of the open positions current
+                    let mut guard = self.margin_guard.lock().await;
+                    if position.position_status == super::ProtoOaPositionStatus::PositionStatusOpen as i32 {
+                        guard.update_position(
+                            execution_event.ctid_trader_account_id,
+                            position.position_id,
+                            position.trade_data.volume,
+                            _position.used_margin,
+                        );
+                    } else if position.position_status == super::ProtoOaPositionStatus::PositionStatusClosed as i32 {
+                        guard.remove_position(execution_event.ctid_trader_account_id, position.position_id);
+                    }
                 }
                 if let Some(order) = execution_event.order{
                     _position.order_id = Some(order.order_id);
@@ -249,26 +460,29 @@ impl super::CtraderClient {
                 }
                 _position.account_id = Some(execution_event.ctid_trader_account_id);
                 if _position.status == 1_i32{
-                    self.event_tx
-                    .send(StreamEvent::ExecutionEvent(_position))
+                    self.emit(
+                        Some(execution_event.ctid_trader_account_id),
+                        StreamEvent::ExecutionEvent(_position),
+                    )
                     .await?;
                 }
-                
+                self.check_margin_guard(execution_event.ctid_trader_account_id).await;
+
             }
 
             //catch-all for unhandled message types
 
             //this handles the response from the ProtoOaGetSymbolByIdReq
             x if x == super::ProtoOaPayloadType::ProtoOaSymbolByIdRes as i32 => {
-                // only process the returned symbol when the request was the one we
-                // sent from `subscribe_live_bars` (identified by the specific
-                // client message string).  any other callers are ignored.
-                if msg.client_msg_id.as_deref() == Some("sent by live_bar_subscription") {
-                    let data = msg.payload.unwrap();
-                    let symbol_res = super::ProtoOaSymbolByIdRes::decode(&data[..])?;
-
-                    // store detailed fields in cache for later volume conversions
+                let data = msg.payload.unwrap();
+                let symbol_res = super::ProtoOaSymbolByIdRes::decode(&data[..])?;
+
+                // store detailed fields in cache for later volume conversions and
+                // margin estimates, whichever request asked for them
+                let mut missing_leverages = Vec::new();
+                {
                     let mut cache = self.symbol_data.lock().await;
+                    let leverage = self.leverage.lock().await;
                     for proto in &symbol_res.symbol {
                         let sd = crate::types::SymbolData {
                             symbol_id: proto.symbol_id as u64,
@@ -278,31 +492,357 @@ impl super::CtraderClient {
                             digits: Some(proto.digits),
                             pip_position: Some(proto.pip_position),
                             lot_size: proto.lot_size,
+                            leverage_id: proto.leverage_id,
                         };
+                        if let Some(leverage_id) = sd.leverage_id
+                            && !leverage.contains(leverage_id)
+                            && !missing_leverages.contains(&leverage_id)
+                        {
+                            missing_leverages.push(leverage_id);
+                        }
                         cache.insert(sd.symbol_id, sd);
-                        return Ok(()); // exit after caching the symbol data, no need to send an event
                     }
+                }
+
+                // no event is sent for the symbol data itself, only the tiers follow
+                for leverage_id in missing_leverages {
+                    self.get_dynamic_leverage(symbol_res.ctid_trader_account_id, leverage_id)
+                        .await?;
+                }
+            }
+
+            //this handles the response from the ProtoOaGetDynamicLeverageByIDReq
+            x if x == super::ProtoOaPayloadType::ProtoOaGetDynamicLeverageRes as i32 => {
+                let data = msg.payload.unwrap();
+                let leverage_res = super::ProtoOaGetDynamicLeverageByIdRes::decode(&data[..])?;
+                let mut tiers: Vec<LeverageTier> = leverage_res
+                    .leverage
+                    .tiers
+                    .iter()
+                    .map(|tier| LeverageTier {
+                        volume: tier.volume,
+                        leverage: tier.leverage,
+                    })
+                    .collect();
+                tiers.sort_by_key(|tier| tier.volume);
+
+                let leverage = DynamicLeverage {
+                    leverage_id: leverage_res.leverage.leverage_id,
+                    tiers,
+                };
+                self.leverage.lock().await.insert(leverage.clone());
+                self.emit(
+                    Some(leverage_res.ctid_trader_account_id),
+                    StreamEvent::DynamicLeverageData(leverage),
+                )
+                .await?;
+            }
 
-                    let mut symbols = Vec::<Symbol>::new();
-                    for symbol in symbol_res.symbol {
-                        symbols.push(Symbol {
-                            symbol_id: symbol.symbol_id as u64,
-                            symbol_name: symbol.symbol_id.to_string(),
-                        });
+            //this handles the response from the ProtoOaGetPositionUnrealizedPnLReq
+            x if x == super::ProtoOaPayloadType::ProtoOaGetPositionUnrealizedPnlRes as i32 => {
+                let data = msg.payload.unwrap();
+                let pnl_res = super::ProtoOaGetPositionUnrealizedPnLRes::decode(&data[..])?;
+
+                let pnls = pnl_res
+                    .position_unrealized_pn_l
+                    .iter()
+                    .map(|pnl| PositionUnrealizedPnl {
+                        account_id: pnl_res.ctid_trader_account_id,
+                        position_id: pnl.position_id,
+                        gross_unrealized_pnl: scale_money(pnl.gross_unrealized_pn_l, pnl_res.money_digits),
+                        net_unrealized_pnl: scale_money(pnl.net_unrealized_pn_l, pnl_res.money_digits),
+                    })
+                    .collect::<Vec<_>>();
+
+                {
+                    let mut guard = self.margin_guard.lock().await;
+                    for pnl in &pnls {
+                        guard.set_unrealized_pnl(pnl.account_id, pnl.position_id, pnl.net_unrealized_pnl);
                     }
+                }
 
-                    self.event_tx
-                        .send(StreamEvent::SymbolsData(symbols))
-                        .await?;
+                self.emit(
+                    Some(pnl_res.ctid_trader_account_id),
+                    StreamEvent::PositionUnrealizedPnlData(pnls),
+                )
+                .await?;
+                self.check_margin_guard(pnl_res.ctid_trader_account_id).await;
+            }
+
+            //this handles the response from the ProtoOaExpectedMarginReq
+            x if x == super::ProtoOaPayloadType::ProtoOaExpectedMarginRes as i32 => {
+                let data = msg.payload.unwrap();
+                let margin_res = super::ProtoOaExpectedMarginRes::decode(&data[..])?;
+                let money_digits = margin_res.money_digits.unwrap_or(2);
+                let symbol_id = msg
+                    .client_msg_id
+                    .as_deref()
+                    .and_then(|id| id.strip_prefix("expected margin "))
+                    .and_then(|id| id.parse::<i64>().ok());
+
+                let margins = margin_res
+                    .margin
+                    .iter()
+                    .map(|margin| ExpectedMargin {
+                        account_id: margin_res.ctid_trader_account_id,
+                        symbol_id,
+                        volume: margin.volume,
+                        buy_margin: scale_money(margin.buy_margin, money_digits),
+                        sell_margin: scale_money(margin.sell_margin, money_digits),
+                    })
+                    .collect();
+
+                self.emit(
+                    Some(margin_res.ctid_trader_account_id),
+                    StreamEvent::ExpectedMarginData(margins),
+                )
+                .await?;
+            }
+
+            //this handles the response from the ProtoOaAssetListReq
+            x if x == super::ProtoOaPayloadType::ProtoOaAssetListRes as i32 => {
+                let data = msg.payload.unwrap();
+                let asset_res = super::ProtoOaAssetListRes::decode(&data[..])?;
+                let assets: Vec<Asset> = asset_res
+                    .asset
+                    .into_iter()
+                    .map(|asset| Asset {
+                        asset_id: asset.asset_id,
+                        name: asset.name,
+                        display_name: asset.display_name,
+                        digits: asset.digits,
+                    })
+                    .collect();
+
+                self.conversion.lock().await.add_assets(&assets);
+                self.emit(
+                    Some(asset_res.ctid_trader_account_id),
+                    StreamEvent::AssetsData(assets),
+                )
+                .await?;
+            }
+
+            //this handles the response from the ProtoOaTraderReq and the trader update events
+            x if x == super::ProtoOaPayloadType::ProtoOaTraderRes as i32
+                || x == super::ProtoOaPayloadType::ProtoOaTraderUpdateEvent as i32 =>
+            {
+                let data = msg.payload.unwrap();
+                let trader = if x == super::ProtoOaPayloadType::ProtoOaTraderRes as i32 {
+                    super::ProtoOaTraderRes::decode(&data[..])?.trader
+                } else {
+                    super::ProtoOaTraderUpdatedEvent::decode(&data[..])?.trader
+                };
+                let money_digits = trader.money_digits.unwrap_or(2);
+                let trader = Trader {
+                    account_id: trader.ctid_trader_account_id,
+                    balance: scale_money(trader.balance, money_digits),
+                    deposit_asset_id: trader.deposit_asset_id,
+                    leverage: trader.leverage_in_cents.map(|l| l as f64 / 100.0),
+                    money_digits,
+                };
+
+                self.conversion
+                    .lock()
+                    .await
+                    .set_deposit_asset(trader.account_id, trader.deposit_asset_id);
+                self.margin_guard
+                    .lock()
+                    .await
+                    .set_balance(trader.account_id, trader.balance);
+                if let Some(account_leverage) = trader.leverage {
+                    self.leverage
+                        .lock()
+                        .await
+                        .set_account_leverage(trader.account_id, account_leverage);
+                }
+                let account_id = trader.account_id;
+                self.emit(Some(account_id), StreamEvent::TraderData(trader))
+                    .await?;
+                self.check_margin_guard(account_id).await;
+            }
+
+            //this handles the margin changes of a position
+            x if x == super::ProtoOaPayloadType::ProtoOaMarginChangedEvent as i32 => {
+                let data = msg.payload.unwrap();
+                let margin_event = super::ProtoOaMarginChangedEvent::decode(&data[..])?;
+                let used_margin = scale_money(
+                    margin_event.used_margin as i64,
+                    margin_event.money_digits.unwrap_or(2),
+                );
+
+                self.margin_guard.lock().await.set_used_margin(
+                    margin_event.ctid_trader_account_id,
+                    margin_event.position_id as i64,
+                    used_margin,
+                );
+                self.check_margin_guard(margin_event.ctid_trader_account_id).await;
+            }
+
+            //this handles the response from the ProtoOaReconcileReq
+            x if x == super::ProtoOaPayloadType::ProtoOaReconcileRes as i32 => {
+                let data = msg.payload.unwrap();
+                let reconcile_res = super::ProtoOaReconcileRes::decode(&data[..])?;
+                let positions: Vec<(i64, i64, f64)> = reconcile_res
+                    .position
+                    .iter()
+                    .map(|position| {
+                        let used_margin = position
+                            .used_margin
+                            .map(|margin| scale_money(margin as i64, position.money_digits.unwrap_or(2)))
+                            .unwrap_or_default();
+                        (position.position_id, position.trade_data.volume, used_margin)
+                    })
+                    .collect();
+
+                self.margin_guard
+                    .lock()
+                    .await
+                    .reconcile_positions(reconcile_res.ctid_trader_account_id, &positions);
+                self.check_margin_guard(reconcile_res.ctid_trader_account_id).await;
+            }
+
+            //this handles the response from the ProtoOaMarginCallListReq
+            x if x == super::ProtoOaPayloadType::ProtoOaMarginCallListRes as i32 => {
+                let data = msg.payload.unwrap();
+                let list_res = super::ProtoOaMarginCallListRes::decode(&data[..])?;
+                let account_id = msg
+                    .client_msg_id
+                    .as_deref()
+                    .and_then(|id| id.strip_prefix("margin call list "))
+                    .and_then(|id| id.parse::<i64>().ok())
+                    .unwrap_or_default();
+
+                let margin_calls = list_res
+                    .margin_call
+                    .iter()
+                    .filter_map(|margin_call| margin_call_from_proto(account_id, margin_call))
+                    .collect();
+
+                self.emit(Some(account_id), StreamEvent::MarginCallsData(margin_calls))
+                    .await?;
+            }
+
+            //this handles the response from the ProtoOaMarginCallUpdateReq, the
+            //MarginCallUpdateEvent that follows carries the new values
+            x if x == super::ProtoOaPayloadType::ProtoOaMarginCallUpdateRes as i32 => {
+                debug!("margin call update accepted by the server");
+            }
+
+            //this handles the margin call update and trigger events
+            x if x == super::ProtoOaPayloadType::ProtoOaMarginCallUpdateEvent as i32 => {
+                let data = msg.payload.unwrap();
+                let update_event = super::ProtoOaMarginCallUpdateEvent::decode(&data[..])?;
+                if let Some(margin_call) =
+                    margin_call_from_proto(update_event.ctid_trader_account_id, &update_event.margin_call)
+                {
+                    self.emit(
+                        Some(update_event.ctid_trader_account_id),
+                        StreamEvent::MarginCallUpdated(margin_call),
+                    )
+                    .await?;
                 }
             }
 
+            x if x == super::ProtoOaPayloadType::ProtoOaMarginCallTriggerEvent as i32 => {
+                let data = msg.payload.unwrap();
+                let trigger_event = super::ProtoOaMarginCallTriggerEvent::decode(&data[..])?;
+                if let Some(margin_call) =
+                    margin_call_from_proto(trigger_event.ctid_trader_account_id, &trigger_event.margin_call)
+                {
+                    self.emit(
+                        Some(trigger_event.ctid_trader_account_id),
+                        StreamEvent::MarginCallTriggered(margin_call),
+                    )
+                    .await?;
+                }
+            }
+
+            //this handles the response from the ProtoOaSymbolsForConversionReq
+            x if x == super::ProtoOaPayloadType::ProtoOaSymbolsForConversionRes as i32 => {
+                let data = msg.payload.unwrap();
+                let conversion_res = super::ProtoOaSymbolsForConversionRes::decode(&data[..])?;
+
+                // the requested asset pair travels in the client message id
+                let assets: Vec<i64> = msg
+                    .client_msg_id
+                    .as_deref()
+                    .and_then(|id| id.strip_prefix("conversion chain "))
+                    .map(|ids| ids.split(' ').filter_map(|id| id.parse().ok()).collect())
+                    .unwrap_or_default();
+                let [from_asset_id, to_asset_id] = assets[..] else {
+                    return Ok(());
+                };
+
+                let legs: Vec<ConversionLeg> = conversion_res
+                    .symbol
+                    .iter()
+                    .filter_map(|symbol| {
+                        Some(ConversionLeg {
+                            symbol_id: symbol.symbol_id,
+                            base_asset_id: symbol.base_asset_id?,
+                            quote_asset_id: symbol.quote_asset_id?,
+                        })
+                    })
+                    .collect();
+                let chain = ConversionChain {
+                    account_id: conversion_res.ctid_trader_account_id,
+                    from_asset_id,
+                    to_asset_id,
+                    legs,
+                };
+
+                // open the spot feeds the chain needs to price itself
+                let to_subscribe: Vec<i64> = {
+                    let mut conversion = self.conversion.lock().await;
+                    for leg in &chain.legs {
+                        conversion.set_symbol_assets(leg.symbol_id, leg.base_asset_id, leg.quote_asset_id);
+                    }
+                    conversion.add_chain(chain.clone());
+                    chain
+                        .legs
+                        .iter()
+                        .filter(|leg| conversion.mark_subscribed(leg.symbol_id))
+                        .map(|leg| leg.symbol_id)
+                        .collect()
+                };
+                for symbol_id in to_subscribe {
+                    let subscribed = self
+                        .subscribe_spot(chain.account_id, symbol_id)
+                        .await
+                        .map_err(|e| e.to_string());
+                    if let Err(e) = subscribed {
+                        // left for the next chain using the symbol to open
+                        self.conversion.lock().await.unmark_subscribed(symbol_id);
+                        let what = format!("could not subscribe to the spots of symbol {} for a conversion chain", symbol_id);
+                        self.report_failure(Some(chain.account_id), &what, &e).await;
+                    }
+                }
+
+                self.emit(
+                    Some(conversion_res.ctid_trader_account_id),
+                    StreamEvent::ConversionChainData(chain),
+                )
+                .await?;
+            }
+
             _ => {
-                println!("Received unhandled message type: {}", msg.payload_type);
-                println!("Full message: {:#?}", msg);
+                // the payload itself is not logged, it may hold tokens
+                debug!(
+                    payload_len = msg.payload.as_ref().map_or(0, |payload| payload.len()),
+                    "unhandled message"
+                );
             }
         }
 
         Ok(())
     }
 }
+
+fn margin_call_from_proto(account_id: i64, margin_call: &super::ProtoOaMarginCall) -> Option<MarginCall> {
+    Some(MarginCall {
+        account_id,
+        margin_call_type: MarginCallType::from_proto(margin_call.margin_call_type)?,
+        margin_level_threshold: margin_call.margin_level_threshold,
+        last_update_timestamp: margin_call.utc_last_update_timestamp,
+    })
+}
@@ -0,0 +1,142 @@
+// graceful shutdown.  `start` hands out the handles of the tasks it spawns so
+// they can be supervised, and `shutdown` takes down what the client set up
+// on the server before stopping them: the live bar and spot subscriptions,
+// the pending orders when asked to, and the account sessions.  then the
+// recording is flushed, the background tasks end and the connection is
+// closed, which sends the TLS close_notify or the websocket close frame
+// instead of just dropping the socket.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures_util::SinkExt;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::types::StreamEvent;
+
+use super::CtraderClient;
+
+/// What `shutdown_with` does besides closing the connection.
+#[derive(Debug, Clone, Copy)]
+pub struct ShutdownOptions {
+    /// cancel the pending orders of every authorized account before logging
+    /// it out
+    pub cancel_pending_orders: bool,
+    /// how long to wait for the server to confirm the logouts
+    pub logout_timeout: Duration,
+}
+
+impl Default for ShutdownOptions {
+    fn default() -> Self {
+        Self {
+            cancel_pending_orders: false,
+            logout_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The tasks spawned by `CtraderClient::start`.
+#[derive(Debug)]
+pub struct ClientTasks {
+    pub read_loop: JoinHandle<()>,
+    /// `None` when heartbeats are disabled
+    pub heartbeat: Option<JoinHandle<()>>,
+}
+
+// resolves once `shutdown` was called, for the background tasks to select on
+pub(crate) async fn stopped(stop: &mut watch::Receiver<bool>) {
+    let _ = stop.wait_for(|stopped| *stopped).await;
+}
+
+impl CtraderClient {
+    /// `shutdown_with` the default options, pending orders stay in place.
+    pub async fn shutdown(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.shutdown_with(ShutdownOptions::default()).await
+    }
+
+    /// Unsubscribe every feed, cancel the pending orders if asked to and log
+    /// the accounts out, then flush the recording, stop the background tasks
+    /// and close the connection.  When a request fails the remaining ones
+    /// are skipped but the client is still shut down, and the first error is
+    /// returned.  `StreamEvent::Shutdown` is the last event; requests fail
+    /// from then on.
+    pub async fn shutdown_with(&self, options: ShutdownOptions) -> Result<(), Box<dyn std::error::Error>> {
+        if self.is_shut_down() {
+            return Ok(());
+        }
+        info!(cancel_pending_orders = options.cancel_pending_orders, "shutting down");
+
+        let mut result = self.end_sessions(options).await;
+        if let Err(e) = &result {
+            warn!(error = %e, "could not end the sessions cleanly");
+        }
+
+        // the read loop, heartbeat, token refresh and metrics tasks end here
+        self.stop.send_replace(true);
+
+        if let Some(recorder) = &self.recorder
+            && let Err(e) = recorder.flush().await
+        {
+            warn!(error = %e, "could not flush the recording");
+            result = result.and(Err(e.into()));
+        }
+        if let Err(e) = self.writer.lock().await.close().await {
+            warn!(error = %e, "could not close the connection cleanly");
+            result = result.and(Err(e.into()));
+        }
+
+        let _ = self.emit(None, StreamEvent::Shutdown).await;
+        info!("shut down");
+        result
+    }
+
+    /// Whether `shutdown` was called.
+    pub fn is_shut_down(&self) -> bool {
+        *self.stop.borrow()
+    }
+
+    async fn end_sessions(&self, options: ShutdownOptions) -> Result<(), Box<dyn std::error::Error>> {
+        // live bars ride on the spot subscription, so they go first
+        let live_bars: Vec<(i64, i64, i32)> = self.live_bar_subscriptions.lock().await.iter().copied().collect();
+        for (account_id, symbol_id, period) in live_bars {
+            self.unsubscribe_live_bar_period(account_id, symbol_id, period).await?;
+        }
+
+        let mut spots: HashMap<i64, Vec<i64>> = HashMap::new();
+        for (account_id, symbol_id) in self.spot_subscriptions.lock().await.iter() {
+            spots.entry(*account_id).or_default().push(*symbol_id);
+        }
+        for (account_id, symbol_ids) in spots {
+            self.unsubscribe_spots(account_id, symbol_ids).await?;
+        }
+
+        let accounts = self.authorized_accounts().await;
+        if options.cancel_pending_orders {
+            for &account_id in &accounts {
+                for order_id in self.pending_orders(account_id).await {
+                    self.cancel_order(account_id, order_id).await?;
+                }
+            }
+        }
+        for &account_id in &accounts {
+            self.logout_account(account_id).await?;
+        }
+
+        // the responses are handled by the read loop, still running
+        let logged_out = async {
+            loop {
+                let notified = self.logged_out.notified();
+                if self.pending_logouts.lock().await.is_empty() {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        tokio::time::timeout(options.logout_timeout, logged_out)
+            .await
+            .map_err(|_| "timed out waiting for the accounts to be logged out")?;
+        Ok(())
+    }
+}
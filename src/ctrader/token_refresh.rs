@@ -0,0 +1,280 @@
+// in-protocol token refresh.  instead of going through the https token
+// endpoint of `AuthClient`, the ProtoOARefreshTokenReq is sent on the live
+// connection; the response swaps the stored tokens in place, re-authorizes
+// the accounts waiting for it and hands the new tokens to the application.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep, timeout};
+use tracing::warn;
+
+use crate::token_store::TokenStore;
+use crate::types::{StreamEvent, Tokens};
+
+/// Called with the new tokens after every successful refresh so that the
+/// refresh token can be persisted.  It runs on a blocking thread, so it may
+/// write files.
+pub type TokenCallback = Arc<dyn Fn(&Tokens) + Send + Sync>;
+
+// refresh this long before the access token expires, or a tenth of the
+// token's lifetime when that is shorter
+const REFRESH_MARGIN: Duration = Duration::from_secs(60 * 60);
+// never wake up sooner than this, so that short lived tokens are not
+// refreshed in a tight loop
+const MIN_REFRESH_DELAY: Duration = Duration::from_secs(10);
+// how long to wait for the refresh response before trying again
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(60);
+// a failed refresh is retried after this, doubling up to the cap
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+// attempts in a row before the refresh task gives up
+const MAX_REFRESH_ATTEMPTS: u32 = 5;
+
+// how long to sleep before refreshing a token that expires in `expires_in`
+fn refresh_delay(expires_in: Duration) -> Duration {
+    let margin = REFRESH_MARGIN.min(expires_in / 10);
+    expires_in.saturating_sub(margin).max(MIN_REFRESH_DELAY)
+}
+
+// how long to wait before the next attempt after `failures` failed ones
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_DELAY
+        .saturating_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+impl super::CtraderClient {
+    /// Give the client a refresh token.  It is used by
+    /// `refresh_access_token` and to re-authorize accounts whose token is
+    /// invalidated or whose session is dropped.
+    pub async fn set_refresh_token(&self, refresh_token: &str) {
+        *self.refresh_token.lock().await = Some(refresh_token.to_string());
+    }
+
+    /// Register a callback receiving the new tokens after every refresh.
+    pub async fn on_token_refresh<F>(&self, callback: F)
+    where
+        F: Fn(&Tokens) + Send + Sync + 'static,
+    {
+        *self.token_callback.lock().await = Some(Arc::new(callback));
+    }
+
+    /// Send a ProtoOARefreshTokenReq with the stored refresh token.  When the
+    /// response arrives the stored access token is replaced,
+    /// `StreamEvent::AccessTokenRefreshed` is emitted and the callback runs.
+    pub async fn refresh_access_token(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let refresh_token = self
+            .refresh_token
+            .lock()
+            .await
+            .clone()
+            .ok_or("no refresh token set, call set_refresh_token first")?;
+
+        tracing::debug!("refreshing the access token");
+        let req = super::ProtoOaRefreshTokenReq {
+            payload_type: Some(super::ProtoOaPayloadType::ProtoOaRefreshTokenReq as i32),
+            refresh_token,
+        };
+
+        self.send_message(
+            super::ProtoOaPayloadType::ProtoOaRefreshTokenReq as u32,
+            req,
+            Some(String::from("refresh token")),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Spawn a task refreshing the access token shortly before it expires,
+    /// `expires_in` seconds from now (see `Tokens::expires_in`).  The expiry of
+    /// every refreshed token is followed automatically until `shutdown`.  A
+    /// failed refresh is retried with a growing delay; after
+    /// `MAX_REFRESH_ATTEMPTS` failures in a row the task reports the error
+    /// and ends.
+    pub fn start_token_refresh(self: &Arc<Self>, expires_in: i64) -> JoinHandle<()> {
+        let client = Arc::clone(self);
+        let mut stop = self.stop.subscribe();
+
+        tokio::spawn(async move {
+            client.set_token_expiry(expires_in).await;
+            let mut failures = 0;
+            loop {
+                let Some(expiry) = *client.token_expiry.lock().await else {
+                    return;
+                };
+                let delay = match failures {
+                    0 => refresh_delay(expiry.saturating_duration_since(Instant::now())),
+                    _ => retry_delay(failures),
+                };
+                tokio::select! {
+                    _ = sleep(delay) => {}
+                    _ = super::shutdown::stopped(&mut stop) => return,
+                }
+
+                let notified = client.token_refreshed.notified();
+                let sent = client.refresh_access_token().await.map_err(|e| e.to_string());
+                let error = match sent {
+                    Ok(()) => match timeout(REFRESH_TIMEOUT, notified).await {
+                        Ok(()) => {
+                            failures = 0;
+                            continue;
+                        }
+                        Err(_) => format!("no answer within {}s", REFRESH_TIMEOUT.as_secs()),
+                    },
+                    Err(e) => e,
+                };
+
+                failures += 1;
+                warn!(failures, error = %error, "could not refresh the access token");
+                if failures >= MAX_REFRESH_ATTEMPTS {
+                    let err_msg = format!(
+                        "Failed to refresh the access token after {} attempts: {}",
+                        failures, error
+                    );
+                    let _ = client.emit(None, StreamEvent::Error(err_msg)).await;
+                    return;
+                }
+            }
+        })
+    }
+
+    /// Take the tokens from `store`, save every refreshed token back into it
+    /// and keep the access token refreshed in the background.  The returned
+    /// handle is the refresh task of `start_token_refresh`.
+    pub async fn attach_token_store(
+        self: &Arc<Self>,
+        store: Arc<dyn TokenStore>,
+    ) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+        let tokens = store
+            .load()?
+            .ok_or("the token store is empty, run the authorization flow first")?;
+
+        *self.access_token.lock().await = tokens.access_token.clone();
+        self.set_refresh_token(&tokens.refresh_token).await;
+        self.on_token_refresh(move |tokens| {
+            if let Err(e) = store.save(tokens) {
+                tracing::error!(error = %e, "could not save the refreshed tokens");
+            }
+        })
+        .await;
+
+        Ok(self.start_token_refresh(tokens.remaining_secs()))
+    }
+
+    pub(crate) async fn set_token_expiry(&self, expires_in: i64) {
+        let expiry = Instant::now() + Duration::from_secs(expires_in.max(0) as u64);
+        *self.token_expiry.lock().await = Some(expiry);
+    }
+
+    // called by the handler with the ProtoOARefreshTokenRes
+    pub(crate) async fn apply_refreshed_tokens(&self, tokens: Tokens) -> Result<(), Box<dyn std::error::Error>> {
+        *self.access_token.lock().await = tokens.access_token.clone();
+        *self.refresh_token.lock().await = Some(tokens.refresh_token.clone());
+        self.set_token_expiry(tokens.expires_in).await;
+        self.token_refreshed.notify_waiters();
+
+        // taken out of the lock, the callback may block on file io
+        let callback = self.token_callback.lock().await.clone();
+        if let Some(callback) = callback {
+            let saved = tokens.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || callback(&saved)).await {
+                warn!(error = %e, "the token refresh callback panicked");
+            }
+        }
+        self.emit(None, StreamEvent::AccessTokenRefreshed(tokens)).await?;
+
+        let pending: Vec<i64> = self.pending_reauth.lock().await.drain(..).collect();
+        for account_id in pending {
+            self.authorize_again(account_id).await;
+        }
+        Ok(())
+    }
+
+    // authorizes the accounts again.  accounts authorized with an older token
+    // (e.g. invalidated because we refreshed) just use the current one, the
+    // others wait for a refresh.  does nothing without a refresh token.
+    // this runs in the read loop, so a failed request is reported on the
+    // accounts' streams instead of ending it.
+    pub(crate) async fn reauthorize_accounts(&self, account_ids: Vec<i64>) {
+        let access_token = self.access_token.lock().await.clone();
+        let (stale, current): (Vec<i64>, Vec<i64>) = {
+            let account_tokens = self.account_tokens.lock().await;
+            account_ids
+                .into_iter()
+                .partition(|id| account_tokens.get(id).is_some_and(|token| *token != access_token))
+        };
+
+        for account_id in stale {
+            self.authorize_again(account_id).await;
+        }
+        if current.is_empty() || self.refresh_token.lock().await.is_none() {
+            return;
+        }
+
+        let refresh_in_flight = {
+            let mut pending = self.pending_reauth.lock().await;
+            let in_flight = !pending.is_empty();
+            for account_id in current {
+                if !pending.contains(&account_id) {
+                    pending.push(account_id);
+                }
+            }
+            in_flight
+        };
+
+        if refresh_in_flight {
+            return;
+        }
+        let sent = self.refresh_access_token().await.map_err(|e| e.to_string());
+        if let Err(e) = sent {
+            // no answer is coming, so nobody waits for it any more
+            let pending: Vec<i64> = self.pending_reauth.lock().await.drain(..).collect();
+            for account_id in pending {
+                self.report_failure(Some(account_id), "could not refresh the token to re-authorize the account", &e)
+                    .await;
+            }
+        }
+    }
+
+    async fn authorize_again(&self, account_id: i64) {
+        let sent = self.authorize_account(account_id).await.map_err(|e| e.to_string());
+        if let Err(e) = sent {
+            self.report_failure(Some(account_id), "could not re-authorize the account", &e).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_delay_keeps_an_hour_for_long_lived_tokens() {
+        let month = Duration::from_secs(30 * 24 * 60 * 60);
+        assert_eq!(refresh_delay(month), month - REFRESH_MARGIN);
+    }
+
+    #[test]
+    fn refresh_delay_scales_the_margin_for_short_lived_tokens() {
+        let cases = [
+            (Duration::from_secs(30 * 60), Duration::from_secs(27 * 60)),
+            (Duration::from_secs(5 * 60), Duration::from_secs(270)),
+            (Duration::from_secs(60), Duration::from_secs(54)),
+            // never a tight loop, even for expired tokens
+            (Duration::from_secs(5), MIN_REFRESH_DELAY),
+            (Duration::ZERO, MIN_REFRESH_DELAY),
+        ];
+        for (expires_in, expected) in cases {
+            assert_eq!(refresh_delay(expires_in), expected, "expires in {:?}", expires_in);
+        }
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        let delays: Vec<u64> = (1..=9).map(|failures| retry_delay(failures).as_secs()).collect();
+        assert_eq!(delays, [5, 10, 20, 40, 80, 160, 300, 300, 300]);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+}
@@ -0,0 +1,351 @@
+// how `ProtoMessage`s get to the server and back.  the client only ever sees a
+// stream of decoded messages and a sink to send them to, so the length
+// prefixed protobuf connection on port 5035 and the json over websocket one
+// on port 5036 are interchangeable.  both dial through `stream_builder`, so
+// TLS, proxies and timeouts work the same way for either.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::LazyLock;
+
+use futures_util::future::BoxFuture;
+use futures_util::stream::BoxStream;
+use futures_util::{Sink, SinkExt, StreamExt, TryStreamExt};
+use prost::Message;
+use prost_reflect::{DescriptorPool, DeserializeOptions, DynamicMessage, MessageDescriptor, SerializeOptions};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::open_api::{self, ProtoMessage};
+use crate::types::Endpoint;
+
+use super::codec::OpenApiCodec;
+use super::stream_builder::{self, ConnectOptions, TlsMode};
+
+/// The messages received from the server, ending when the connection closes.
+pub type MessageStream = BoxStream<'static, io::Result<ProtoMessage>>;
+/// Where requests are written to.
+pub type MessageSink = Pin<Box<dyn Sink<ProtoMessage, Error = io::Error> + Send>>;
+
+/// A way of exchanging `ProtoMessage`s with an endpoint.  `connect` is called
+/// when the client is opened and again on every reconnect.
+pub trait Transport: fmt::Debug + Send + Sync {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        options: &'a ConnectOptions,
+        max_frame_size: usize,
+    ) -> BoxFuture<'a, Result<(MessageStream, MessageSink), Box<dyn std::error::Error>>>;
+}
+
+/// Length prefixed protobuf over TLS (or plain TCP), the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProtobufTransport;
+
+impl Transport for ProtobufTransport {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        options: &'a ConnectOptions,
+        max_frame_size: usize,
+    ) -> BoxFuture<'a, Result<(MessageStream, MessageSink), Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            let stream_ = stream_builder::initialize_stream(endpoint.host(), endpoint.port(), options).await?;
+            Ok(protobuf_messages(stream_, max_frame_size))
+        })
+    }
+}
+
+// the length prefixed messages of a connection, for the client and the mock
+// server
+pub(crate) fn protobuf_messages<S>(stream: S, max_frame_size: usize) -> (MessageStream, MessageSink)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let codec = OpenApiCodec::new(max_frame_size);
+    let (reader, writer) = tokio::io::split(stream);
+
+    let messages: MessageStream = FramedRead::new(reader, codec).map_err(io::Error::from).boxed();
+    let sink: MessageSink = Box::pin(FramedWrite::new(writer, codec).sink_map_err(io::Error::from));
+    (messages, sink)
+}
+
+/// JSON text messages over a websocket, for networks where only web ports
+/// are open.  `Demo` and `Live` are reached on port 5036, a `Custom`
+/// endpoint on its own port.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WebSocketTransport;
+
+/// Port of the json api on the demo and live servers.
+pub const WEBSOCKET_PORT: u16 = 5036;
+
+impl Transport for WebSocketTransport {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        options: &'a ConnectOptions,
+        max_frame_size: usize,
+    ) -> BoxFuture<'a, Result<(MessageStream, MessageSink), Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            let host = endpoint.host();
+            let port = match endpoint {
+                Endpoint::Custom { port, .. } => *port,
+                Endpoint::Demo | Endpoint::Live => WEBSOCKET_PORT,
+            };
+            let stream_ = stream_builder::initialize_stream(host, port, options).await?;
+
+            let scheme = if matches!(options.tls, TlsMode::Plain) { "ws" } else { "wss" };
+            let config = WebSocketConfig::default().max_message_size(Some(max_frame_size));
+            let handshake = tokio_tungstenite::client_async_with_config(
+                format!("{}://{}:{}", scheme, host, port),
+                stream_,
+                Some(config),
+            );
+            let (websocket, _) = tokio::time::timeout(options.handshake_timeout, handshake)
+                .await
+                .map_err(|_| format!("websocket handshake with {}:{} timed out", host, port))??;
+            Ok(json_messages(websocket))
+        })
+    }
+}
+
+// the json text messages of a websocket, for the client and the mock server
+pub(crate) fn json_messages<S>(websocket: WebSocketStream<S>) -> (MessageStream, MessageSink)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (writer, reader) = websocket.split();
+
+    let messages: MessageStream = reader
+        .map_err(io::Error::other)
+        .try_filter_map(|message| async move {
+            match message {
+                WsMessage::Text(text) => from_json(text.as_str()).map(Some),
+                WsMessage::Binary(bytes) => ProtoMessage::decode(bytes).map(Some).map_err(io::Error::other),
+                // pings are answered by tungstenite itself
+                _ => Ok(None),
+            }
+        })
+        .boxed();
+    let sink: MessageSink = Box::pin(
+        writer
+            .sink_map_err(io::Error::other)
+            .with(|message: ProtoMessage| async move { to_json(&message).map(|json| WsMessage::Text(json.into())) }),
+    );
+    (messages, sink)
+}
+
+// the json form of a `ProtoMessage`: the payload is the message itself
+// instead of its encoded bytes
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonMessage {
+    payload_type: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_msg_id: Option<String>,
+}
+
+// every message that declares a payloadType, keyed by the default value of
+// that field
+static PAYLOAD_TYPES: LazyLock<HashMap<u32, MessageDescriptor>> = LazyLock::new(|| {
+    let pool = DescriptorPool::decode(open_api::FILE_DESCRIPTOR_SET).expect("invalid open api descriptors");
+    pool.all_messages()
+        .filter_map(|message| {
+            let payload_type = message.get_field_by_name("payloadType")?.default_value();
+            let payload_type = payload_type.as_enum_number()?;
+            Some((payload_type as u32, message))
+        })
+        .collect()
+});
+
+fn descriptor(payload_type: u32) -> io::Result<&'static MessageDescriptor> {
+    PAYLOAD_TYPES.get(&payload_type).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("unknown payload type {}", payload_type))
+    })
+}
+
+/// The JSON text of a message, as sent over the websocket.
+pub fn to_json(message: &ProtoMessage) -> io::Result<String> {
+    let payload = match &message.payload {
+        Some(bytes) => {
+            let payload = DynamicMessage::decode(descriptor(message.payload_type)?.clone(), bytes.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            // numbers as numbers, enums by value, unset fields left out
+            let options = SerializeOptions::new().stringify_64_bit_integers(false).use_enum_numbers(true);
+            Some(
+                payload
+                    .serialize_with_options(serde_json::value::Serializer, &options)
+                    .map_err(io::Error::other)?,
+            )
+        }
+        None => None,
+    };
+
+    let json = JsonMessage {
+        payload_type: message.payload_type,
+        payload,
+        client_msg_id: message.client_msg_id.clone(),
+    };
+    serde_json::to_string(&json).map_err(io::Error::other)
+}
+
+/// Parse a JSON message received over the websocket.
+pub fn from_json(text: &str) -> io::Result<ProtoMessage> {
+    let json: JsonMessage = serde_json::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let payload = match json.payload {
+        Some(payload) => {
+            // newer servers may add fields this client does not know yet
+            let options = DeserializeOptions::new().deny_unknown_fields(false);
+            let payload = DynamicMessage::deserialize_with_options(descriptor(json.payload_type)?.clone(), payload, &options)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Some(payload.encode_to_vec().into())
+        }
+        None => None,
+    };
+
+    Ok(ProtoMessage {
+        payload_type: json.payload_type,
+        payload,
+        client_msg_id: json.client_msg_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::open_api::{
+        ProtoHeartbeatEvent, ProtoOaExecutionEvent, ProtoOaExecutionType, ProtoOaNewOrderReq, ProtoOaOrderType,
+        ProtoOaPayloadType, ProtoOaPosition, ProtoOaPositionStatus, ProtoOaSpotEvent, ProtoOaTradeData, ProtoOaTradeSide,
+        ProtoOaTrendbar, ProtoOaTrendbarPeriod, ProtoPayloadType,
+    };
+
+    fn message<M: Message>(payload_type: u32, payload: &M) -> ProtoMessage {
+        ProtoMessage {
+            payload_type,
+            payload: Some(payload.encode_to_vec().into()),
+            client_msg_id: Some("id".to_string()),
+        }
+    }
+
+    // the payload sent as json and decoded again
+    fn round_trip<M: Message + Default>(message: &ProtoMessage) -> M {
+        let back = from_json(&to_json(message).unwrap()).unwrap();
+        assert_eq!(back.payload_type, message.payload_type);
+        assert_eq!(back.client_msg_id, message.client_msg_id);
+        M::decode(back.payload.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn requests_with_enums_survive_json() {
+        let order = ProtoOaNewOrderReq {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaNewOrderReq as i32),
+            ctid_trader_account_id: 4_100_001,
+            symbol_id: 1,
+            order_type: ProtoOaOrderType::Limit as i32,
+            trade_side: ProtoOaTradeSide::Sell as i32,
+            volume: 100_000,
+            limit_price: Some(1.08123),
+            comment: Some("json".to_string()),
+            ..Default::default()
+        };
+        let message = message(ProtoOaPayloadType::ProtoOaNewOrderReq as u32, &order);
+
+        // enums by number, fields in camel case, unset ones left out
+        let json: serde_json::Value = serde_json::from_str(&to_json(&message).unwrap()).unwrap();
+        assert_eq!(json["payloadType"], 2106);
+        assert_eq!(json["clientMsgId"], "id");
+        assert_eq!(json["payload"]["tradeSide"], 2);
+        assert_eq!(json["payload"]["ctidTraderAccountId"], 4_100_001);
+        assert!(json["payload"].get("stopPrice").is_none());
+
+        assert_eq!(round_trip::<ProtoOaNewOrderReq>(&message), order);
+    }
+
+    #[test]
+    fn events_with_nested_messages_survive_json() {
+        let spot = ProtoOaSpotEvent {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaSpotEvent as i32),
+            ctid_trader_account_id: 4_100_001,
+            symbol_id: 1,
+            bid: Some(108_000),
+            ask: Some(108_020),
+            trendbar: vec![ProtoOaTrendbar {
+                volume: 12,
+                period: Some(ProtoOaTrendbarPeriod::M1 as i32),
+                low: Some(107_900),
+                delta_open: Some(50),
+                delta_high: Some(150),
+                utc_timestamp_in_minutes: Some(28_000_000),
+                ..Default::default()
+            }],
+            timestamp: Some(1_700_000_000_000),
+            ..Default::default()
+        };
+        let message_ = message(ProtoOaPayloadType::ProtoOaSpotEvent as u32, &spot);
+        assert_eq!(round_trip::<ProtoOaSpotEvent>(&message_), spot);
+
+        let execution = ProtoOaExecutionEvent {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaExecutionEvent as i32),
+            ctid_trader_account_id: 4_100_001,
+            execution_type: ProtoOaExecutionType::OrderFilled as i32,
+            position: Some(ProtoOaPosition {
+                position_id: 5,
+                trade_data: ProtoOaTradeData {
+                    symbol_id: 1,
+                    volume: 100_000,
+                    trade_side: ProtoOaTradeSide::Buy as i32,
+                    ..Default::default()
+                },
+                position_status: ProtoOaPositionStatus::PositionStatusOpen as i32,
+                swap: -12,
+                price: Some(1.08),
+                money_digits: Some(2),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let message_ = message(ProtoOaPayloadType::ProtoOaExecutionEvent as u32, &execution);
+        assert_eq!(round_trip::<ProtoOaExecutionEvent>(&message_), execution);
+    }
+
+    #[test]
+    fn common_messages_survive_json() {
+        let heartbeat = ProtoHeartbeatEvent {
+            payload_type: Some(ProtoPayloadType::HeartbeatEvent as i32),
+        };
+        let message_ = message(ProtoPayloadType::HeartbeatEvent as u32, &heartbeat);
+        assert_eq!(round_trip::<ProtoHeartbeatEvent>(&message_), heartbeat);
+
+        // a message without payload or id stays without them
+        let bare = ProtoMessage {
+            payload_type: ProtoPayloadType::HeartbeatEvent as u32,
+            payload: None,
+            client_msg_id: None,
+        };
+        assert_eq!(to_json(&bare).unwrap(), r#"{"payloadType":51}"#);
+        assert_eq!(from_json(r#"{"payloadType":51}"#).unwrap(), bare);
+    }
+
+    #[test]
+    fn rejects_unknown_payload_types_and_bad_json() {
+        let unknown = ProtoMessage {
+            payload_type: 9_999,
+            payload: Some(Vec::new().into()),
+            client_msg_id: None,
+        };
+        assert_eq!(to_json(&unknown).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(from_json(r#"{"payloadType":9999,"payload":{}}"#).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(from_json("not json").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // fields added by newer servers are skipped
+        let spot = from_json(r#"{"payloadType":2131,"payload":{"ctidTraderAccountId":1,"symbolId":2,"newField":3}}"#);
+        assert_eq!(ProtoOaSpotEvent::decode(spot.unwrap().payload.unwrap()).unwrap().symbol_id, 2);
+    }
+}
@@ -0,0 +1,183 @@
+// this is the dynamic leverage cache.  symbols reference a leverage entity by
+// `leverage_id`; its tiers say which leverage applies to which slice of the
+// position's usd volume, so large positions need more margin per unit than
+// small ones.  the account leverage caps every tier.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LeverageTier {
+    /// max usd volume (in cents) of the position per side for this tier
+    pub volume: i64,
+    /// e.g. 500 for 1:500
+    pub leverage: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DynamicLeverage {
+    pub leverage_id: i64,
+    /// sorted by volume, the last tier also applies above its volume
+    pub tiers: Vec<LeverageTier>,
+}
+
+impl DynamicLeverage {
+    /// Margin in usd for a position worth `usd_notional`, each tier slice
+    /// divided by its leverage capped at `account_leverage`.
+    pub fn margin_for(&self, usd_notional: f64, account_leverage: Option<f64>) -> Option<f64> {
+        let capped = |leverage: i32| {
+            let leverage = leverage as f64;
+            account_leverage.map_or(leverage, |account| leverage.min(account))
+        };
+
+        let mut margin = 0.0;
+        let mut lower = 0.0;
+        for tier in &self.tiers {
+            let upper = tier.volume as f64 / 100.0;
+            if usd_notional <= lower {
+                break;
+            }
+            let slice = usd_notional.min(upper) - lower;
+            margin += slice / capped(tier.leverage);
+            lower = upper;
+        }
+
+        // whatever is left above the last tier uses the last tier's leverage
+        let last = self.tiers.last()?;
+        if usd_notional > lower {
+            margin += (usd_notional - lower) / capped(last.leverage);
+        }
+
+        Some(margin)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LeverageBook {
+    leverages: HashMap<i64, DynamicLeverage>,
+    account_leverage: HashMap<i64, f64>,
+}
+
+impl LeverageBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, leverage: DynamicLeverage) {
+        self.leverages.insert(leverage.leverage_id, leverage);
+    }
+
+    pub fn get(&self, leverage_id: i64) -> Option<&DynamicLeverage> {
+        self.leverages.get(&leverage_id)
+    }
+
+    pub fn contains(&self, leverage_id: i64) -> bool {
+        self.leverages.contains_key(&leverage_id)
+    }
+
+    pub fn set_account_leverage(&mut self, account_id: i64, leverage: f64) {
+        self.account_leverage.insert(account_id, leverage);
+    }
+
+    pub fn account_leverage(&self, account_id: i64) -> Option<f64> {
+        self.account_leverage.get(&account_id).copied()
+    }
+
+    /// Margin for a position given its notional value in usd and in the
+    /// deposit currency.  Falls back to the flat account leverage when the
+    /// symbol has no dynamic leverage.
+    pub fn margin_required(
+        &self,
+        account_id: i64,
+        leverage_id: Option<i64>,
+        usd_notional: f64,
+        deposit_notional: f64,
+    ) -> Option<f64> {
+        let account_leverage = self.account_leverage(account_id);
+
+        match leverage_id.and_then(|id| self.get(id)) {
+            Some(leverage) if usd_notional > 0.0 => {
+                let usd_margin = leverage.margin_for(usd_notional, account_leverage)?;
+                // the tiers work in usd, scale the blended rate back to the deposit currency
+                Some(deposit_notional * usd_margin / usd_notional)
+            }
+            _ => Some(deposit_notional / account_leverage?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1:500 up to $100k, 1:200 up to $1m, 1:100 above
+    fn tiered() -> DynamicLeverage {
+        DynamicLeverage {
+            leverage_id: 7,
+            tiers: vec![
+                LeverageTier { volume: 10_000_000, leverage: 500 },
+                LeverageTier { volume: 100_000_000, leverage: 200 },
+                LeverageTier { volume: 200_000_000, leverage: 100 },
+            ],
+        }
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("no margin");
+        assert!((actual - expected).abs() < 1e-6, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn blends_the_tiers_a_position_spans() {
+        let leverage = tiered();
+        let cases = [
+            // (usd notional, account leverage, margin)
+            (0.0, None, 0.0),
+            (50_000.0, None, 100.0),
+            (100_000.0, None, 200.0),
+            (600_000.0, None, 200.0 + 2_500.0),
+            (2_000_000.0, None, 200.0 + 4_500.0 + 10_000.0),
+            // above the last tier its leverage still applies
+            (3_000_000.0, None, 200.0 + 4_500.0 + 20_000.0),
+            // the account leverage caps every tier
+            (50_000.0, Some(100.0), 500.0),
+            (600_000.0, Some(300.0), 100_000.0 / 300.0 + 2_500.0),
+            (600_000.0, Some(1_000.0), 200.0 + 2_500.0),
+        ];
+
+        for (notional, account_leverage, margin) in cases {
+            assert_close(leverage.margin_for(notional, account_leverage), margin);
+        }
+    }
+
+    #[test]
+    fn needs_at_least_one_tier() {
+        let leverage = DynamicLeverage { leverage_id: 7, tiers: Vec::new() };
+        assert_eq!(leverage.margin_for(1_000.0, Some(100.0)), None);
+    }
+
+    #[test]
+    fn margin_required_in_the_deposit_currency() {
+        let mut book = LeverageBook::new();
+        book.insert(tiered());
+        assert!(book.contains(7));
+        assert_eq!(book.margin_required(1, None, 10_000.0, 10_000.0), None);
+
+        book.set_account_leverage(1, 50.0);
+        let cases = [
+            // (leverage id, usd notional, deposit notional, margin)
+            (None, 600_000.0, 500_000.0, 10_000.0),
+            // unknown ids fall back to the account leverage too
+            (Some(8), 600_000.0, 500_000.0, 10_000.0),
+            // 50 caps every tier, then scaled from usd into the deposit currency
+            (Some(7), 600_000.0, 500_000.0, 10_000.0),
+            (Some(7), 0.0, 1_000.0, 20.0),
+        ];
+        for (leverage_id, usd, deposit, margin) in cases {
+            assert_close(book.margin_required(1, leverage_id, usd, deposit), margin);
+        }
+
+        book.set_account_leverage(1, 1_000.0);
+        // 2700 usd of margin on 600k, the same rate applied to 500k
+        assert_close(book.margin_required(1, Some(7), 600_000.0, 500_000.0), 2_250.0);
+    }
+}
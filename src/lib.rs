@@ -1,17 +1,53 @@
 pub mod open_api {
     tonic::include_proto!("open_api");
+
+    /// The encoded descriptors of every message above.
+    pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/open_api_descriptor.bin"));
+
+    /// The server version these messages were taken from, see `proto/VERSION`.
+    pub const PROTO_VERSION: &str = env!("OPEN_API_PROTO_VERSION");
 }
 pub mod auth;
 pub mod types;
 //pub mod ctrader_;
 pub mod ctrader;
 pub mod utilities;
+pub mod conversion;
+pub mod sizing;
+pub mod margin_guard;
+pub mod leverage;
+pub mod token_store;
+pub mod connection_pool;
+pub mod config;
+pub mod rate_limit;
+pub mod recording;
+pub mod version;
+pub mod metrics;
+#[cfg(feature = "mock-server")]
+pub mod mock_server;
 
-pub use auth::AuthClient;
-pub use ctrader::CtraderClient;
+pub use auth::{AuthClient, AuthError};
+pub use ctrader::{
+    AccountHandle, ClientTasks, CodecError, ConnectOptions, CtraderClient, CtraderClientBuilder, OpenApiCodec, Proxy,
+    ProtobufTransport, ShutdownOptions, TlsMode, Transport, WebSocketTransport,
+};
+pub use config::{CtraderConfig, ReconnectPolicy, TransportKind};
+pub use rate_limit::{DelayStats, RateLimitStats, RateLimits, RequestClass};
+pub use recording::{Direction, RecordedFrame, Recorder, ReplayTransport, read_recording};
+pub use version::{ServerVersion, VersionMatch, VersionPolicy};
+pub use metrics::{Histogram, Metrics};
+pub use ctrader::stream_builder::root_store_from_pem;
+pub use tokio_rustls::rustls::RootCertStore;
+pub use conversion::{ConversionChain, ConversionEngine};
+pub use sizing::{BelowMinimum, OrderBuilder, OrderSize, StopDistance};
+pub use margin_guard::{GuardAction, MarginGuardAlert, MarginGuardConfig};
+pub use leverage::{DynamicLeverage, LeverageTier};
+pub use token_store::{FileTokenStore, MemoryTokenStore, TokenStore};
+pub use connection_pool::{ConnectionPool, PoolEvent};
 pub use types::{
-    Account, BarData, Endpoint, Quote, Scope, StreamEvent,
-    Symbol, SymbolData, TimeFrame, Tokens, Order, RelativeBarData, Signal
+    AccountAuthState, Account, AccountFilter, CtidProfile, BarData, Endpoint, Quote, Scope, StreamEvent,
+    Symbol, SymbolData, TimeFrame, Tokens, Order, RelativeBarData, Signal,
+    PositionUnrealizedPnl, ExpectedMargin, Asset, Trader, MarginCall, MarginCallType
 };
 
 //pub use ctrader_::CtraderClient;
@@ -0,0 +1,284 @@
+// this is the local margin guard.  it follows the balance, the margin used by
+// every open position and the unrealized pnl reported by the server so that
+// the margin level (equity / used margin * 100) of an account can be checked
+// after every update, and trading can be blocked or the account flattened
+// before the broker's own stop-out kicks in.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardAction {
+    /// refuse `new_order` calls until the margin level recovers
+    BlockNewOrders,
+    /// close every known open position and block new orders
+    Flatten,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MarginGuardConfig {
+    /// margin level in percent, e.g. 150.0
+    pub threshold: f64,
+    pub action: GuardAction,
+}
+
+/// Emitted as `StreamEvent::MarginGuardTriggered` when an account's margin
+/// level drops under its configured threshold.
+#[derive(Debug, Clone)]
+pub struct MarginGuardAlert {
+    pub account_id: i64,
+    pub margin_level: f64,
+    pub threshold: f64,
+    pub action: GuardAction,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct OpenPosition {
+    volume: i64,
+    used_margin: f64,
+    unrealized_pnl: f64,
+}
+
+#[derive(Debug, Default)]
+struct AccountMargin {
+    config: Option<MarginGuardConfig>,
+    balance: Option<f64>,
+    positions: HashMap<i64, OpenPosition>,
+    blocked: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct MarginGuard {
+    accounts: HashMap<i64, AccountMargin>,
+}
+
+impl MarginGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn configure(&mut self, account_id: i64, config: Option<MarginGuardConfig>) {
+        let account = self.accounts.entry(account_id).or_default();
+        account.config = config;
+        if config.is_none() {
+            account.blocked = false;
+        }
+    }
+
+    pub fn set_balance(&mut self, account_id: i64, balance: f64) {
+        self.accounts.entry(account_id).or_default().balance = Some(balance);
+    }
+
+    pub fn update_position(&mut self, account_id: i64, position_id: i64, volume: i64, used_margin: Option<f64>) {
+        let position = self
+            .accounts
+            .entry(account_id)
+            .or_default()
+            .positions
+            .entry(position_id)
+            .or_default();
+        position.volume = volume;
+        if let Some(used_margin) = used_margin {
+            position.used_margin = used_margin;
+        }
+    }
+
+    pub fn set_used_margin(&mut self, account_id: i64, position_id: i64, used_margin: f64) {
+        if let Some(position) = self
+            .accounts
+            .get_mut(&account_id)
+            .and_then(|account| account.positions.get_mut(&position_id))
+        {
+            position.used_margin = used_margin;
+        }
+    }
+
+    pub fn set_unrealized_pnl(&mut self, account_id: i64, position_id: i64, pnl: f64) {
+        if let Some(position) = self
+            .accounts
+            .get_mut(&account_id)
+            .and_then(|account| account.positions.get_mut(&position_id))
+        {
+            position.unrealized_pnl = pnl;
+        }
+    }
+
+    /// Replace the account's open positions with what the server reported
+    /// as (position id, volume, used margin).  Positions the guard tracked
+    /// but the server no longer has are dropped; the unrealized pnl of the
+    /// ones still open is kept.
+    pub fn reconcile_positions(&mut self, account_id: i64, positions: &[(i64, i64, f64)]) {
+        let account = self.accounts.entry(account_id).or_default();
+        account
+            .positions
+            .retain(|position_id, _| positions.iter().any(|(id, _, _)| id == position_id));
+        for &(position_id, volume, used_margin) in positions {
+            let position = account.positions.entry(position_id).or_default();
+            position.volume = volume;
+            position.used_margin = used_margin;
+        }
+    }
+
+    pub fn remove_position(&mut self, account_id: i64, position_id: i64) {
+        if let Some(account) = self.accounts.get_mut(&account_id) {
+            account.positions.remove(&position_id);
+        }
+    }
+
+    /// Equity / used margin in percent.  `None` while the balance is unknown
+    /// or no margin is used.
+    pub fn margin_level(&self, account_id: i64) -> Option<f64> {
+        let account = self.accounts.get(&account_id)?;
+        let used_margin: f64 = account.positions.values().map(|p| p.used_margin).sum();
+        if used_margin <= 0.0 {
+            return None;
+        }
+        let unrealized: f64 = account.positions.values().map(|p| p.unrealized_pnl).sum();
+
+        Some((account.balance? + unrealized) / used_margin * 100.0)
+    }
+
+    pub fn is_blocked(&self, account_id: i64) -> bool {
+        self.accounts.get(&account_id).is_some_and(|account| account.blocked)
+    }
+
+    /// Open positions as (position id, volume), used when flattening.
+    pub fn open_positions(&self, account_id: i64) -> Vec<(i64, i64)> {
+        self.accounts
+            .get(&account_id)
+            .map(|account| account.positions.iter().map(|(id, p)| (*id, p.volume)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Re-evaluate the account.  Returns an alert only when the margin level
+    /// crosses under the threshold; the block is lifted once it recovers.
+    pub fn evaluate(&mut self, account_id: i64) -> Option<MarginGuardAlert> {
+        let margin_level = self.margin_level(account_id);
+        let account = self.accounts.get_mut(&account_id)?;
+        let config = account.config?;
+
+        match margin_level {
+            Some(level) if level < config.threshold => {
+                if account.blocked {
+                    return None;
+                }
+                account.blocked = true;
+                Some(MarginGuardAlert {
+                    account_id,
+                    margin_level: level,
+                    threshold: config.threshold,
+                    action: config.action,
+                })
+            }
+            _ => {
+                account.blocked = false;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCOUNT: i64 = 1;
+
+    fn guard(threshold: f64, action: GuardAction) -> MarginGuard {
+        let mut guard = MarginGuard::new();
+        guard.configure(ACCOUNT, Some(MarginGuardConfig { threshold, action }));
+        guard.set_balance(ACCOUNT, 1_000.0);
+        guard
+    }
+
+    #[test]
+    fn margin_level_needs_a_balance_and_used_margin() {
+        let mut guard = MarginGuard::new();
+        guard.update_position(ACCOUNT, 10, 100_000, Some(500.0));
+        assert_eq!(guard.margin_level(ACCOUNT), None);
+
+        guard.set_balance(ACCOUNT, 1_000.0);
+        assert_eq!(guard.margin_level(ACCOUNT), Some(200.0));
+
+        guard.remove_position(ACCOUNT, 10);
+        assert_eq!(guard.margin_level(ACCOUNT), None);
+    }
+
+    #[test]
+    fn margin_level_counts_unrealized_pnl() {
+        let mut guard = guard(150.0, GuardAction::BlockNewOrders);
+        guard.update_position(ACCOUNT, 10, 100_000, Some(300.0));
+        guard.update_position(ACCOUNT, 11, 100_000, Some(200.0));
+        guard.set_unrealized_pnl(ACCOUNT, 10, -150.0);
+        guard.set_unrealized_pnl(ACCOUNT, 11, 50.0);
+
+        // (1000 - 150 + 50) / 500
+        assert_eq!(guard.margin_level(ACCOUNT), Some(180.0));
+    }
+
+    #[test]
+    fn alerts_once_when_crossing_under_the_threshold() {
+        let mut guard = guard(150.0, GuardAction::Flatten);
+        guard.update_position(ACCOUNT, 10, 100_000, Some(500.0));
+        assert!(guard.evaluate(ACCOUNT).is_none());
+        assert!(!guard.is_blocked(ACCOUNT));
+
+        guard.set_unrealized_pnl(ACCOUNT, 10, -300.0);
+        let alert = guard.evaluate(ACCOUNT).expect("no alert under the threshold");
+        assert_eq!(alert.account_id, ACCOUNT);
+        assert_eq!(alert.margin_level, 140.0);
+        assert_eq!(alert.threshold, 150.0);
+        assert_eq!(alert.action, GuardAction::Flatten);
+        assert!(guard.is_blocked(ACCOUNT));
+
+        // still under, already blocked
+        guard.set_unrealized_pnl(ACCOUNT, 10, -400.0);
+        assert!(guard.evaluate(ACCOUNT).is_none());
+        assert!(guard.is_blocked(ACCOUNT));
+    }
+
+    #[test]
+    fn lifts_the_block_once_the_level_recovers() {
+        let mut guard = guard(150.0, GuardAction::BlockNewOrders);
+        guard.update_position(ACCOUNT, 10, 100_000, Some(500.0));
+        guard.set_unrealized_pnl(ACCOUNT, 10, -300.0);
+        assert!(guard.evaluate(ACCOUNT).is_some());
+
+        // exactly at the threshold is not under it
+        guard.set_unrealized_pnl(ACCOUNT, 10, -250.0);
+        assert!(guard.evaluate(ACCOUNT).is_none());
+        assert!(!guard.is_blocked(ACCOUNT));
+
+        guard.set_unrealized_pnl(ACCOUNT, 10, -300.0);
+        assert!(guard.evaluate(ACCOUNT).is_some());
+    }
+
+    #[test]
+    fn removing_the_config_unblocks() {
+        let mut guard = guard(150.0, GuardAction::BlockNewOrders);
+        guard.update_position(ACCOUNT, 10, 100_000, Some(1_000.0));
+        assert!(guard.evaluate(ACCOUNT).is_some());
+
+        guard.configure(ACCOUNT, None);
+        assert!(!guard.is_blocked(ACCOUNT));
+        assert!(guard.evaluate(ACCOUNT).is_none());
+    }
+
+    #[test]
+    fn reconcile_seeds_positions_opened_elsewhere() {
+        let mut guard = guard(150.0, GuardAction::Flatten);
+        guard.update_position(ACCOUNT, 10, 100_000, Some(200.0));
+        guard.set_unrealized_pnl(ACCOUNT, 10, -100.0);
+        // closed while the client was away
+        guard.update_position(ACCOUNT, 11, 100_000, Some(200.0));
+
+        guard.reconcile_positions(ACCOUNT, &[(10, 100_000, 300.0), (12, 200_000, 400.0)]);
+
+        let mut positions = guard.open_positions(ACCOUNT);
+        positions.sort();
+        assert_eq!(positions, vec![(10, 100_000), (12, 200_000)]);
+        // (1000 - 100) / 700, the pnl of the kept position stays
+        let level = guard.margin_level(ACCOUNT).unwrap();
+        assert!((level - 900.0 / 700.0 * 100.0).abs() < 1e-9);
+        assert!(guard.evaluate(ACCOUNT).is_some());
+    }
+}
@@ -0,0 +1,303 @@
+// connection and trading health.  the client counts what goes over the
+// connection as it happens: messages per payload type, how long each kind of
+// request waits for its response, reconnects, heartbeats, events nobody was
+// listening for, order rejections and spot events per symbol.
+// `CtraderClient::metrics` returns a copy, and `serve_metrics` answers
+// `GET /metrics` with it in the prometheus text format.  the last spot and
+// last message timestamps are there to alert on a feed that went quiet.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Weak;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+use crate::CtraderClient;
+use crate::open_api::{ProtoOaPayloadType, ProtoPayloadType};
+use crate::rate_limit::{DelayStats, RateLimitStats};
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// a slow scraper must not hold the listener
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Response latencies of one kind of request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// observations per bucket of `LATENCY_BUCKETS`, each counting the ones
+    /// at or below its bound; the ones above the last bound are only in
+    /// `count`
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    pub sum: Duration,
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// What the client counted since it was opened.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// messages written, by payload type
+    pub messages_sent: BTreeMap<u32, u64>,
+    /// messages read, by payload type
+    pub messages_received: BTreeMap<u32, u64>,
+    /// time from sending a request to the response carrying its
+    /// `client_msg_id`, by payload type of the request
+    pub request_latency: BTreeMap<u32, Histogram>,
+    pub reconnect_attempts: u64,
+    /// reconnects that got the accounts authorized again
+    pub reconnects: u64,
+    pub heartbeats_sent: u64,
+    pub heartbeats_received: u64,
+    /// events that could not be delivered because the receiver was dropped
+    pub dropped_events: u64,
+    /// order error events and rejected executions, by error code
+    pub order_rejections: BTreeMap<String, u64>,
+    /// spot events by symbol id
+    pub spot_events: BTreeMap<i64, u64>,
+    pub last_spot: BTreeMap<i64, SystemTime>,
+    pub last_message_received: Option<SystemTime>,
+    /// filled in by `CtraderClient::metrics` when rate limiting is enabled
+    pub rate_limits: Option<RateLimitStats>,
+}
+
+impl Metrics {
+    pub(crate) fn message_sent(&mut self, payload_type: u32) {
+        *self.messages_sent.entry(payload_type).or_default() += 1;
+        if payload_type == ProtoPayloadType::HeartbeatEvent as u32 {
+            self.heartbeats_sent += 1;
+        }
+    }
+
+    pub(crate) fn message_received(&mut self, payload_type: u32) {
+        *self.messages_received.entry(payload_type).or_default() += 1;
+        if payload_type == ProtoPayloadType::HeartbeatEvent as u32 {
+            self.heartbeats_received += 1;
+        }
+        self.last_message_received = Some(SystemTime::now());
+    }
+
+    pub(crate) fn response_received(&mut self, request_payload_type: u32, latency: Duration) {
+        self.request_latency.entry(request_payload_type).or_default().observe(latency);
+    }
+
+    pub(crate) fn spot_received(&mut self, symbol_id: i64) {
+        *self.spot_events.entry(symbol_id).or_default() += 1;
+        self.last_spot.insert(symbol_id, SystemTime::now());
+    }
+
+    pub(crate) fn order_rejected(&mut self, error_code: &str) {
+        *self.order_rejections.entry(error_code.to_string()).or_default() += 1;
+    }
+
+    /// The metrics in the prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        family(&mut out, "ctrader_messages_sent_total", "counter", "Messages written to the connection.");
+        for (payload_type, count) in &self.messages_sent {
+            sample(&mut out, "ctrader_messages_sent_total", &[("payload_type", &payload_type_name(*payload_type))], *count);
+        }
+        family(&mut out, "ctrader_messages_received_total", "counter", "Messages read from the connection.");
+        for (payload_type, count) in &self.messages_received {
+            sample(&mut out, "ctrader_messages_received_total", &[("payload_type", &payload_type_name(*payload_type))], *count);
+        }
+
+        family(
+            &mut out,
+            "ctrader_request_latency_seconds",
+            "histogram",
+            "Time from sending a request to its response.",
+        );
+        for (payload_type, histogram) in &self.request_latency {
+            let request = payload_type_name(*payload_type);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                sample(
+                    &mut out,
+                    "ctrader_request_latency_seconds_bucket",
+                    &[("request", &request), ("le", &bound.to_string())],
+                    count,
+                );
+            }
+            sample(
+                &mut out,
+                "ctrader_request_latency_seconds_bucket",
+                &[("request", &request), ("le", "+Inf")],
+                histogram.count,
+            );
+            sample(&mut out, "ctrader_request_latency_seconds_sum", &[("request", &request)], histogram.sum.as_secs_f64());
+            sample(&mut out, "ctrader_request_latency_seconds_count", &[("request", &request)], histogram.count);
+        }
+
+        let totals = [
+            ("ctrader_reconnect_attempts_total", "Reconnect attempts.", self.reconnect_attempts),
+            ("ctrader_reconnects_total", "Successful reconnects.", self.reconnects),
+            ("ctrader_heartbeats_sent_total", "Heartbeats sent.", self.heartbeats_sent),
+            ("ctrader_heartbeats_received_total", "Heartbeats received.", self.heartbeats_received),
+            ("ctrader_dropped_events_total", "Events dropped because nobody received them.", self.dropped_events),
+        ];
+        for (name, help, value) in totals {
+            family(&mut out, name, "counter", help);
+            sample(&mut out, name, &[], value);
+        }
+
+        family(&mut out, "ctrader_order_rejections_total", "counter", "Rejected orders by error code.");
+        for (error_code, count) in &self.order_rejections {
+            sample(&mut out, "ctrader_order_rejections_total", &[("error_code", error_code)], *count);
+        }
+
+        family(&mut out, "ctrader_spot_events_total", "counter", "Spot events by symbol.");
+        for (symbol_id, count) in &self.spot_events {
+            sample(&mut out, "ctrader_spot_events_total", &[("symbol_id", &symbol_id.to_string())], *count);
+        }
+        family(
+            &mut out,
+            "ctrader_last_spot_timestamp_seconds",
+            "gauge",
+            "Unix time of the last spot event by symbol.",
+        );
+        for (symbol_id, at) in &self.last_spot {
+            sample(
+                &mut out,
+                "ctrader_last_spot_timestamp_seconds",
+                &[("symbol_id", &symbol_id.to_string())],
+                unix_secs(*at),
+            );
+        }
+        if let Some(at) = self.last_message_received {
+            family(
+                &mut out,
+                "ctrader_last_message_received_timestamp_seconds",
+                "gauge",
+                "Unix time of the last message read from the connection.",
+            );
+            sample(&mut out, "ctrader_last_message_received_timestamp_seconds", &[], unix_secs(at));
+        }
+
+        if let Some(stats) = &self.rate_limits {
+            let classes: [(&str, &DelayStats); 3] = [
+                ("historical", &stats.historical),
+                ("trading", &stats.trading),
+                ("general", &stats.general),
+            ];
+            family(&mut out, "ctrader_rate_limited_requests_total", "counter", "Requests through the rate limiter.");
+            for (class, stats) in classes {
+                sample(&mut out, "ctrader_rate_limited_requests_total", &[("class", class)], stats.requests);
+            }
+            family(
+                &mut out,
+                "ctrader_rate_limit_delay_seconds_total",
+                "counter",
+                "Time requests waited for the rate limiter.",
+            );
+            for (class, stats) in classes {
+                sample(
+                    &mut out,
+                    "ctrader_rate_limit_delay_seconds_total",
+                    &[("class", class)],
+                    stats.total_delay.as_secs_f64(),
+                );
+            }
+        }
+
+        out
+    }
+}
+
+// the name from the proto files, e.g. PROTO_OA_SPOT_EVENT, or the number
+fn payload_type_name(payload_type: u32) -> String {
+    if let Ok(payload_type) = ProtoOaPayloadType::try_from(payload_type as i32) {
+        return payload_type.as_str_name().to_string();
+    }
+    if let Ok(payload_type) = ProtoPayloadType::try_from(payload_type as i32) {
+        return payload_type.as_str_name().to_string();
+    }
+    payload_type.to_string()
+}
+
+fn unix_secs(at: SystemTime) -> f64 {
+    at.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default()
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape_label(value)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// answers every connection until the client is shut down or dropped.  only
+// the request line is read: GET /metrics gets the metrics, anything else a
+// 404.
+pub(crate) async fn serve(listener: TcpListener, client: Weak<CtraderClient>, mut stop: watch::Receiver<bool>) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = crate::ctrader::shutdown::stopped(&mut stop) => break,
+        };
+        let socket = match accepted {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                warn!(error = %e, "could not accept a metrics connection");
+                continue;
+            }
+        };
+        let Some(client) = client.upgrade() else { break };
+        let body = client.metrics().await.to_prometheus();
+        drop(client);
+
+        if let Err(e) = tokio::time::timeout(SCRAPE_TIMEOUT, answer(socket, &body)).await {
+            debug!(error = %e, "metrics scrape timed out");
+        }
+    }
+}
+
+async fn answer(mut socket: TcpStream, body: &str) {
+    let mut request_line = String::new();
+    if BufReader::new(&mut socket).read_line(&mut request_line).await.is_err() {
+        return;
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", body),
+        _ => ("404 Not Found", "Not found\n"),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(e) = socket.write_all(response.as_bytes()).await {
+        debug!(error = %e, "could not answer a metrics scrape");
+    }
+    let _ = socket.shutdown().await;
+}
@@ -0,0 +1,993 @@
+// an in-process stand in for the open api servers, built with the
+// `mock-server` feature.  it speaks the length prefixed protobuf protocol on a
+// local socket, over TLS when given a certificate, or json over a websocket,
+// and answers the requests
+// the client makes with simulated responses: the server version, application
+// and account auth, the account and symbol lists, trend bars, spot / live bar subscriptions,
+// market orders, cancelling pending ones and reconciling what is open.
+// tests can replace any of those answers with scripted ones, push events to the connected clients and assert on the requests received.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::{SinkExt, StreamExt};
+use prost::Message;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::ctrader::codec::DEFAULT_MAX_FRAME_SIZE;
+use crate::ctrader::transport;
+use crate::open_api::{
+    ProtoErrorCode, ProtoMessage, ProtoOaAccountAuthReq, ProtoOaAccountAuthRes, ProtoOaAccountLogoutReq,
+    ProtoOaAccountLogoutRes, ProtoOaApplicationAuthReq, ProtoOaApplicationAuthRes, ProtoOaCancelOrderReq,
+    ProtoOaClosePositionReq,
+    ProtoOaCtidProfile, ProtoOaCtidTraderAccount, ProtoOaDeal, ProtoOaDealStatus, ProtoOaErrorRes,
+    ProtoOaExecutionEvent, ProtoOaExecutionType, ProtoOaGetAccountListByAccessTokenRes,
+    ProtoOaGetCtidProfileByTokenRes, ProtoOaGetTrendbarsReq, ProtoOaGetTrendbarsRes, ProtoOaLightSymbol,
+    ProtoOaNewOrderReq, ProtoOaOrder, ProtoOaOrderErrorEvent, ProtoOaOrderStatus, ProtoOaOrderType,
+    ProtoOaPayloadType, ProtoOaPosition, ProtoOaPositionStatus, ProtoOaReconcileReq, ProtoOaReconcileRes, ProtoOaSpotEvent, ProtoOaSubscribeLiveTrendbarReq,
+    ProtoOaSubscribeLiveTrendbarRes, ProtoOaSubscribeSpotsReq, ProtoOaSubscribeSpotsRes, ProtoOaSymbol,
+    ProtoOaSymbolByIdReq, ProtoOaSymbolByIdRes, ProtoOaSymbolsListReq, ProtoOaSymbolsListRes, ProtoOaTradeData,
+    ProtoOaTrendbar, ProtoOaTrendbarPeriod, ProtoOaUnsubscribeLiveTrendbarReq, ProtoOaUnsubscribeLiveTrendbarRes,
+    ProtoOaUnsubscribeSpotsReq, ProtoOaUnsubscribeSpotsRes, ProtoOaVersionRes, PROTO_VERSION, ProtoPayloadType,
+};
+use crate::types::Endpoint;
+
+/// A symbol served by the mock, with EURUSD-like defaults.
+#[derive(Debug, Clone)]
+pub struct MockSymbol {
+    pub symbol_id: i64,
+    pub name: String,
+    pub base_asset_id: i64,
+    pub quote_asset_id: i64,
+    pub digits: i32,
+    pub pip_position: i32,
+    /// in cents of a unit, 10_000_000 for 100_000 units
+    pub lot_size: i64,
+    pub min_volume: i64,
+    pub max_volume: i64,
+    pub step_volume: i64,
+}
+
+impl MockSymbol {
+    pub fn new(symbol_id: i64, name: &str) -> Self {
+        Self {
+            symbol_id,
+            name: name.to_string(),
+            base_asset_id: 1,
+            quote_asset_id: 2,
+            digits: 5,
+            pip_position: 4,
+            lot_size: 10_000_000,
+            min_volume: 100_000,
+            max_volume: 10_000_000_000,
+            step_volume: 100_000,
+        }
+    }
+}
+
+/// A trend bar in the relative form of the protocol, from absolute prices
+/// in 1/100000 of a unit.
+pub fn trendbar(utc_timestamp_in_minutes: u32, open: u64, high: u64, low: u64, close: u64, volume: i64) -> ProtoOaTrendbar {
+    ProtoOaTrendbar {
+        volume,
+        period: None,
+        low: Some(low as i64),
+        delta_open: Some(open - low),
+        delta_close: Some(close - low),
+        delta_high: Some(high - low),
+        utc_timestamp_in_minutes: Some(utc_timestamp_in_minutes),
+    }
+}
+
+/// Wrap a payload the way the server sends it.
+pub fn message<M: Message>(payload_type: impl Into<u32>, payload: M) -> ProtoMessage {
+    ProtoMessage {
+        payload_type: payload_type.into(),
+        payload: Some(payload.encode_to_vec().into()),
+        client_msg_id: None,
+    }
+}
+
+/// An `ProtoOaErrorRes` with this code and description.
+pub fn error_response(account_id: Option<i64>, error_code: &str, description: &str) -> ProtoMessage {
+    message(
+        ProtoOaPayloadType::ProtoOaErrorRes as u32,
+        ProtoOaErrorRes {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaErrorRes as i32),
+            ctid_trader_account_id: account_id,
+            error_code: error_code.to_string(),
+            description: Some(description.to_string()),
+            maintenance_end_timestamp: None,
+            retry_after: None,
+        },
+    )
+}
+
+pub struct MockServerBuilder {
+    accounts: Vec<ProtoOaCtidTraderAccount>,
+    symbols: Vec<MockSymbol>,
+    trendbars: HashMap<(i64, i32), Vec<ProtoOaTrendbar>>,
+    credentials: Option<(String, String)>,
+    user_id: i64,
+    version: String,
+    tls: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    websocket: bool,
+}
+
+impl MockServerBuilder {
+    /// An account returned by the account list and accepted by account auth.
+    pub fn account(mut self, account_id: i64, is_live: bool) -> Self {
+        self.accounts.push(ProtoOaCtidTraderAccount {
+            ctid_trader_account_id: account_id as u64,
+            is_live: Some(is_live),
+            trader_login: Some(account_id % 1_000_000),
+            last_closing_deal_timestamp: None,
+            last_balance_update_timestamp: None,
+            broker_title_short: Some("Mock".to_string()),
+        });
+        self
+    }
+
+    pub fn symbol(mut self, symbol: MockSymbol) -> Self {
+        self.symbols.push(symbol);
+        self
+    }
+
+    /// The bars answered to trend bar requests for this symbol and period.
+    pub fn trendbars(mut self, symbol_id: i64, period: ProtoOaTrendbarPeriod, bars: Vec<ProtoOaTrendbar>) -> Self {
+        self.trendbars.insert((symbol_id, period as i32), bars);
+        self
+    }
+
+    /// Only accept application auth with this client id and secret, any
+    /// credentials are accepted otherwise.
+    pub fn credentials(mut self, client_id: &str, client_secret: &str) -> Self {
+        self.credentials = Some((client_id.to_string(), client_secret.to_string()));
+        self
+    }
+
+    pub fn user_id(mut self, user_id: i64) -> Self {
+        self.user_id = user_id;
+        self
+    }
+
+    /// The version answered to version requests, the compiled one otherwise.
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// Serve TLS with this certificate instead of plain TCP.
+    pub fn tls(mut self, cert_chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Self {
+        self.tls = Some((cert_chain, key));
+        self
+    }
+
+    /// Speak json over a websocket, like the servers on port 5036, instead of
+    /// protobuf.  Connect with `WebSocketTransport`.
+    pub fn websocket(mut self) -> Self {
+        self.websocket = true;
+        self
+    }
+
+    /// Listen on a free port of 127.0.0.1.
+    pub async fn start(self) -> Result<MockServer, Box<dyn std::error::Error>> {
+        let acceptor = match self.tls {
+            Some((cert_chain, key)) => {
+                let config = ServerConfig::builder()
+                    .with_no_client_auth()
+                    .with_single_cert(cert_chain, key)?;
+                Some(TlsAcceptor::from(Arc::new(config)))
+            }
+            None => None,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(MockState {
+                accounts: self.accounts,
+                symbols: self.symbols,
+                trendbars: self.trendbars,
+                credentials: self.credentials,
+                user_id: self.user_id,
+                version: self.version,
+                scripts: HashMap::new(),
+                requests: Vec::new(),
+                connections: Vec::new(),
+                accepted: 0,
+                closed: 0,
+                positions: HashMap::new(),
+                orders: HashMap::new(),
+                last_prices: HashMap::new(),
+                next_id: 1,
+            }),
+            received: Notify::new(),
+        });
+
+        let accept_loop = tokio::spawn(accept(listener, acceptor, self.websocket, Arc::clone(&shared)));
+        Ok(MockServer {
+            addr,
+            shared,
+            accept_loop,
+        })
+    }
+}
+
+/// A running mock server.  Dropping it closes the listener and every
+/// connection.
+pub struct MockServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    accept_loop: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub fn builder() -> MockServerBuilder {
+        MockServerBuilder {
+            accounts: Vec::new(),
+            symbols: Vec::new(),
+            trendbars: HashMap::new(),
+            credentials: None,
+            user_id: 1,
+            version: PROTO_VERSION.to_string(),
+            tls: None,
+            websocket: false,
+        }
+    }
+
+    /// A server without accounts or symbols.
+    pub async fn start() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::builder().start().await
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The endpoint to connect a client to.  Use `TlsMode::Plain` unless the
+    /// server was given a certificate.
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::Custom {
+            host: "127.0.0.1".to_string(),
+            port: self.addr.port(),
+        }
+    }
+
+    /// Every request received so far, oldest first.
+    pub fn requests(&self) -> Vec<ProtoMessage> {
+        self.shared.lock().requests.clone()
+    }
+
+    /// The payloads of the requests of one type received so far.
+    pub fn requests_of<M: Message + Default>(&self, payload_type: impl Into<u32>) -> Vec<M> {
+        let payload_type = payload_type.into();
+        self.shared
+            .lock()
+            .requests
+            .iter()
+            .filter(|request| request.payload_type == payload_type)
+            .filter_map(|request| M::decode(request.payload.clone().unwrap_or_default()).ok())
+            .collect()
+    }
+
+    /// Wait until `count` requests of a type have been received, returning
+    /// their payloads, or `None` after `timeout`.
+    pub async fn wait_for<M: Message + Default>(
+        &self,
+        payload_type: impl Into<u32>,
+        count: usize,
+        timeout: Duration,
+    ) -> Option<Vec<M>> {
+        let payload_type = payload_type.into();
+        let wait = async {
+            loop {
+                // registered before checking so no request is missed
+                let received = self.shared.received.notified();
+                let requests = self.requests_of::<M>(payload_type);
+                if requests.len() >= count {
+                    return requests;
+                }
+                received.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.ok()
+    }
+
+    /// Answer the next request of this type with `responses` instead of the
+    /// simulated ones.  Scripts for the same type are used in order.
+    pub fn script(&self, payload_type: impl Into<u32>, responses: Vec<ProtoMessage>) {
+        self.shared
+            .lock()
+            .scripts
+            .entry(payload_type.into())
+            .or_default()
+            .push_back(responses);
+    }
+
+    /// Send a message to every connected client.
+    pub fn push(&self, message: ProtoMessage) {
+        self.shared
+            .lock()
+            .connections
+            .retain(|connection| connection.send(Command::Send(message.clone())).is_ok());
+    }
+
+    /// Push a spot event, prices in 1/100000 of a unit.
+    pub fn push_spot(&self, account_id: i64, symbol_id: i64, bid: u64, ask: u64) {
+        self.shared.lock().last_prices.insert(symbol_id, (bid, ask));
+        self.push(message(
+            ProtoOaPayloadType::ProtoOaSpotEvent as u32,
+            ProtoOaSpotEvent {
+                payload_type: Some(ProtoOaPayloadType::ProtoOaSpotEvent as i32),
+                ctid_trader_account_id: account_id,
+                symbol_id,
+                bid: Some(bid),
+                ask: Some(ask),
+                trendbar: Vec::new(),
+                session_close: None,
+                timestamp: Some(now_millis()),
+            },
+        ));
+    }
+
+    /// Close every open connection, the listener keeps accepting new ones.
+    pub fn disconnect_all(&self) {
+        for connection in self.shared.lock().connections.drain(..) {
+            let _ = connection.send(Command::Close);
+        }
+    }
+
+    /// How many connections were accepted so far.
+    pub fn connections_accepted(&self) -> usize {
+        self.shared.lock().accepted
+    }
+
+    /// How many connections the client closed cleanly, with a TLS
+    /// close_notify when over TLS, rather than just dropping them.
+    pub fn connections_closed(&self) -> usize {
+        self.shared.lock().closed
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.accept_loop.abort();
+        self.disconnect_all();
+    }
+}
+
+enum Command {
+    Send(ProtoMessage),
+    Close,
+}
+
+struct MockState {
+    accounts: Vec<ProtoOaCtidTraderAccount>,
+    symbols: Vec<MockSymbol>,
+    trendbars: HashMap<(i64, i32), Vec<ProtoOaTrendbar>>,
+    credentials: Option<(String, String)>,
+    user_id: i64,
+    version: String,
+    scripts: HashMap<u32, VecDeque<Vec<ProtoMessage>>>,
+    requests: Vec<ProtoMessage>,
+    connections: Vec<mpsc::UnboundedSender<Command>>,
+    accepted: usize,
+    closed: usize,
+    // open positions by id, with their account
+    positions: HashMap<i64, (i64, ProtoOaPosition)>,
+    // accepted pending orders by id, with their account
+    orders: HashMap<i64, (i64, ProtoOaOrder)>,
+    // the last pushed (bid, ask) of every symbol, used as fill prices
+    last_prices: HashMap<i64, (u64, u64)>,
+    next_id: i64,
+}
+
+struct Shared {
+    // a std mutex so `Drop` can close the connections, nothing awaits while
+    // holding it
+    state: Mutex<MockState>,
+    received: Notify,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // records a request and works out the answer
+    fn respond(&self, request: ProtoMessage) -> Vec<ProtoMessage> {
+        let mut state = self.lock();
+        state.requests.push(request.clone());
+        let scripted = state
+            .scripts
+            .get_mut(&request.payload_type)
+            .and_then(|scripts| scripts.pop_front());
+        let mut responses = match scripted {
+            Some(responses) => responses,
+            None => state.simulate(&request),
+        };
+        drop(state);
+        self.received.notify_waiters();
+
+        // responses carry the id of the request, as on the real servers
+        for response in &mut responses {
+            if response.client_msg_id.is_none() {
+                response.client_msg_id = request.client_msg_id.clone();
+            }
+        }
+        responses
+    }
+}
+
+impl MockState {
+    fn simulate(&mut self, request: &ProtoMessage) -> Vec<ProtoMessage> {
+        let payload = request.payload.clone().unwrap_or_default();
+        let Ok(payload_type) = ProtoOaPayloadType::try_from(request.payload_type as i32) else {
+            if request.payload_type == ProtoPayloadType::HeartbeatEvent as u32 {
+                return Vec::new();
+            }
+            return vec![unsupported(request.payload_type)];
+        };
+
+        match payload_type {
+            ProtoOaPayloadType::ProtoOaApplicationAuthReq => {
+                let Ok(req) = ProtoOaApplicationAuthReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                if let Some((client_id, client_secret)) = &self.credentials
+                    && (req.client_id != *client_id || req.client_secret != *client_secret)
+                {
+                    return vec![error_response(None, "CH_CLIENT_AUTH_FAILURE", "Invalid client id or secret")];
+                }
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaApplicationAuthRes as u32,
+                    ProtoOaApplicationAuthRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaApplicationAuthRes as i32),
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaAccountAuthReq => {
+                let Ok(req) = ProtoOaAccountAuthReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                let account_id = req.ctid_trader_account_id;
+                if !self.has_account(account_id) {
+                    return vec![error_response(
+                        Some(account_id),
+                        "CH_CTID_TRADER_ACCOUNT_NOT_FOUND",
+                        "Trading account is not found",
+                    )];
+                }
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaAccountAuthRes as u32,
+                    ProtoOaAccountAuthRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaAccountAuthRes as i32),
+                        ctid_trader_account_id: account_id,
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaAccountLogoutReq => {
+                let Ok(req) = ProtoOaAccountLogoutReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaAccountLogoutRes as u32,
+                    ProtoOaAccountLogoutRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaAccountLogoutRes as i32),
+                        ctid_trader_account_id: req.ctid_trader_account_id,
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaVersionReq => vec![message(
+                ProtoOaPayloadType::ProtoOaVersionRes as u32,
+                ProtoOaVersionRes {
+                    payload_type: Some(ProtoOaPayloadType::ProtoOaVersionRes as i32),
+                    version: self.version.clone(),
+                },
+            )],
+
+            ProtoOaPayloadType::ProtoOaGetAccountsByAccessTokenReq => vec![message(
+                ProtoOaPayloadType::ProtoOaGetAccountsByAccessTokenRes as u32,
+                ProtoOaGetAccountListByAccessTokenRes {
+                    payload_type: Some(ProtoOaPayloadType::ProtoOaGetAccountsByAccessTokenRes as i32),
+                    access_token: String::new(),
+                    permission_scope: Some(1),
+                    ctid_trader_account: self.accounts.clone(),
+                },
+            )],
+
+            ProtoOaPayloadType::ProtoOaGetCtidProfileByTokenReq => vec![message(
+                ProtoOaPayloadType::ProtoOaGetCtidProfileByTokenRes as u32,
+                ProtoOaGetCtidProfileByTokenRes {
+                    payload_type: Some(ProtoOaPayloadType::ProtoOaGetCtidProfileByTokenRes as i32),
+                    profile: ProtoOaCtidProfile { user_id: self.user_id },
+                },
+            )],
+
+            ProtoOaPayloadType::ProtoOaSymbolsListReq => {
+                let Ok(req) = ProtoOaSymbolsListReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                let symbol = self
+                    .symbols
+                    .iter()
+                    .map(|symbol| ProtoOaLightSymbol {
+                        symbol_id: symbol.symbol_id,
+                        symbol_name: Some(symbol.name.clone()),
+                        enabled: Some(true),
+                        base_asset_id: Some(symbol.base_asset_id),
+                        quote_asset_id: Some(symbol.quote_asset_id),
+                        ..Default::default()
+                    })
+                    .collect();
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaSymbolsListRes as u32,
+                    ProtoOaSymbolsListRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaSymbolsListRes as i32),
+                        ctid_trader_account_id: req.ctid_trader_account_id,
+                        symbol,
+                        archived_symbol: Vec::new(),
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaSymbolByIdReq => {
+                let Ok(req) = ProtoOaSymbolByIdReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                let symbol = self
+                    .symbols
+                    .iter()
+                    .filter(|symbol| req.symbol_id.contains(&symbol.symbol_id))
+                    .map(|symbol| ProtoOaSymbol {
+                        symbol_id: symbol.symbol_id,
+                        digits: symbol.digits,
+                        pip_position: symbol.pip_position,
+                        max_volume: Some(symbol.max_volume),
+                        min_volume: Some(symbol.min_volume),
+                        step_volume: Some(symbol.step_volume),
+                        lot_size: Some(symbol.lot_size),
+                        ..Default::default()
+                    })
+                    .collect();
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaSymbolByIdRes as u32,
+                    ProtoOaSymbolByIdRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaSymbolByIdRes as i32),
+                        ctid_trader_account_id: req.ctid_trader_account_id,
+                        symbol,
+                        archived_symbol: Vec::new(),
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaGetTrendbarsReq => {
+                let Ok(req) = ProtoOaGetTrendbarsReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                let from = req.from_timestamp.unwrap_or(i64::MIN);
+                let to = req.to_timestamp.unwrap_or(i64::MAX);
+                let trendbar = self
+                    .trendbars
+                    .get(&(req.symbol_id, req.period))
+                    .into_iter()
+                    .flatten()
+                    .filter(|bar| {
+                        let timestamp = bar.utc_timestamp_in_minutes.unwrap_or_default() as i64 * 60_000;
+                        (from..=to).contains(&timestamp)
+                    })
+                    .cloned()
+                    .collect();
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaGetTrendbarsRes as u32,
+                    ProtoOaGetTrendbarsRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaGetTrendbarsRes as i32),
+                        ctid_trader_account_id: req.ctid_trader_account_id,
+                        period: req.period,
+                        trendbar,
+                        symbol_id: Some(req.symbol_id),
+                        has_more: Some(false),
+                        ..Default::default()
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaSubscribeSpotsReq => {
+                let Ok(req) = ProtoOaSubscribeSpotsReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaSubscribeSpotsRes as u32,
+                    ProtoOaSubscribeSpotsRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaSubscribeSpotsRes as i32),
+                        ctid_trader_account_id: req.ctid_trader_account_id,
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaSubscribeLiveTrendbarReq => {
+                let Ok(req) = ProtoOaSubscribeLiveTrendbarReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaSubscribeLiveTrendbarRes as u32,
+                    ProtoOaSubscribeLiveTrendbarRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaSubscribeLiveTrendbarRes as i32),
+                        ctid_trader_account_id: req.ctid_trader_account_id,
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaUnsubscribeSpotsReq => {
+                let Ok(req) = ProtoOaUnsubscribeSpotsReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaUnsubscribeSpotsRes as u32,
+                    ProtoOaUnsubscribeSpotsRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaUnsubscribeSpotsRes as i32),
+                        ctid_trader_account_id: req.ctid_trader_account_id,
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaUnsubscribeLiveTrendbarReq => {
+                let Ok(req) = ProtoOaUnsubscribeLiveTrendbarReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                vec![message(
+                    ProtoOaPayloadType::ProtoOaUnsubscribeLiveTrendbarRes as u32,
+                    ProtoOaUnsubscribeLiveTrendbarRes {
+                        payload_type: Some(ProtoOaPayloadType::ProtoOaUnsubscribeLiveTrendbarRes as i32),
+                        ctid_trader_account_id: req.ctid_trader_account_id,
+                    },
+                )]
+            }
+
+            ProtoOaPayloadType::ProtoOaNewOrderReq => {
+                let Ok(req) = ProtoOaNewOrderReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                self.fill_new_order(req)
+            }
+
+            ProtoOaPayloadType::ProtoOaClosePositionReq => {
+                let Ok(req) = ProtoOaClosePositionReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                self.close_position(req)
+            }
+
+            ProtoOaPayloadType::ProtoOaCancelOrderReq => {
+                let Ok(req) = ProtoOaCancelOrderReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                self.cancel_order(req)
+            }
+
+            ProtoOaPayloadType::ProtoOaReconcileReq => {
+                let Ok(req) = ProtoOaReconcileReq::decode(payload) else {
+                    return vec![invalid_request()];
+                };
+                self.reconcile(req)
+            }
+
+            _ => vec![unsupported(request.payload_type)],
+        }
+    }
+
+    fn has_account(&self, account_id: i64) -> bool {
+        self.accounts
+            .iter()
+            .any(|account| account.ctid_trader_account_id as i64 == account_id)
+    }
+
+    // market orders are filled in full at the last pushed price, anything
+    // else is only accepted
+    fn fill_new_order(&mut self, req: ProtoOaNewOrderReq) -> Vec<ProtoMessage> {
+        let account_id = req.ctid_trader_account_id;
+        if !self.symbols.iter().any(|symbol| symbol.symbol_id == req.symbol_id) {
+            return vec![order_error(account_id, "SYMBOL_NOT_FOUND", "Symbol is not found", None)];
+        }
+
+        let order_id = self.next_id();
+        let position_id = self.next_id();
+        let now = now_millis();
+        let is_market = req.order_type == ProtoOaOrderType::Market as i32;
+        let price = self.last_prices.get(&req.symbol_id).map(|(bid, ask)| {
+            let price = if req.trade_side == crate::open_api::ProtoOaTradeSide::Buy as i32 { *ask } else { *bid };
+            price as f64 / 100_000.0
+        });
+
+        let trade_data = ProtoOaTradeData {
+            symbol_id: req.symbol_id,
+            volume: req.volume,
+            trade_side: req.trade_side,
+            open_timestamp: Some(now),
+            label: req.label.clone(),
+            comment: req.comment.clone(),
+            ..Default::default()
+        };
+        let order = ProtoOaOrder {
+            order_id,
+            trade_data: trade_data.clone(),
+            order_type: req.order_type,
+            order_status: if is_market {
+                ProtoOaOrderStatus::OrderStatusFilled as i32
+            } else {
+                ProtoOaOrderStatus::OrderStatusAccepted as i32
+            },
+            execution_price: price.filter(|_| is_market),
+            executed_volume: is_market.then_some(req.volume),
+            utc_last_update_timestamp: Some(now),
+            limit_price: req.limit_price,
+            stop_price: req.stop_price,
+            client_order_id: req.client_order_id.clone(),
+            position_id: Some(position_id),
+            relative_stop_loss: req.relative_stop_loss,
+            relative_take_profit: req.relative_take_profit,
+            ..Default::default()
+        };
+
+        if !is_market {
+            self.orders.insert(order_id, (account_id, order.clone()));
+            return vec![execution(account_id, ProtoOaExecutionType::OrderAccepted, None, order, None)];
+        }
+
+        let position = ProtoOaPosition {
+            position_id,
+            trade_data,
+            position_status: ProtoOaPositionStatus::PositionStatusOpen as i32,
+            price,
+            utc_last_update_timestamp: Some(now),
+            money_digits: Some(2),
+            ..Default::default()
+        };
+        let deal = ProtoOaDeal {
+            deal_id: self.next_id(),
+            order_id,
+            position_id,
+            volume: req.volume,
+            filled_volume: req.volume,
+            symbol_id: req.symbol_id,
+            create_timestamp: now,
+            execution_timestamp: now,
+            execution_price: price,
+            trade_side: req.trade_side,
+            deal_status: ProtoOaDealStatus::Filled as i32,
+            ..Default::default()
+        };
+        self.positions.insert(position_id, (account_id, position.clone()));
+
+        vec![execution(account_id, ProtoOaExecutionType::OrderFilled, Some(position), order, Some(deal))]
+    }
+
+    fn close_position(&mut self, req: ProtoOaClosePositionReq) -> Vec<ProtoMessage> {
+        let account_id = req.ctid_trader_account_id;
+        let Some((_, mut position)) = self
+            .positions
+            .remove(&req.position_id)
+            .filter(|(owner, _)| *owner == account_id)
+        else {
+            return vec![order_error(
+                account_id,
+                "POSITION_NOT_FOUND",
+                "Position is not found",
+                Some(req.position_id),
+            )];
+        };
+
+        let now = now_millis();
+        let closing_side = if position.trade_data.trade_side == crate::open_api::ProtoOaTradeSide::Buy as i32 {
+            crate::open_api::ProtoOaTradeSide::Sell as i32
+        } else {
+            crate::open_api::ProtoOaTradeSide::Buy as i32
+        };
+        let order = ProtoOaOrder {
+            order_id: self.next_id(),
+            trade_data: ProtoOaTradeData {
+                trade_side: closing_side,
+                volume: req.volume,
+                ..position.trade_data.clone()
+            },
+            order_type: ProtoOaOrderType::Market as i32,
+            order_status: ProtoOaOrderStatus::OrderStatusFilled as i32,
+            executed_volume: Some(req.volume),
+            closing_order: Some(true),
+            position_id: Some(req.position_id),
+            utc_last_update_timestamp: Some(now),
+            ..Default::default()
+        };
+
+        // partial closes leave the rest of the position open
+        let remaining = position.trade_data.volume - req.volume;
+        position.utc_last_update_timestamp = Some(now);
+        if remaining > 0 {
+            position.trade_data.volume = remaining;
+            self.positions.insert(req.position_id, (account_id, position.clone()));
+        } else {
+            position.position_status = ProtoOaPositionStatus::PositionStatusClosed as i32;
+            position.trade_data.volume = 0;
+        }
+
+        vec![execution(account_id, ProtoOaExecutionType::OrderFilled, Some(position), order, None)]
+    }
+
+    fn cancel_order(&mut self, req: ProtoOaCancelOrderReq) -> Vec<ProtoMessage> {
+        let account_id = req.ctid_trader_account_id;
+        let Some((_, mut order)) = self
+            .orders
+            .remove(&req.order_id)
+            .filter(|(owner, _)| *owner == account_id)
+        else {
+            return vec![order_error(account_id, "ORDER_NOT_FOUND", "Order is not found", None)];
+        };
+
+        order.order_status = ProtoOaOrderStatus::OrderStatusCancelled as i32;
+        order.utc_last_update_timestamp = Some(now_millis());
+        vec![execution(account_id, ProtoOaExecutionType::OrderCancelled, None, order, None)]
+    }
+
+    // the open positions and pending orders of the account
+    fn reconcile(&self, req: ProtoOaReconcileReq) -> Vec<ProtoMessage> {
+        let account_id = req.ctid_trader_account_id;
+        let mut position: Vec<ProtoOaPosition> = self
+            .positions
+            .values()
+            .filter(|(owner, _)| *owner == account_id)
+            .map(|(_, position)| position.clone())
+            .collect();
+        position.sort_by_key(|position| position.position_id);
+        let mut order: Vec<ProtoOaOrder> = self
+            .orders
+            .values()
+            .filter(|(owner, _)| *owner == account_id)
+            .map(|(_, order)| order.clone())
+            .collect();
+        order.sort_by_key(|order| order.order_id);
+
+        vec![message(
+            ProtoOaPayloadType::ProtoOaReconcileRes as u32,
+            ProtoOaReconcileRes {
+                payload_type: Some(ProtoOaPayloadType::ProtoOaReconcileRes as i32),
+                ctid_trader_account_id: account_id,
+                position,
+                order,
+            },
+        )]
+    }
+
+    fn next_id(&mut self) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+fn execution(
+    account_id: i64,
+    execution_type: ProtoOaExecutionType,
+    position: Option<ProtoOaPosition>,
+    order: ProtoOaOrder,
+    deal: Option<ProtoOaDeal>,
+) -> ProtoMessage {
+    message(
+        ProtoOaPayloadType::ProtoOaExecutionEvent as u32,
+        ProtoOaExecutionEvent {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaExecutionEvent as i32),
+            ctid_trader_account_id: account_id,
+            execution_type: execution_type as i32,
+            position,
+            order: Some(order),
+            deal,
+            ..Default::default()
+        },
+    )
+}
+
+fn order_error(account_id: i64, error_code: &str, description: &str, position_id: Option<i64>) -> ProtoMessage {
+    message(
+        ProtoOaPayloadType::ProtoOaOrderErrorEvent as u32,
+        ProtoOaOrderErrorEvent {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaOrderErrorEvent as i32),
+            ctid_trader_account_id: account_id,
+            error_code: error_code.to_string(),
+            order_id: None,
+            position_id,
+            description: Some(description.to_string()),
+        },
+    )
+}
+
+fn unsupported(payload_type: u32) -> ProtoMessage {
+    error_response(
+        None,
+        ProtoErrorCode::UnsupportedMessage.as_str_name(),
+        &format!("payload type {} is not supported by the mock server", payload_type),
+    )
+}
+
+fn invalid_request() -> ProtoMessage {
+    error_response(None, ProtoErrorCode::InvalidRequest.as_str_name(), "the request could not be decoded")
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+async fn accept(listener: TcpListener, acceptor: Option<TlsAcceptor>, websocket: bool, shared: Arc<Shared>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        shared.lock().accepted += 1;
+        let shared = Arc::clone(&shared);
+        match acceptor.clone() {
+            Some(acceptor) => {
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        serve(stream, websocket, shared).await;
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(serve(stream, websocket, shared));
+            }
+        }
+    }
+}
+
+// answers the requests of one connection and forwards the pushed messages
+async fn serve<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S, websocket: bool, shared: Arc<Shared>) {
+    let (mut requests, mut responses) = if websocket {
+        match tokio_tungstenite::accept_async(stream).await {
+            Ok(websocket) => transport::json_messages(websocket),
+            Err(_) => return,
+        }
+    } else {
+        transport::protobuf_messages(stream, DEFAULT_MAX_FRAME_SIZE)
+    };
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    shared.lock().connections.push(tx);
+
+    loop {
+        tokio::select! {
+            request = requests.next() => {
+                let request = match request {
+                    Some(Ok(request)) => request,
+                    // the client closed its side, over TLS with a close_notify
+                    None => {
+                        shared.lock().closed += 1;
+                        return;
+                    }
+                    Some(Err(_)) => return,
+                };
+                for response in shared.respond(request) {
+                    if responses.send(response).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            command = rx.recv() => match command {
+                Some(Command::Send(message)) => {
+                    if responses.send(message).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Command::Close) | None => return,
+            },
+        }
+    }
+}
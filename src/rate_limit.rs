@@ -0,0 +1,362 @@
+// this is the client side rate limiter.  the server allows a connection about
+// 50 requests per second, and 5 per second for historical data, and answers
+// anything above that with REQUEST_FREQUENCY_EXCEEDED.  every request sent by
+// `CtraderClient::send_message` first takes a token from the bucket of its
+// class, waiting in line when the bucket is empty.  order related requests
+// skip the line of the other non-historical requests.  heartbeats are never
+// held back, they keep the connection alive while the buckets are empty.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
+
+use crate::open_api::{ProtoOaPayloadType, ProtoPayloadType};
+
+/// Requests per second allowed on one connection.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// every request that is not historical, order related ones included
+    pub requests_per_sec: f64,
+    /// trend bars, tick data, deal / order / cash flow history
+    pub historical_per_sec: f64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            requests_per_sec: 50.0,
+            historical_per_sec: 5.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestClass {
+    /// historical data, limited separately
+    Historical,
+    /// placing, amending, cancelling orders and closing positions, served
+    /// before waiting `General` requests
+    Trading,
+    General,
+}
+
+impl RequestClass {
+    /// The class a request is limited by, `None` for heartbeats.
+    pub fn of(payload_type: u32) -> Option<Self> {
+        if payload_type == ProtoPayloadType::HeartbeatEvent as u32 {
+            return None;
+        }
+        let Ok(payload_type) = ProtoOaPayloadType::try_from(payload_type as i32) else {
+            return Some(RequestClass::General);
+        };
+        let class = match payload_type {
+            ProtoOaPayloadType::ProtoOaGetTrendbarsReq
+            | ProtoOaPayloadType::ProtoOaGetTickdataReq
+            | ProtoOaPayloadType::ProtoOaDealListReq
+            | ProtoOaPayloadType::ProtoOaDealListByPositionIdReq
+            | ProtoOaPayloadType::ProtoOaDealOffsetListReq
+            | ProtoOaPayloadType::ProtoOaOrderListReq
+            | ProtoOaPayloadType::ProtoOaOrderListByPositionIdReq
+            | ProtoOaPayloadType::ProtoOaCashFlowHistoryListReq => RequestClass::Historical,
+            ProtoOaPayloadType::ProtoOaNewOrderReq
+            | ProtoOaPayloadType::ProtoOaCancelOrderReq
+            | ProtoOaPayloadType::ProtoOaAmendOrderReq
+            | ProtoOaPayloadType::ProtoOaAmendPositionSltpReq
+            | ProtoOaPayloadType::ProtoOaClosePositionReq => RequestClass::Trading,
+            _ => RequestClass::General,
+        };
+        Some(class)
+    }
+}
+
+/// How long the requests of one class waited for a token.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DelayStats {
+    pub requests: u64,
+    /// requests that waited a millisecond or more
+    pub delayed: u64,
+    pub total_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl DelayStats {
+    pub fn average_delay(&self) -> Duration {
+        if self.requests == 0 {
+            return Duration::ZERO;
+        }
+        self.total_delay / self.requests as u32
+    }
+
+    fn record(&mut self, delay: Duration) {
+        self.requests += 1;
+        if delay >= Duration::from_millis(1) {
+            self.delayed += 1;
+        }
+        self.total_delay += delay;
+        self.max_delay = self.max_delay.max(delay);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimitStats {
+    pub historical: DelayStats,
+    pub trading: DelayStats,
+    pub general: DelayStats,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    per_sec: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    // full from the start, so a burst of `per_sec` requests goes out at once
+    fn new(per_sec: f64) -> Self {
+        let capacity = per_sec.max(1.0);
+        Self {
+            capacity,
+            per_sec,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    // takes a token, or tells how long until the next one
+    fn try_take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    general: Mutex<TokenBucket>,
+    historical: Mutex<TokenBucket>,
+    // the lines requests wait in.  tokio mutexes are fair, so holding one of
+    // these while waiting for a token serves each class in fifo order.
+    trading_line: Mutex<()>,
+    general_line: Mutex<()>,
+    historical_line: Mutex<()>,
+    // trading requests waiting for a general token, and a wake up for the
+    // general requests held back by them.
+    trading_waiting: AtomicUsize,
+    trading_served: Notify,
+    stats: Mutex<RateLimitStats>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Result<Self, String> {
+        if !(limits.requests_per_sec > 0.0 && limits.historical_per_sec > 0.0) {
+            return Err("rate limits must be greater than 0".to_string());
+        }
+        Ok(Self {
+            general: Mutex::new(TokenBucket::new(limits.requests_per_sec)),
+            historical: Mutex::new(TokenBucket::new(limits.historical_per_sec)),
+            trading_line: Mutex::new(()),
+            general_line: Mutex::new(()),
+            historical_line: Mutex::new(()),
+            trading_waiting: AtomicUsize::new(0),
+            trading_served: Notify::new(),
+            stats: Mutex::new(RateLimitStats::default()),
+        })
+    }
+
+    /// Wait until a request of this class may be sent, returning how long
+    /// that took.
+    pub async fn acquire(&self, class: RequestClass) -> Duration {
+        let started = Instant::now();
+        match class {
+            RequestClass::Historical => {
+                let _line = self.historical_line.lock().await;
+                take(&self.historical).await;
+            }
+            RequestClass::Trading => {
+                let _waiting = TradingWaiting::new(self);
+                let _line = self.trading_line.lock().await;
+                take(&self.general).await;
+            }
+            RequestClass::General => {
+                let _line = self.general_line.lock().await;
+                loop {
+                    // registered before checking so no wake up is missed
+                    let served = self.trading_served.notified();
+                    if self.trading_waiting.load(Ordering::SeqCst) > 0 {
+                        served.await;
+                        continue;
+                    }
+                    let next = self.general.lock().await.try_take();
+                    match next {
+                        Ok(()) => break,
+                        Err(wait) => tokio::time::sleep(wait).await,
+                    }
+                }
+            }
+        }
+
+        let delay = started.elapsed();
+        let mut stats = self.stats.lock().await;
+        match class {
+            RequestClass::Historical => stats.historical.record(delay),
+            RequestClass::Trading => stats.trading.record(delay),
+            RequestClass::General => stats.general.record(delay),
+        }
+        delay
+    }
+
+    pub async fn stats(&self) -> RateLimitStats {
+        *self.stats.lock().await
+    }
+}
+
+// counts a trading request as waiting until it is served or dropped
+struct TradingWaiting<'a>(&'a RateLimiter);
+
+impl<'a> TradingWaiting<'a> {
+    fn new(limiter: &'a RateLimiter) -> Self {
+        limiter.trading_waiting.fetch_add(1, Ordering::SeqCst);
+        Self(limiter)
+    }
+}
+
+impl Drop for TradingWaiting<'_> {
+    fn drop(&mut self) {
+        self.0.trading_waiting.fetch_sub(1, Ordering::SeqCst);
+        self.0.trading_served.notify_waiters();
+    }
+}
+
+async fn take(bucket: &Mutex<TokenBucket>) {
+    loop {
+        let next = bucket.lock().await.try_take();
+        match next {
+            Ok(()) => return,
+            Err(wait) => tokio::time::sleep(wait).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn classifies_requests() {
+        let cases = [
+            (ProtoPayloadType::HeartbeatEvent as u32, None),
+            (ProtoOaPayloadType::ProtoOaNewOrderReq as u32, Some(RequestClass::Trading)),
+            (ProtoOaPayloadType::ProtoOaClosePositionReq as u32, Some(RequestClass::Trading)),
+            (ProtoOaPayloadType::ProtoOaGetTrendbarsReq as u32, Some(RequestClass::Historical)),
+            (ProtoOaPayloadType::ProtoOaDealListReq as u32, Some(RequestClass::Historical)),
+            (ProtoOaPayloadType::ProtoOaSubscribeSpotsReq as u32, Some(RequestClass::General)),
+            (ProtoOaPayloadType::ProtoOaVersionReq as u32, Some(RequestClass::General)),
+        ];
+        for (payload_type, class) in cases {
+            assert_eq!(RequestClass::of(payload_type), class, "payload type {payload_type}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_starts_full_and_refills() {
+        let mut bucket = TokenBucket::new(5.0);
+        for _ in 0..5 {
+            assert_eq!(bucket.try_take(), Ok(()));
+        }
+        let wait = bucket.try_take().unwrap_err();
+        assert!((wait.as_secs_f64() - 0.2).abs() < 1e-6, "{wait:?}");
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(bucket.try_take().is_err());
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(bucket.try_take(), Ok(()));
+
+        // never more than a second worth of tokens
+        tokio::time::advance(Duration::from_secs(10)).await;
+        for _ in 0..5 {
+            assert_eq!(bucket.try_take(), Ok(()));
+        }
+        assert!(bucket.try_take().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_out_requests_over_the_limit() {
+        let limiter = RateLimiter::new(RateLimits {
+            requests_per_sec: 50.0,
+            historical_per_sec: 5.0,
+        })
+        .unwrap();
+        for _ in 0..5 {
+            assert_eq!(limiter.acquire(RequestClass::Historical).await, Duration::ZERO);
+        }
+        let delay = limiter.acquire(RequestClass::Historical).await;
+        assert!(delay >= Duration::from_millis(199), "{delay:?}");
+        // the general bucket is untouched by historical requests
+        assert_eq!(limiter.acquire(RequestClass::General).await, Duration::ZERO);
+
+        let stats = limiter.stats().await;
+        assert_eq!(stats.historical.requests, 6);
+        assert_eq!(stats.historical.delayed, 1);
+        assert_eq!(stats.general.requests, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serves_trading_before_waiting_general_requests() {
+        let limiter = Arc::new(
+            RateLimiter::new(RateLimits {
+                requests_per_sec: 1.0,
+                historical_per_sec: 1.0,
+            })
+            .unwrap(),
+        );
+        limiter.acquire(RequestClass::General).await;
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let spawn = |class| {
+            let limiter = Arc::clone(&limiter);
+            let order = Arc::clone(&order);
+            tokio::spawn(async move {
+                let delay = limiter.acquire(class).await;
+                order.lock().await.push((class, delay));
+            })
+        };
+        let general = spawn(RequestClass::General);
+        // the general request is waiting for a token before the order comes in
+        tokio::task::yield_now().await;
+        let trading = spawn(RequestClass::Trading);
+        general.await.unwrap();
+        trading.await.unwrap();
+
+        let order = order.lock().await;
+        assert_eq!(order[0].0, RequestClass::Trading);
+        assert_eq!(order[1].0, RequestClass::General);
+        assert!(order[0].1 < order[1].1);
+    }
+
+    #[test]
+    fn rejects_limits_of_zero() {
+        assert!(
+            RateLimiter::new(RateLimits {
+                requests_per_sec: 0.0,
+                historical_per_sec: 5.0,
+            })
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,348 @@
+// wire level recording and replay.  a `Recorder` attached to the client logs
+// every `ProtoMessage` sent and received, with the time it went over the
+// wire, to a compact binary file.  `ReplayTransport` plays the received half
+// of such a file back to a client, so an incident can be reproduced offline
+// through the same `handle_proto_message` path that saw it live.
+//
+// the records are written by a thread of their own, so the read loop and
+// `send_message` only queue them.  they reach the disk on `flush`, which
+// `shutdown` calls, and when the recorder is dropped.
+//
+// the file is a header followed by one record per message:
+//
+//     timestamp   u64  microseconds since the unix epoch, big endian
+//     direction   u8   0 received, 1 sent
+//     length      u32  big endian
+//     message     the encoded ProtoMessage
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::future::BoxFuture;
+use futures_util::{SinkExt, StreamExt};
+use prost::Message;
+use tokio::sync::oneshot;
+use tracing::warn;
+
+use crate::ctrader::codec::DEFAULT_MAX_FRAME_SIZE;
+use crate::ctrader::stream_builder::ConnectOptions;
+use crate::ctrader::transport::{MessageSink, MessageStream, Transport};
+use crate::open_api::ProtoMessage;
+use crate::types::Endpoint;
+
+const MAGIC: &[u8; 8] = b"CTRADER1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// sent by the server
+    Received,
+    /// sent by the client
+    Sent,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedFrame {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub message: ProtoMessage,
+}
+
+/// Appends every message the client sends or receives to a recording.
+///
+/// Sent requests carry the client secret and the access tokens, so a
+/// recording has to be kept as private as the credentials themselves.
+#[derive(Debug)]
+pub struct Recorder {
+    // `None` once dropped, which ends the writer thread
+    commands: Option<mpsc::Sender<Command>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+enum Command {
+    Record(Vec<u8>),
+    Flush(oneshot::Sender<io::Result<()>>),
+}
+
+impl Recorder {
+    /// Start a new recording, replacing `path` if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.flush()?;
+
+        let (commands, received) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("ctrader-recorder".to_string())
+            .spawn(move || write_records(file, received))?;
+        Ok(Self {
+            commands: Some(commands),
+            writer: Some(writer),
+        })
+    }
+
+    /// Queue one message for the recording without waiting for the disk.
+    pub fn record(&self, direction: Direction, message: &ProtoMessage) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let encoded = message.encode_to_vec();
+
+        let mut record = Vec::with_capacity(13 + encoded.len());
+        record.extend_from_slice(&timestamp.to_be_bytes());
+        record.push(match direction {
+            Direction::Received => 0,
+            Direction::Sent => 1,
+        });
+        record.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        record.extend_from_slice(&encoded);
+        self.send(Command::Record(record))
+    }
+
+    /// Write everything recorded so far through to the disk, returning the
+    /// first write error since the last flush.
+    pub async fn flush(&self) -> io::Result<()> {
+        let (done, flushed) = oneshot::channel();
+        self.send(Command::Flush(done))?;
+        flushed.await.map_err(|_| writer_stopped())?
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        match &self.commands {
+            Some(commands) => commands.send(command).map_err(|_| writer_stopped()),
+            None => Err(writer_stopped()),
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // closing the channel makes the thread write what is queued and flush
+        self.commands = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn writer_stopped() -> io::Error {
+    io::Error::other("the recording writer stopped")
+}
+
+fn write_records(mut file: BufWriter<File>, commands: mpsc::Receiver<Command>) {
+    // kept until a flush reports it
+    let mut failed: Option<io::Error> = None;
+    for command in commands {
+        match command {
+            Command::Record(record) => {
+                if failed.is_none()
+                    && let Err(e) = file.write_all(&record)
+                {
+                    warn!(error = %e, "could not write to the recording");
+                    failed = Some(e);
+                }
+            }
+            Command::Flush(done) => {
+                let result = match failed.take() {
+                    Some(e) => Err(e),
+                    None => file.flush().and_then(|()| file.get_ref().sync_all()),
+                };
+                let _ = done.send(result);
+            }
+        }
+    }
+    if let Err(e) = file.flush() {
+        warn!(error = %e, "could not flush the recording");
+    }
+}
+
+/// Read a whole recording.  Records longer than `DEFAULT_MAX_FRAME_SIZE`
+/// are refused like they are on a live connection.
+pub fn read_recording(path: impl AsRef<Path>) -> io::Result<Vec<RecordedFrame>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a ctrader recording"));
+    }
+
+    let mut frames = Vec::new();
+    let mut header = [0u8; 13];
+    loop {
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let timestamp = u64::from_be_bytes(header[0..8].try_into().unwrap_or_default());
+        let direction = match header[8] {
+            0 => Direction::Received,
+            1 => Direction::Sent,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid direction {} in recording", other),
+                ));
+            }
+        };
+        let len = u32::from_be_bytes(header[9..13].try_into().unwrap_or_default()) as usize;
+        if len > DEFAULT_MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "record of {} bytes exceeds the maximum frame size of {} bytes",
+                    len, DEFAULT_MAX_FRAME_SIZE
+                ),
+            ));
+        }
+
+        let mut encoded = vec![0u8; len];
+        match file.read_exact(&mut encoded) {
+            Ok(()) => {}
+            // the last record of a recording cut short by a crash
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let message = ProtoMessage::decode(&encoded[..]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        frames.push(RecordedFrame {
+            timestamp: UNIX_EPOCH + Duration::from_micros(timestamp),
+            direction,
+            message,
+        });
+    }
+
+    Ok(frames)
+}
+
+/// A transport playing back the messages received in a recording.  Whatever
+/// the client sends is dropped, and the connection closes after the last
+/// message, so replays are best run without a reconnect policy.
+#[derive(Debug, Clone)]
+pub struct ReplayTransport {
+    frames: Arc<Vec<RecordedFrame>>,
+    speed: Option<f64>,
+}
+
+impl ReplayTransport {
+    /// Replay `path` at the original speed.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(read_recording(path)?))
+    }
+
+    pub fn new(frames: Vec<RecordedFrame>) -> Self {
+        Self {
+            frames: Arc::new(frames),
+            speed: Some(1.0),
+        }
+    }
+
+    /// Play `speed` times faster than recorded, e.g. 10.0.
+    pub fn speed(mut self, speed: f64) -> Self {
+        self.speed = (speed > 0.0).then_some(speed);
+        self
+    }
+
+    /// Play every message as soon as the client reads it.
+    pub fn instant(mut self) -> Self {
+        self.speed = None;
+        self
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn connect<'a>(
+        &'a self,
+        _endpoint: &'a Endpoint,
+        _options: &'a ConnectOptions,
+        _max_frame_size: usize,
+    ) -> BoxFuture<'a, Result<(MessageStream, MessageSink), Box<dyn std::error::Error>>> {
+        let received: Vec<RecordedFrame> = self
+            .frames
+            .iter()
+            .filter(|frame| frame.direction == Direction::Received)
+            .cloned()
+            .collect();
+        let speed = self.speed;
+
+        Box::pin(async move {
+            let mut previous = received.first().map(|frame| frame.timestamp);
+            let messages: MessageStream = futures_util::stream::iter(received)
+                .then(move |frame| {
+                    // the gap to the previous message, scaled by the speed
+                    let gap = previous
+                        .and_then(|previous| frame.timestamp.duration_since(previous).ok())
+                        .unwrap_or_default();
+                    previous = Some(frame.timestamp);
+                    async move {
+                        if let Some(speed) = speed {
+                            tokio::time::sleep(gap.div_f64(speed)).await;
+                        }
+                        Ok(frame.message)
+                    }
+                })
+                .boxed();
+            let sink: MessageSink = Box::pin(futures_util::sink::drain().sink_map_err(|never| match never {}));
+            Ok((messages, sink))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rust_ctrader_{}_{}.rec", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn reads_back_what_was_recorded() {
+        let path = temp_path("round_trip");
+        let message = ProtoMessage {
+            payload_type: 51,
+            payload: None,
+            client_msg_id: Some("heartbeat".to_string()),
+        };
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(Direction::Sent, &message).unwrap();
+        recorder.record(Direction::Received, &message).unwrap();
+        recorder.flush().await.unwrap();
+
+        let frames = read_recording(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].direction, Direction::Sent);
+        assert_eq!(frames[1].direction, Direction::Received);
+        assert_eq!(frames[1].message, message);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn writes_the_queue_when_dropped() {
+        let path = temp_path("dropped");
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(Direction::Received, &ProtoMessage::default()).unwrap();
+        drop(recorder);
+
+        assert_eq!(read_recording(&path).unwrap().len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn refuses_oversized_records() {
+        let path = temp_path("oversized");
+        let mut file = Vec::from(&MAGIC[..]);
+        file.extend_from_slice(&0u64.to_be_bytes());
+        file.push(0);
+        file.extend_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&path, file).unwrap();
+
+        let err = read_recording(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,470 @@
+// this is the risk based position sizing.  instead of hard-coding a lot size
+// the caller says how much of the account it is willing to lose if the stop
+// is hit, and the lot size is derived from the stop distance and the pip value
+// of the symbol, then snapped to the volume constraints of the symbol.
+
+use crate::CtraderClient;
+use crate::types::{Order, OrderType, SymbolData, TimeInForce, TradeSide};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopDistance {
+    Pips(f64),
+    /// distance in price units, e.g. 0.0020 on EURUSD
+    Price(f64),
+}
+
+impl StopDistance {
+    pub fn in_pips(&self, pip_position: i32) -> f64 {
+        match self {
+            StopDistance::Pips(pips) => *pips,
+            StopDistance::Price(price) => price * 10_f64.powi(pip_position),
+        }
+    }
+
+    pub fn in_price(&self, pip_position: i32) -> f64 {
+        match self {
+            StopDistance::Pips(pips) => pips / 10_f64.powi(pip_position),
+            StopDistance::Price(price) => *price,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrderSize {
+    Lots(f64),
+    /// amount in the deposit currency lost if the stop is hit
+    Risk { amount: f64, stop: StopDistance },
+}
+
+/// What happens to a size below the symbol's `min_volume`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BelowMinimum {
+    /// trade the minimum volume instead
+    #[default]
+    Clamp,
+    /// fail, for callers that must not risk more than they asked for
+    Refuse,
+}
+
+/// Amount of money to risk for `risk_percent` (e.g. 1.0 for 1%) of the equity.
+pub fn risk_amount(equity: f64, risk_percent: f64) -> f64 {
+    equity * risk_percent / 100.0
+}
+
+/// Lot size that loses `risk_amount` when price moves `stop` against the
+/// position, rounded and clamped with `normalize_lots`.  Below the minimum
+/// volume that is more than `risk_amount`, see `lots_for_risk_with`.
+pub fn lots_for_risk(
+    risk_amount: f64,
+    stop: StopDistance,
+    pip_value_per_lot: f64,
+    symbol: &SymbolData,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    lots_for_risk_with(risk_amount, stop, pip_value_per_lot, symbol, BelowMinimum::Clamp)
+}
+
+/// `lots_for_risk`, with `below_minimum` deciding about sizes under the
+/// minimum volume.
+pub fn lots_for_risk_with(
+    risk_amount: f64,
+    stop: StopDistance,
+    pip_value_per_lot: f64,
+    symbol: &SymbolData,
+    below_minimum: BelowMinimum,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    let pip_position = symbol
+        .pip_position
+        .ok_or_else(|| format!("missing pip position for symbol {}", symbol.symbol_id))?;
+    let pips = stop.in_pips(pip_position);
+
+    if pips <= 0.0 {
+        return Err("stop distance must be positive".into());
+    }
+    if pip_value_per_lot <= 0.0 {
+        return Err("pip value per lot must be positive".into());
+    }
+
+    normalize_lots_with(risk_amount / (pips * pip_value_per_lot), symbol, below_minimum)
+}
+
+/// Round `lots` down to the symbol's `step_volume` and clamp it to
+/// `min_volume` and `max_volume`.
+pub fn normalize_lots(lots: f64, symbol: &SymbolData) -> Result<f64, Box<dyn std::error::Error>> {
+    normalize_lots_with(lots, symbol, BelowMinimum::Clamp)
+}
+
+/// `normalize_lots`, with `below_minimum` deciding about sizes under
+/// `min_volume`.
+pub fn normalize_lots_with(
+    lots: f64,
+    symbol: &SymbolData,
+    below_minimum: BelowMinimum,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    let lot_size = symbol
+        .lot_size
+        .ok_or_else(|| format!("missing lot size for symbol {}", symbol.symbol_id))? as f64;
+    // nothing to clamp up to the minimum
+    if !(lots > 0.0 && lots.is_finite()) {
+        return Err(format!("invalid order size of {} lots", lots).into());
+    }
+
+    // volumes are in cents, like lot_size
+    let mut volume = lots * lot_size;
+    if let Some(step) = symbol.step_volume.filter(|step| *step > 0) {
+        let step = step as f64;
+        // the epsilon keeps 0.3 lots from flooring to 0.29999...
+        volume = (volume / step + 1e-9).floor() * step;
+    }
+    if let Some(min) = symbol.min_volume
+        && volume < min as f64
+    {
+        match below_minimum {
+            BelowMinimum::Clamp => volume = min as f64,
+            BelowMinimum::Refuse => {
+                return Err(format!(
+                    "{} lots is below the minimum volume of {} lots for symbol {}",
+                    lots,
+                    min as f64 / lot_size,
+                    symbol.symbol_id
+                )
+                .into());
+            }
+        }
+    }
+    if let Some(max) = symbol.max_volume {
+        volume = volume.min(max as f64);
+    }
+
+    Ok(volume / lot_size)
+}
+
+// relative distances are expressed in 1/100000 of a price unit
+fn relative_distance(stop: StopDistance, pip_position: i32) -> i64 {
+    (stop.in_price(pip_position) * 100_000.0).round() as i64
+}
+
+/// Builder for `Order`, created with `Order::builder`.  The size is given
+/// either in lots or as a risk amount that is resolved when building.
+pub struct OrderBuilder {
+    account_id: u64,
+    symbol_id: u64,
+    trade_side: TradeSide,
+    order_type: OrderType,
+    size: OrderSize,
+    limit_price: Option<f64>,
+    stop_price: Option<f64>,
+    time_in_force: Option<TimeInForce>,
+    expiration_timestamp: Option<i64>,
+    comment: Option<String>,
+    slippage_in_points: Option<i32>,
+    label: Option<String>,
+    client_order_id: Option<String>,
+    stop_loss: Option<f64>,
+    take_profit: Option<f64>,
+    relative_stop_loss: Option<i64>,
+    relative_take_profit: Option<i64>,
+    guaranteed_stop_loss: Option<bool>,
+    trailing_stop_loss: Option<bool>,
+    below_minimum: BelowMinimum,
+}
+
+impl OrderBuilder {
+    pub fn new(account_id: u64, symbol_id: u64, trade_side: TradeSide) -> Self {
+        Self {
+            account_id,
+            symbol_id,
+            trade_side,
+            order_type: OrderType::Market,
+            size: OrderSize::Lots(0.0),
+            limit_price: None,
+            stop_price: None,
+            time_in_force: None,
+            expiration_timestamp: None,
+            comment: None,
+            slippage_in_points: None,
+            label: None,
+            client_order_id: None,
+            stop_loss: None,
+            take_profit: None,
+            relative_stop_loss: None,
+            relative_take_profit: None,
+            guaranteed_stop_loss: None,
+            trailing_stop_loss: None,
+            below_minimum: BelowMinimum::Clamp,
+        }
+    }
+
+    pub fn order_type(mut self, order_type: OrderType) -> Self {
+        self.order_type = order_type;
+        self
+    }
+
+    pub fn size(mut self, size: OrderSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn lots(self, lots: f64) -> Self {
+        self.size(OrderSize::Lots(lots))
+    }
+
+    /// Risk `amount` of the deposit currency with a stop `stop` away from
+    /// the entry.  The stop is also sent as the relative stop loss unless
+    /// `relative_stop_loss` is set explicitly.
+    pub fn risk(self, amount: f64, stop: StopDistance) -> Self {
+        self.size(OrderSize::Risk { amount, stop })
+    }
+
+    pub fn risk_percent(self, equity: f64, risk_percent: f64, stop: StopDistance) -> Self {
+        self.risk(risk_amount(equity, risk_percent), stop)
+    }
+
+    pub fn limit_price(mut self, price: f64) -> Self {
+        self.limit_price = Some(price);
+        self
+    }
+
+    pub fn stop_price(mut self, price: f64) -> Self {
+        self.stop_price = Some(price);
+        self
+    }
+
+    pub fn time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = Some(time_in_force);
+        self
+    }
+
+    pub fn expiration_timestamp(mut self, timestamp: i64) -> Self {
+        self.expiration_timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn slippage_in_points(mut self, points: i32) -> Self {
+        self.slippage_in_points = Some(points);
+        self
+    }
+
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn client_order_id(mut self, id: &str) -> Self {
+        self.client_order_id = Some(id.to_string());
+        self
+    }
+
+    /// Absolute stop loss price, refused on market orders by the server.
+    pub fn stop_loss(mut self, price: f64) -> Self {
+        self.stop_loss = Some(price);
+        self
+    }
+
+    /// Absolute take profit price, refused on market orders by the server.
+    pub fn take_profit(mut self, price: f64) -> Self {
+        self.take_profit = Some(price);
+        self
+    }
+
+    pub fn relative_stop_loss(mut self, distance: i64) -> Self {
+        self.relative_stop_loss = Some(distance);
+        self
+    }
+
+    pub fn relative_take_profit(mut self, distance: i64) -> Self {
+        self.relative_take_profit = Some(distance);
+        self
+    }
+
+    pub fn guaranteed_stop_loss(mut self, enabled: bool) -> Self {
+        self.guaranteed_stop_loss = Some(enabled);
+        self
+    }
+
+    pub fn trailing_stop_loss(mut self, enabled: bool) -> Self {
+        self.trailing_stop_loss = Some(enabled);
+        self
+    }
+
+    /// What a risk based size below the minimum volume turns into, the
+    /// minimum by default.
+    pub fn below_minimum(mut self, below_minimum: BelowMinimum) -> Self {
+        self.below_minimum = below_minimum;
+        self
+    }
+
+    /// Build the order.  A risk based size needs the symbol data and the pip
+    /// value to be available on the client, see
+    /// `CtraderClient::watch_symbol_conversion`.
+    pub async fn build(self, client: &CtraderClient) -> Result<Order, Box<dyn std::error::Error>> {
+        let mut relative_stop_loss = self.relative_stop_loss;
+
+        let lotsize = match self.size {
+            OrderSize::Lots(lots) => lots,
+            OrderSize::Risk { amount, stop } => {
+                let symbol = client
+                    .symbol_data(self.symbol_id)
+                    .await
+                    .ok_or_else(|| format!("no cached symbol data for symbol {}", self.symbol_id))?;
+                let pip_value = client
+                    .pip_value_per_lot(self.account_id as i64, self.symbol_id as i64)
+                    .await
+                    .ok_or_else(|| format!("pip value unknown for symbol {}", self.symbol_id))?;
+
+                if relative_stop_loss.is_none() {
+                    relative_stop_loss = Some(relative_distance(stop, symbol.pip_position.unwrap_or_default()));
+                }
+
+                lots_for_risk_with(amount, stop, pip_value, &symbol, self.below_minimum)?
+            }
+        };
+
+        if lotsize <= 0.0 {
+            return Err("order size must be positive".into());
+        }
+
+        Ok(Order {
+            account_id: self.account_id,
+            symbol_id: self.symbol_id,
+            order_type: self.order_type,
+            trade_side: self.trade_side,
+            lotsize,
+            limit_price: self.limit_price,
+            stop_price: self.stop_price,
+            time_in_force: self.time_in_force,
+            expiration_timestamp: self.expiration_timestamp,
+            comment: self.comment,
+            slippage_in_points: self.slippage_in_points,
+            label: self.label,
+            client_order_id: self.client_order_id,
+            stop_loss: self.stop_loss,
+            take_profit: self.take_profit,
+            relative_stop_loss,
+            relative_take_profit: self.relative_take_profit,
+            guaranteed_stop_loss: self.guaranteed_stop_loss,
+            trailing_stop_loss: self.trailing_stop_loss,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // EURUSD as the servers describe it: volumes in cents, 0.01 lot steps
+    fn eurusd() -> SymbolData {
+        SymbolData {
+            symbol_id: 1,
+            max_volume: Some(10_000_000_000),
+            min_volume: Some(100_000),
+            step_volume: Some(100_000),
+            digits: Some(5),
+            pip_position: Some(4),
+            lot_size: Some(10_000_000),
+            leverage_id: None,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn converts_stop_distances() {
+        let cases = [
+            (StopDistance::Pips(20.0), 4, 20.0, 0.0020),
+            (StopDistance::Price(0.0020), 4, 20.0, 0.0020),
+            (StopDistance::Pips(15.0), 2, 15.0, 0.15),
+            (StopDistance::Price(1.5), 2, 150.0, 1.5),
+        ];
+        for (stop, pip_position, pips, price) in cases {
+            assert_close(stop.in_pips(pip_position), pips);
+            assert_close(stop.in_price(pip_position), price);
+        }
+    }
+
+    #[test]
+    fn relative_stop_loss_is_in_100000ths_of_a_price_unit() {
+        assert_eq!(relative_distance(StopDistance::Pips(20.0), 4), 200);
+        assert_eq!(relative_distance(StopDistance::Price(0.0020), 4), 200);
+        assert_eq!(relative_distance(StopDistance::Pips(15.0), 2), 15_000);
+    }
+
+    #[test]
+    fn sizes_by_risk() {
+        // 100 risked over 20 pips at 10 per pip and lot
+        assert_close(lots_for_risk(100.0, StopDistance::Pips(20.0), 10.0, &eurusd()).unwrap(), 0.5);
+        assert_close(risk_amount(10_000.0, 1.0), 100.0);
+        assert!(lots_for_risk(100.0, StopDistance::Pips(0.0), 10.0, &eurusd()).is_err());
+        assert!(lots_for_risk(100.0, StopDistance::Pips(20.0), 0.0, &eurusd()).is_err());
+    }
+
+    #[test]
+    fn rounds_down_to_the_step() {
+        let cases = [
+            (0.3, 0.3),
+            (0.456, 0.45),
+            (1.0 / 3.0, 0.33),
+            (0.019999, 0.01),
+            // 0.1 + 0.2 is 0.30000000000000004 and 0.7 * 3 is 2.0999999999999996
+            (0.1 + 0.2, 0.3),
+            (0.7 * 3.0, 2.1),
+        ];
+        for (lots, expected) in cases {
+            assert_close(normalize_lots(lots, &eurusd()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn caps_at_the_maximum_volume() {
+        assert_close(normalize_lots(5_000.0, &eurusd()).unwrap(), 1_000.0);
+    }
+
+    #[test]
+    fn clamps_up_to_the_minimum_volume() {
+        // rounds down to 0 lots, then up to the minimum of 0.01
+        assert_close(normalize_lots(0.009, &eurusd()).unwrap(), 0.01);
+        assert_close(lots_for_risk(1.0, StopDistance::Pips(20.0), 10.0, &eurusd()).unwrap(), 0.01);
+
+        let coarse = SymbolData {
+            min_volume: Some(1_000_000),
+            ..eurusd()
+        };
+        assert_close(normalize_lots(0.05, &coarse).unwrap(), 0.1);
+        assert_close(normalize_lots(0.12, &coarse).unwrap(), 0.12);
+
+        // but no size is not the minimum
+        assert!(normalize_lots(0.0, &eurusd()).is_err());
+        assert!(normalize_lots(-1.0, &eurusd()).is_err());
+        assert!(normalize_lots(f64::NAN, &eurusd()).is_err());
+    }
+
+    #[test]
+    fn refuses_sizes_below_the_minimum_volume_when_asked() {
+        // the minimum of 0.01 would risk more than asked for
+        assert!(normalize_lots_with(0.009, &eurusd(), BelowMinimum::Refuse).is_err());
+        let sized = lots_for_risk_with(1.0, StopDistance::Pips(20.0), 10.0, &eurusd(), BelowMinimum::Refuse);
+        assert!(sized.is_err());
+
+        let coarse = SymbolData {
+            min_volume: Some(1_000_000),
+            ..eurusd()
+        };
+        assert!(normalize_lots_with(0.05, &coarse, BelowMinimum::Refuse).is_err());
+        assert_close(normalize_lots_with(0.1, &coarse, BelowMinimum::Refuse).unwrap(), 0.1);
+    }
+
+    #[test]
+    fn needs_the_lot_size() {
+        let symbol = SymbolData {
+            lot_size: None,
+            ..eurusd()
+        };
+        assert!(normalize_lots(1.0, &symbol).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 // this is the moving average strategy implementation using EMAs
 
 use std::{collections::VecDeque, sync::Arc};
-use crate::{CtraderClient, Order, types::OrderType, types::{Signal, Position, TradeSide}};
+use crate::{CtraderClient, Order, sizing::OrderSize, types::{Signal, Position, TradeSide}};
 
 //#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 
@@ -64,16 +64,15 @@ pub fn get_signal(fast_ema: Option<f64>, slow_ema: Option<f64>, slow_prev_ema: &
 
 
 
-pub async fn take_a_trade(client: &mut Arc<CtraderClient>, account_id: i64, signal: Signal, in_position: &mut bool, prev_signal: &mut Signal, positions: &mut Vec<Position>) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn take_a_trade(client: &mut Arc<CtraderClient>, account_id: i64, size: OrderSize, signal: Signal, in_position: &mut bool, prev_signal: &mut Signal, positions: &mut Vec<Position>) -> Result<(), Box<dyn std::error::Error>> {
     let symbol_id = 41;
-    let lot_size = 0.05;
     let comment = String::from("Executed by the crossover_bot.");
 
     let trade_side = match signal {
         Signal::Buy => TradeSide::Buy,
         Signal::Sell => TradeSide::Sell,
         Signal::Hold => {
-            println!("Signal is Hold; no trade action taken.");
+            tracing::debug!("signal is hold, no trade action taken");
             return Ok(());
         }
     };
@@ -87,42 +86,28 @@ pub async fn take_a_trade(client: &mut Arc<CtraderClient>, account_id: i64, sign
     );
 
     if *in_position && signal_changed {
-        println!("Signal changed from {:?} to {:?}; closing existing position(s) first.", prev_signal, signal);
+        tracing::info!(from = ?prev_signal, to = ?signal, "signal changed, closing the open positions first");
         for position in positions.clone().iter() {
             client.close_position(position.account_id.unwrap(), position.id, position.volume).await?;
         }
         *in_position = false;
         *positions = Vec::new();
-        println!("Existing position(s) closed due to signal change.");
+        tracing::info!("positions closed after the signal change");
     }
 
     if !*in_position {
-        let order = Order {
-            account_id: account_id as u64,
-            symbol_id,
-            order_type: OrderType::Market,
-            trade_side: trade_side.clone(),
-            lotsize: lot_size,
-            limit_price: None,
-            stop_price: None,
-            time_in_force: None,
-            expiration_timestamp: None,
-            comment: Some(comment),
-            slippage_in_points: None,
-            label: None,
-            client_order_id: None,
-            relative_stop_loss: None,
-            relative_take_profit: None,
-            guaranteed_stop_loss: None,
-            trailing_stop_loss: None,
-        };
-
-        println!("Placing order: {:?} {:?} lots for symbol {}", trade_side, lot_size, symbol_id);
+        let order = Order::builder(account_id as u64, symbol_id, trade_side)
+            .size(size)
+            .comment(&comment)
+            .build(client)
+            .await?;
+
+        tracing::info!(?trade_side, lots = order.lotsize, symbol_id, "placing order");
         client.new_order(order).await?;
         *in_position = true;
         *prev_signal = signal;
     } else {
-        println!("Already in position and signal is unchanged: {:?}.", signal);
+        tracing::debug!(?signal, "already in position and the signal is unchanged");
     }
 
     Ok(())
@@ -0,0 +1,192 @@
+// this is the token persistence.  a `TokenStore` keeps the last tokens issued
+// for the application so a long running bot can restart without pasting new
+// tokens: `AuthClient::fresh_tokens` refreshes them over https when they are
+// about to expire and `CtraderClient::attach_token_store` keeps the store
+// updated with every in-protocol refresh.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::types::Tokens;
+
+pub trait TokenStore: Send + Sync {
+    /// The stored tokens, `None` if nothing was saved yet.
+    fn load(&self) -> Result<Option<Tokens>, Box<dyn std::error::Error>>;
+
+    fn save(&self, tokens: &Tokens) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Tokens kept as JSON in a file readable only by its owner (0600 on unix).
+#[derive(Debug, Clone)]
+pub struct FileTokenStore {
+    path: PathBuf,
+}
+
+impl FileTokenStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> Result<Option<Tokens>, Box<dyn std::error::Error>> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, tokens: &Tokens) -> Result<(), Box<dyn std::error::Error>> {
+        let content = serde_json::to_string_pretty(tokens)?;
+
+        // write next to the target and rename, so a crash never leaves half a file
+        let tmp_path = self.path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        {
+            use std::io::Write;
+            let mut file = options.open(&tmp_path)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+        }
+        #[cfg(unix)]
+        {
+            // `mode` only applies when the file is created
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Tokens kept in memory only, e.g. when the application persists them itself.
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    tokens: Mutex<Option<Tokens>>,
+}
+
+impl MemoryTokenStore {
+    pub fn new(tokens: Option<Tokens>) -> Self {
+        Self {
+            tokens: Mutex::new(tokens),
+        }
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn load(&self) -> Result<Option<Tokens>, Box<dyn std::error::Error>> {
+        Ok(self.tokens.lock().map_err(|e| e.to_string())?.clone())
+    }
+
+    fn save(&self, tokens: &Tokens) -> Result<(), Box<dyn std::error::Error>> {
+        *self.tokens.lock().map_err(|e| e.to_string())? = Some(tokens.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_ctrader_{}_{}.json", name, std::process::id()))
+    }
+
+    fn tokens() -> Tokens {
+        Tokens {
+            access_token: "access".to_string(),
+            refresh_token: "refresh".to_string(),
+            expires_in: 2_628_000,
+            issued_at: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn file_store_reads_back_what_was_saved() {
+        let store = FileTokenStore::new(temp_path("token_round_trip"));
+        store.save(&tokens()).unwrap();
+        // saving again replaces the file
+        let mut refreshed = tokens();
+        refreshed.access_token = "new access".to_string();
+        store.save(&refreshed).unwrap();
+
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.access_token, "new access");
+        assert_eq!(loaded.refresh_token, "refresh");
+        assert_eq!(loaded.expires_in, 2_628_000);
+        assert_eq!(loaded.issued_at, 1_700_000_000);
+        assert!(!store.path().with_extension("tmp").exists());
+        let _ = fs::remove_file(store.path());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_store_is_readable_by_its_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let store = FileTokenStore::new(temp_path("token_mode"));
+        // an existing file keeps its mode when opened, so start from a wide one
+        fs::write(store.path().with_extension("tmp"), "").unwrap();
+        fs::set_permissions(store.path().with_extension("tmp"), fs::Permissions::from_mode(0o644)).unwrap();
+        store.save(&tokens()).unwrap();
+
+        let mode = fs::metadata(store.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _ = fs::remove_file(store.path());
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_store() {
+        let store = FileTokenStore::new(temp_path("token_missing"));
+        let _ = fs::remove_file(store.path());
+        assert!(store.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn memory_store_reads_back_what_was_saved() {
+        let store = MemoryTokenStore::default();
+        assert!(store.load().unwrap().is_none());
+        store.save(&tokens()).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.access_token, "access");
+        assert_eq!(loaded.refresh_token, "refresh");
+    }
+
+    #[test]
+    fn expiry_follows_the_issue_time() {
+        let tokens = tokens();
+        assert_eq!(tokens.expires_at(), 1_700_000_000 + 2_628_000);
+        // issued in 2023, long expired
+        assert!(tokens.is_expired());
+        assert!(tokens.remaining_secs() < 0);
+
+        let fresh = Tokens::new("access", "refresh", 3600);
+        assert!(!fresh.is_expired());
+        assert!((3599..=3600).contains(&fresh.remaining_secs()));
+        assert!(!fresh.expires_within(60));
+        assert!(fresh.expires_within(3600));
+    }
+
+    #[test]
+    fn issue_time_defaults_to_now_when_missing() {
+        // the token endpoint answers without `issued_at`
+        let tokens: Tokens =
+            serde_json::from_str(r#"{"accessToken":"a","refreshToken":"r","expiresIn":3600}"#).unwrap();
+        assert!((3599..=3600).contains(&tokens.remaining_secs()));
+    }
+}
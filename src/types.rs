@@ -1,32 +1,191 @@
 use crate::utilities::{handle_option_value, handle_timestamp};
 use round::round;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::open_api::ProtoOaTrendbarPeriod;
+use crate::conversion::ConversionChain;
+use crate::leverage::DynamicLeverage;
+use crate::margin_guard::MarginGuardAlert;
+use crate::version::ServerVersion;
+use crate::open_api::{ProtoOaNotificationType, ProtoOaTrendbarPeriod};
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Tokens {
+    #[serde(alias = "accessToken")]
     pub access_token: String,
+    #[serde(alias = "refreshToken")]
     pub refresh_token: String,
+    #[serde(alias = "expiresIn")]
     pub expires_in: i64,
+    /// unix time in seconds the tokens were issued at.  the token endpoint
+    /// does not send it, so it defaults to the time of deserialization.
+    #[serde(default = "unix_now")]
+    pub issued_at: i64,
+}
+
+impl std::fmt::Debug for Tokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tokens")
+            .field("access_token", &crate::utilities::REDACTED)
+            .field("refresh_token", &crate::utilities::REDACTED)
+            .field("expires_in", &self.expires_in)
+            .field("issued_at", &self.issued_at)
+            .finish()
+    }
+}
+
+fn unix_now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+impl Tokens {
+    pub fn new(access_token: &str, refresh_token: &str, expires_in: i64) -> Self {
+        Self {
+            access_token: access_token.to_string(),
+            refresh_token: refresh_token.to_string(),
+            expires_in,
+            issued_at: unix_now(),
+        }
+    }
+
+    /// unix time in seconds at which the access token expires
+    pub fn expires_at(&self) -> i64 {
+        self.issued_at + self.expires_in
+    }
+
+    /// seconds left before the access token expires, negative once expired
+    pub fn remaining_secs(&self) -> i64 {
+        self.expires_at() - unix_now()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining_secs() <= 0
+    }
+
+    pub fn expires_within(&self, secs: i64) -> bool {
+        self.remaining_secs() <= secs
+    }
+}
+
+/// Authorization state of a trading account session on the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountAuthState {
+    Authorizing,
+    Authorized,
+    LoggingOut,
+    Unauthorized,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 pub enum Scope {
     Trading,
     Accounts,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Account {
     pub id: u64,
+    /// short name of the broker, e.g. "IC Markets"
     pub broker: String,
     pub is_live: bool,
     pub scope: Scope,
+    /// the login shown in the cTrader apps
+    pub trader_login: Option<i64>,
+    /// unix time in milliseconds of the last closed deal
+    pub last_closing_deal_timestamp: Option<i64>,
+    /// unix time in milliseconds of the last balance change
+    pub last_balance_update_timestamp: Option<i64>,
 }
+
+/// Selects accounts out of an `AccountsData` list, every criterion that is
+/// set must match, e.g. `AccountFilter::new().live_only().trading_only()`.
+#[derive(Debug, Clone, Default)]
+pub struct AccountFilter {
+    pub is_live: Option<bool>,
+    pub scope: Option<Scope>,
+    pub broker: Option<String>,
+    /// only accounts that closed a deal after this unix time in milliseconds
+    pub active_since: Option<i64>,
+}
+
+impl AccountFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn live_only(mut self) -> Self {
+        self.is_live = Some(true);
+        self
+    }
+
+    pub fn demo_only(mut self) -> Self {
+        self.is_live = Some(false);
+        self
+    }
+
+    pub fn trading_only(mut self) -> Self {
+        self.scope = Some(Scope::Trading);
+        self
+    }
+
+    pub fn broker(mut self, broker: &str) -> Self {
+        self.broker = Some(broker.to_string());
+        self
+    }
+
+    pub fn active_since(mut self, timestamp: i64) -> Self {
+        self.active_since = Some(timestamp);
+        self
+    }
+
+    pub fn matches(&self, account: &Account) -> bool {
+        self.is_live.is_none_or(|is_live| account.is_live == is_live)
+            && self.scope.is_none_or(|scope| account.scope == scope)
+            && self
+                .broker
+                .as_ref()
+                .is_none_or(|broker| account.broker.eq_ignore_ascii_case(broker))
+            && self.active_since.is_none_or(|since| {
+                account
+                    .last_closing_deal_timestamp
+                    .is_some_and(|timestamp| timestamp >= since)
+            })
+    }
+
+    pub fn apply(&self, accounts: &[Account]) -> Vec<Account> {
+        accounts.iter().filter(|account| self.matches(account)).cloned().collect()
+    }
+}
+
+/// The cTrader ID (ctid) user the access token was issued for.
+#[derive(Debug, Clone, Copy)]
+pub struct CtidProfile {
+    pub user_id: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Endpoint {
     Demo,
     Live,
+    /// any other server speaking the Open API protocol, e.g. a local mock
+    Custom { host: String, port: u16 },
+}
+
+impl Endpoint {
+    pub fn host(&self) -> &str {
+        match self {
+            Endpoint::Demo => "demo.ctraderapi.com",
+            Endpoint::Live => "live.ctraderapi.com",
+            Endpoint::Custom { host, .. } => host,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Endpoint::Demo | Endpoint::Live => 5035,
+            Endpoint::Custom { port, .. } => *port,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -35,6 +194,7 @@ pub enum StreamEvent {
     AccountAuthorized(String),
     SymbolsData(Vec<Symbol>),
     AccountsData(Vec<Account>),
+    CtidProfileData(CtidProfile),
     TrendbarsData(Vec<BarData>),
     QuotesData(Vec<Quote>),
     LiveData((Option<Quote>, Option<BarData>, Option<BarData>)),
@@ -45,7 +205,48 @@ pub enum StreamEvent {
     SymbolData(Vec<Symbol>),
     SubscribeSpotsData(String),
     SubscribeLiveBarsData(String),
+    UnsubscribeSpotsData(String),
+    UnsubscribeLiveBarsData(String),
     ExecutionEvent(Position),
+    /// Server-side unrealized PnL for every open position of an account.
+    PositionUnrealizedPnlData(Vec<PositionUnrealizedPnl>),
+    /// Buy/sell margin estimates returned by `CtraderClient::expected_margin`.
+    ExpectedMarginData(Vec<ExpectedMargin>),
+    AssetsData(Vec<Asset>),
+    TraderData(Trader),
+    /// Emitted once a conversion chain is cached and its spot feeds have been
+    /// requested, see `CtraderClient::watch_symbol_conversion`.
+    ConversionChainData(ConversionChain),
+    MarginCallsData(Vec<MarginCall>),
+    /// A margin call threshold was changed (by this client or elsewhere).
+    MarginCallUpdated(MarginCall),
+    /// The broker reports that the margin level reached a margin call threshold.
+    MarginCallTriggered(MarginCall),
+    /// The local margin guard detected a margin level under its threshold.
+    MarginGuardTriggered(MarginGuardAlert),
+    DynamicLeverageData(DynamicLeverage),
+    /// The server accepted `logout_account`; the session ends with the
+    /// following `AccountDisconnected`.
+    AccountLoggedOut(i64),
+    /// The session of an account was dropped by the server.
+    AccountDisconnected(i64),
+    /// The token of these accounts is no longer valid (expired, revoked or
+    /// refreshed elsewhere), with the reason given by the server.
+    AccountsTokenInvalidated((Vec<i64>, Option<String>)),
+    /// The server cancelled the whole connection; every account session is gone.
+    ClientDisconnected(Option<String>),
+    /// The connection dropped and reconnect attempt n is starting.
+    Reconnecting(u32),
+    /// A new connection is open and the application and the previously
+    /// authorized accounts are being authorized again.  Spot and live bar
+    /// subscriptions have to be renewed.
+    Reconnected,
+    /// The version the server runs, compared with the compiled messages.
+    ServerVersionData(ServerVersion),
+    /// The client refreshed its tokens; persist the new refresh token.
+    AccessTokenRefreshed(Tokens),
+    /// `shutdown` closed the connection, no events follow.
+    Shutdown,
     Error(String),
 }
 
@@ -53,6 +254,30 @@ pub enum StreamEvent {
 pub struct Symbol {
     pub symbol_name: String,
     pub symbol_id: u64,
+    pub base_asset_id: Option<i64>,
+    pub quote_asset_id: Option<i64>,
+}
+
+/// An asset (currency, metal, index unit...) as returned by the asset list
+/// request.
+#[derive(Debug, Clone)]
+pub struct Asset {
+    pub asset_id: i64,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub digits: Option<i32>,
+}
+
+/// The parts of the trader account entity needed for money calculations.
+/// `balance` is already scaled by `money_digits`.
+#[derive(Debug, Clone)]
+pub struct Trader {
+    pub account_id: i64,
+    pub balance: f64,
+    pub deposit_asset_id: i64,
+    /// e.g. 1:50 leverage is 50.0
+    pub leverage: Option<f64>,
+    pub money_digits: u32,
 }
 
 /// Information about a symbol returned by the symbol‑by‑id request.  We cache
@@ -68,6 +293,8 @@ pub struct SymbolData {
     pub digits: Option<i32>,
     pub pip_position: Option<i32>,
     pub lot_size: Option<i64>,
+    /// dynamic leverage entity of the symbol, see `CtraderClient::get_dynamic_leverage`
+    pub leverage_id: Option<i64>,
 }
 
 #[derive(Debug)]
@@ -105,14 +332,10 @@ pub struct RelativeBarData {
 
 impl RelativeBarData {
     pub fn change_to_actual_symbol_price(&self) -> BarData {
-        let new_low = round(self.low as f64 / 100_000.0, 4);
-        let new_high = round((self.low as f64 + self.delta_high as f64) / 100_000.0, 4);
-        let new_close = if let Some(close_val) = handle_option_value(self.delta_close) {
-            Some(round((self.low as f64 + close_val as f64) / 100_000.0, 4))
-        } else {
-            None
-        };
-        let new_open = round((self.low as f64 + self.delta_open as f64) / 100_000.0, 4);
+        let new_low = round(self.low / 100_000.0, 4);
+        let new_high = round((self.low + self.delta_high) / 100_000.0, 4);
+        let new_close = handle_option_value(self.delta_close).map(|close_val| round((self.low + close_val) / 100_000.0, 4));
+        let new_open = round((self.low + self.delta_open) / 100_000.0, 4);
 
         BarData {
             open: new_open,
@@ -172,12 +395,12 @@ impl Clone for Quote {
 impl Quote {
     pub fn change_to_actual_quote_price(&self) -> Self {
         let new_bid = if self.bid.is_some() {
-            Some(self.bid.unwrap_or(0.0) as f64 / 100_000.0)
+            Some(self.bid.unwrap_or(0.0) / 100_000.0)
         } else {
             None
         };
         let new_ask = if self.ask.is_some() {
-            Some(self.ask.unwrap_or(0.0) as f64 / 100_000.0)
+            Some(self.ask.unwrap_or(0.0) / 100_000.0)
         } else {
             None
         };
@@ -190,6 +413,7 @@ impl Quote {
     }
 }
 
+#[derive(Debug)]
 pub enum OrderType {
     Market,
     Limit,
@@ -229,6 +453,10 @@ pub struct Order {
     /// this is the number of the points you want to allow for slippage for the limit/market orders
     pub label: Option<String>,
     pub client_order_id: Option<String>,
+    /// absolute stop loss price, for limit and stop orders only
+    pub stop_loss: Option<f64>,
+    /// absolute take profit price, for limit and stop orders only
+    pub take_profit: Option<f64>,
     pub relative_stop_loss: Option<i64>,
     pub relative_take_profit: Option<i64>,
     pub guaranteed_stop_loss: Option<bool>,
@@ -236,7 +464,12 @@ pub struct Order {
     pub trailing_stop_loss: Option<bool>,
 }
 
-impl Order {}
+impl Order {
+    /// Start building a market order; see `OrderBuilder` for sizing by risk.
+    pub fn builder(account_id: u64, symbol_id: u64, trade_side: TradeSide) -> crate::sizing::OrderBuilder {
+        crate::sizing::OrderBuilder::new(account_id, symbol_id, trade_side)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Position {
@@ -247,7 +480,19 @@ pub struct Position {
     pub stop_loss: Option<f64>,
     pub volume: i64,
     pub order_id: Option<i64>,
+    pub symbol_id: Option<i64>,
+    pub trade_side: Option<TradeSide>,
+    /// VWAP entry price of the position
+    pub price: Option<f64>,
+    /// margin used by the position in the deposit currency
+    pub used_margin: Option<f64>,
 }
+impl Default for Position {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Position {
     pub fn new() -> Self {
         Self {
@@ -257,11 +502,73 @@ impl Position {
             take_profit: None,
             stop_loss: None,
             volume: 0,
-            order_id: None
+            order_id: None,
+            symbol_id: None,
+            trade_side: None,
+            price: None,
+            used_margin: None,
         }
     }
 }
 
+/// Unrealized PnL of a single position as calculated by the broker.  The
+/// monetary values are already divided by the response's `money_digits`, so
+/// they are expressed directly in the account deposit currency.
+#[derive(Debug, Clone)]
+pub struct PositionUnrealizedPnl {
+    pub account_id: i64,
+    pub position_id: i64,
+    pub gross_unrealized_pnl: f64,
+    /// does not include the potential closing commission
+    pub net_unrealized_pnl: f64,
+}
+
+/// Margin the broker expects to allocate for `volume` (protocol units) of a
+/// symbol, scaled by `money_digits` into the deposit currency.
+#[derive(Debug, Clone)]
+pub struct ExpectedMargin {
+    pub account_id: i64,
+    pub symbol_id: Option<i64>,
+    pub volume: i64,
+    pub buy_margin: f64,
+    pub sell_margin: f64,
+}
+
+/// The three margin call slots supported by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MarginCallType {
+    Threshold1,
+    Threshold2,
+    Threshold3,
+}
+
+impl MarginCallType {
+    pub fn to_proto(&self) -> ProtoOaNotificationType {
+        match self {
+            MarginCallType::Threshold1 => ProtoOaNotificationType::MarginLevelThreshold1,
+            MarginCallType::Threshold2 => ProtoOaNotificationType::MarginLevelThreshold2,
+            MarginCallType::Threshold3 => ProtoOaNotificationType::MarginLevelThreshold3,
+        }
+    }
+
+    pub fn from_proto(value: i32) -> Option<Self> {
+        match ProtoOaNotificationType::try_from(value).ok()? {
+            ProtoOaNotificationType::MarginLevelThreshold1 => Some(MarginCallType::Threshold1),
+            ProtoOaNotificationType::MarginLevelThreshold2 => Some(MarginCallType::Threshold2),
+            ProtoOaNotificationType::MarginLevelThreshold3 => Some(MarginCallType::Threshold3),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MarginCall {
+    pub account_id: i64,
+    pub margin_call_type: MarginCallType,
+    /// margin level in percent
+    pub margin_level_threshold: f64,
+    pub last_update_timestamp: Option<i64>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Signal {
     Buy,
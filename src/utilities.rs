@@ -1,4 +1,4 @@
-use chrono::{Utc, Datelike, Duration, Local, TimeZone};
+use chrono::{Utc, Local, TimeZone};
 
 use crate::CtraderClient;
 
@@ -21,10 +21,14 @@ pub fn handle_timestamp(timestamp: u64) -> String{
 
 //method for handling the option values
 pub fn handle_option_value<T>(option: Option<T>) -> Option<T> {
-    match option {
-        Some(value) => Some(value),
-        None => None
-    }
+    option
+}
+
+
+//scaling the monetary values sent by the server (balance, pnl, margin...) by
+//their `money_digits` exponent, e.g. 10053099944 with 8 digits is 100.53099944
+pub fn scale_money(value: i64, money_digits: u32) -> f64 {
+    value as f64 / 10_f64.powi(money_digits as i32)
 }
 
 
@@ -43,15 +47,27 @@ pub async fn lots_to_protocol_std_volume(client: &CtraderClient, symbol_id: i64,
         // Get lot_size
         let lot_size = {
             let map = client.symbol_data.lock().await;
-            map.get(&symbol_id_u64).and_then(|sd| sd.lot_size).unwrap_or(10000) as f64
+            map.get(&symbol_id_u64).and_then(|sd| sd.lot_size)
+        };
+        let lot_size = match lot_size {
+            Some(lot_size) => lot_size as f64,
+            None => {
+                // the symbol request above has not been answered yet
+                tracing::warn!(symbol_id, account_id, "no cached lot size, assuming 10000");
+                10000.0
+            }
         };
 
-        // Compute volume in protocol units (0.01 of a unit)
-        let volume = (order_lotsize * lot_size) as i64;
+        // Compute volume in protocol units (0.01 of a unit), rounding so that
+        // step-aligned lot sizes do not lose a cent to float error
+        let volume = (order_lotsize * lot_size).round() as i64;
+        tracing::trace!(symbol_id, lots = order_lotsize, lot_size, volume, "converted lots to volume");
         
         Ok(volume)
 
 
 }
 
-
+// shown instead of tokens, secrets and passwords in Debug output, so that
+// logging a value never leaks them
+pub(crate) const REDACTED: &str = "<redacted>";
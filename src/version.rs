@@ -0,0 +1,78 @@
+// the version check.  the messages this crate is compiled from were taken
+// from one version of the server (`open_api::PROTO_VERSION`).  a newer server
+// still understands them, but an older one may not know some requests or
+// fields, and orders built on them could be rejected or, worse, read
+// differently.  `ProtoOAVersionReq` tells which version the server runs and
+// `VersionPolicy` decides what happens when it is not the compiled one.
+
+use serde::Deserialize;
+
+use crate::open_api::PROTO_VERSION;
+
+/// What the client does with the server version when it connects.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionPolicy {
+    /// no version request is sent
+    #[default]
+    Off,
+    /// the version is requested and an older or unreadable server version is
+    /// reported as an error event
+    Warn,
+    /// like `Warn`, and order related requests fail until the server reported
+    /// a version at least as new as the compiled messages
+    Refuse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionMatch {
+    Same,
+    /// the server is newer, unknown fields are ignored
+    ServerNewer,
+    /// the server may not know some of the compiled messages
+    ServerOlder,
+    /// the version reported by the server could not be read
+    Unknown,
+}
+
+impl VersionMatch {
+    /// Whether trading is safe with this server.
+    pub fn is_compatible(&self) -> bool {
+        matches!(self, VersionMatch::Same | VersionMatch::ServerNewer)
+    }
+}
+
+/// The version reported by the server next to the compiled one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerVersion {
+    pub server: String,
+    pub compiled: &'static str,
+    pub matches: VersionMatch,
+}
+
+impl ServerVersion {
+    pub fn new(server: &str) -> Self {
+        let matches = match (major(server), major(PROTO_VERSION)) {
+            (Some(server), Some(compiled)) if server == compiled => VersionMatch::Same,
+            (Some(server), Some(compiled)) if server > compiled => VersionMatch::ServerNewer,
+            (Some(_), Some(_)) => VersionMatch::ServerOlder,
+            _ => VersionMatch::Unknown,
+        };
+        Self {
+            server: server.to_string(),
+            compiled: PROTO_VERSION,
+            matches,
+        }
+    }
+}
+
+// the leading number of a version, "88" and "88.1.2" are both 88
+fn major(version: &str) -> Option<u64> {
+    let digits: String = version
+        .trim()
+        .trim_start_matches(['v', 'V'])
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
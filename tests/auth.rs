@@ -0,0 +1,192 @@
+// the authorization code flow against a local stand-in for the token
+// endpoint, with the test playing the browser that cTrader redirects back.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use rust_ctrader::auth::{AuthClient, AuthError};
+use rust_ctrader::types::Scope;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+const TOKENS: &str = r#"{"accessToken":"access","refreshToken":"refresh","expiresIn":2628000,"tokenType":"bearer","errorCode":null,"description":null}"#;
+
+/// Answers every POST with `response` and keeps the form bodies it got.
+struct TokenEndpoint {
+    addr: SocketAddr,
+    forms: Arc<Mutex<Vec<String>>>,
+}
+
+impl TokenEndpoint {
+    async fn start(response: &'static str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let forms = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&forms);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let form = answer(socket, response).await;
+                received.lock().unwrap().push(form);
+            }
+        });
+        Self { addr, forms }
+    }
+
+    fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    fn forms(&self) -> Vec<String> {
+        self.forms.lock().unwrap().clone()
+    }
+}
+
+async fn answer(socket: TcpStream, response: &str) -> String {
+    let mut reader = BufReader::new(socket);
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).await.unwrap();
+
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.len(),
+        response
+    );
+    reader.get_mut().write_all(response.as_bytes()).await.unwrap();
+    String::from_utf8(body).unwrap()
+}
+
+// a redirect uri on a port nobody listens on yet
+async fn redirect_uri() -> String {
+    let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+    format!("http://127.0.0.1:{port}/callback")
+}
+
+/// What the browser does after the user granted access: request each of
+/// `targets` on the redirect host, returning the status lines.
+async fn browse(redirect_uri: &str, targets: Vec<String>) -> Vec<String> {
+    let host = redirect_uri.trim_start_matches("http://").split('/').next().unwrap().to_string();
+    let mut statuses = Vec::new();
+    for target in targets {
+        let mut socket = TcpStream::connect(&host).await.unwrap();
+        let request = format!("GET {target} HTTP/1.1\r\nHost: {host}\r\n\r\n");
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        statuses.push(response.lines().next().unwrap_or_default().to_string());
+    }
+    statuses
+}
+
+fn state_of(authorization_url: &str) -> String {
+    reqwest::Url::parse(authorization_url)
+        .unwrap()
+        .query_pairs()
+        .find(|(key, _)| key == "state")
+        .map(|(_, value)| value.into_owned())
+        .unwrap()
+}
+
+/// Run `authorize_interactive_with`, answering the authorization url with
+/// the requests `redirects` makes from its state.
+async fn authorize(
+    client: &AuthClient,
+    redirects: impl FnOnce(&str) -> Vec<String> + Send + 'static,
+) -> (Result<rust_ctrader::types::Tokens, String>, Vec<String>) {
+    let redirect_uri = client.redirect_uri.clone();
+    let mut browser = None;
+    let result = client
+        .authorize_interactive_with(Scope::Trading, |url| {
+            let targets = redirects(&state_of(url));
+            browser = Some(tokio::spawn(async move { browse(&redirect_uri, targets).await }));
+        })
+        .await
+        .map_err(|e| e.to_string());
+    let statuses = browser.unwrap().await.unwrap();
+    (result, statuses)
+}
+
+#[tokio::test]
+async fn exchanges_the_code_for_tokens() {
+    let endpoint = TokenEndpoint::start(TOKENS).await;
+    let client = AuthClient::new("id", "secret", &redirect_uri().await).with_token_base_url(&endpoint.url());
+
+    let (tokens, statuses) = authorize(&client, |state| {
+        vec!["/favicon.ico".to_string(), format!("/callback?code=granted&state={state}")]
+    })
+    .await;
+
+    let tokens = tokens.unwrap();
+    assert_eq!(tokens.access_token, "access");
+    assert_eq!(tokens.refresh_token, "refresh");
+    assert!(statuses[0].contains("404"));
+    assert!(statuses[1].contains("200"));
+
+    let forms = endpoint.forms();
+    assert_eq!(forms.len(), 1);
+    assert!(forms[0].contains("grant_type=authorization_code"));
+    assert!(forms[0].contains("code=granted"));
+    assert!(forms[0].contains("client_secret=secret"));
+}
+
+#[tokio::test]
+async fn ignores_a_redirect_with_another_state() {
+    let endpoint = TokenEndpoint::start(TOKENS).await;
+    let client = AuthClient::new("id", "secret", &redirect_uri().await).with_token_base_url(&endpoint.url());
+
+    let (tokens, statuses) = authorize(&client, |state| {
+        vec![
+            "/callback?code=forged&state=guessed".to_string(),
+            format!("/callback?code=granted&state={state}"),
+        ]
+    })
+    .await;
+
+    assert!(tokens.is_ok());
+    assert!(statuses[0].contains("400"));
+    assert!(statuses[1].contains("200"));
+    // only the code of this flow was exchanged
+    let forms = endpoint.forms();
+    assert_eq!(forms.len(), 1);
+    assert!(forms[0].contains("code=granted"));
+}
+
+#[tokio::test]
+async fn returns_the_token_endpoint_error() {
+    let endpoint = TokenEndpoint::start(r#"{"errorCode":"ACCESS_DENIED","description":"the code expired"}"#).await;
+    let client = AuthClient::new("id", "secret", &redirect_uri().await).with_token_base_url(&endpoint.url());
+
+    let (tokens, _) = authorize(&client, |state| vec![format!("/callback?code=stale&state={state}")]).await;
+
+    assert_eq!(tokens.unwrap_err(), "token endpoint error ACCESS_DENIED: the code expired");
+
+    let error = client.refresh_token("refresh").await.unwrap_err();
+    assert!(matches!(error, AuthError::Api { error_code, .. } if error_code == "ACCESS_DENIED"));
+}
+
+#[tokio::test]
+async fn fails_when_access_is_denied() {
+    let endpoint = TokenEndpoint::start(TOKENS).await;
+    let client = AuthClient::new("id", "secret", &redirect_uri().await).with_token_base_url(&endpoint.url());
+
+    let (tokens, statuses) = authorize(&client, |state| {
+        vec![format!("/callback?error=access_denied&error_description=declined&state={state}")]
+    })
+    .await;
+
+    assert_eq!(tokens.unwrap_err(), "authorization denied: access_denied declined");
+    assert!(statuses[0].contains("400"));
+    assert!(endpoint.forms().is_empty());
+}
@@ -0,0 +1,1072 @@
+// integration tests for `CtraderClient` against the in-process mock server.
+// every test starts its own server on a free port, so they run in parallel.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::Sink;
+use futures_util::future::BoxFuture;
+
+use rust_ctrader::ctrader::transport::{MessageSink, MessageStream};
+use rust_ctrader::mock_server::{self, MockServer, MockSymbol};
+use rust_ctrader::open_api::{
+    ProtoHeartbeatEvent, ProtoMessage, ProtoOaAccountAuthReq, ProtoOaAccountDisconnectEvent, ProtoOaAccountLogoutReq,
+    ProtoOaAccountsTokenInvalidatedEvent, ProtoOaApplicationAuthReq, ProtoOaCancelOrderReq, ProtoOaClosePositionReq,
+    ProtoOaExecutionEvent, ProtoOaExecutionType, ProtoOaLightSymbol, ProtoOaNewOrderReq, ProtoOaPayloadType,
+    ProtoOaPosition, ProtoOaPositionStatus, ProtoOaReconcileReq, ProtoOaRefreshTokenReq, ProtoOaRefreshTokenRes,
+    ProtoOaSubscribeSpotsReq, ProtoOaSymbolsForConversionRes, ProtoOaTradeData, ProtoOaTrader, ProtoOaTraderRes,
+    ProtoOaTrendbarPeriod, ProtoOaUnsubscribeLiveTrendbarReq, ProtoOaUnsubscribeSpotsReq, ProtoPayloadType,
+};
+use rust_ctrader::types::{OrderType, TradeSide};
+use rust_ctrader::{
+    AccountAuthState, ConnectOptions, ConnectionPool, CtraderClient, CtraderClientBuilder, Endpoint, GuardAction,
+    PoolEvent, ProtobufTransport, ReconnectPolicy, ReplayTransport, RootCertStore, ShutdownOptions, StreamEvent,
+    TimeFrame, TlsMode, Transport, VersionMatch, VersionPolicy, WebSocketTransport,
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+const ACCOUNT: i64 = 4_100_001;
+const EURUSD: i64 = 1;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn server() -> MockServer {
+    MockServer::builder()
+        .account(ACCOUNT, false)
+        .account(4_100_002, true)
+        .symbol(MockSymbol::new(EURUSD, "EURUSD"))
+        .symbol(MockSymbol::new(2, "GBPUSD"))
+        .start()
+        .await
+        .unwrap()
+}
+
+fn builder(server: &MockServer) -> CtraderClientBuilder {
+    CtraderClient::builder()
+        .credentials("client id", "client secret", "access token")
+        .endpoint(server.endpoint())
+        .tls(TlsMode::Plain)
+        .heartbeat_interval(None)
+}
+
+async fn start(builder: CtraderClientBuilder) -> (Arc<CtraderClient>, mpsc::Receiver<StreamEvent>) {
+    let (client, events) = builder.build().await.unwrap();
+    client.start().await;
+    (client, events)
+}
+
+// the first event `pick` returns something for, skipping the others
+async fn next<T>(events: &mut mpsc::Receiver<StreamEvent>, mut pick: impl FnMut(StreamEvent) -> Option<T>) -> T {
+    let wait = async {
+        loop {
+            let event = events.recv().await.expect("event channel closed");
+            if let Some(found) = pick(event) {
+                return found;
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, wait).await.expect("timed out waiting for an event")
+}
+
+// like `next`, for tests on paused time where a timeout would fire at once
+async fn next_paused<T>(events: &mut mpsc::Receiver<StreamEvent>, mut pick: impl FnMut(StreamEvent) -> Option<T>) -> T {
+    loop {
+        let event = events.recv().await.expect("event channel closed");
+        if let Some(found) = pick(event) {
+            return found;
+        }
+    }
+}
+
+// symbol data is cached without an event
+async fn symbol_data(client: &CtraderClient, symbol_id: i64) -> rust_ctrader::SymbolData {
+    let wait = async {
+        loop {
+            if let Some(symbol) = client.symbol_data(symbol_id as u64).await {
+                return symbol;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(TIMEOUT, wait).await.expect("timed out waiting for symbol data")
+}
+
+async fn authorized(server: &MockServer) -> (Arc<CtraderClient>, mpsc::Receiver<StreamEvent>) {
+    let (client, mut events) = start(builder(server)).await;
+    client.authorize_application().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+    client.authorize_account(ACCOUNT).await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::AccountAuthorized(_)).then_some(())).await;
+    (client, events)
+}
+
+#[tokio::test]
+async fn authorizes_the_application_and_lists_accounts() {
+    let server = server().await;
+    let (client, mut events) = start(builder(&server)).await;
+
+    client.authorize_application().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+    client.get_accounts().await.unwrap();
+    let accounts = next(&mut events, |event| match event {
+        StreamEvent::AccountsData(accounts) => Some(accounts),
+        _ => None,
+    })
+    .await;
+
+    assert_eq!(accounts.len(), 2);
+    assert_eq!(accounts[0].id, ACCOUNT as u64);
+    assert!(!accounts[0].is_live);
+    assert!(accounts[1].is_live);
+    assert_eq!(accounts[0].broker, "Mock");
+
+    let requests = server.requests_of::<ProtoOaApplicationAuthReq>(ProtoOaPayloadType::ProtoOaApplicationAuthReq as u32);
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].client_id, "client id");
+    assert_eq!(requests[0].client_secret, "client secret");
+}
+
+#[tokio::test]
+async fn connect_sends_no_heartbeats_and_does_not_rate_limit() {
+    let server = server().await;
+    let options = ConnectOptions {
+        tls: TlsMode::Plain,
+        ..Default::default()
+    };
+    let (client, _events) =
+        CtraderClient::connect_with("client id", "client secret", "access token", server.endpoint(), options)
+            .await
+            .unwrap();
+
+    let tasks = client.start().await;
+    assert!(tasks.heartbeat.is_none());
+    assert!(client.rate_limit_stats().await.is_none());
+}
+
+#[tokio::test]
+async fn rejects_wrong_credentials() {
+    let server = MockServer::builder()
+        .credentials("client id", "another secret")
+        .start()
+        .await
+        .unwrap();
+    let (client, mut events) = start(builder(&server)).await;
+
+    client.authorize_application().await.unwrap();
+    let error = next(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        StreamEvent::ApplicationAuthorized(_) => panic!("authorized with the wrong secret"),
+        _ => None,
+    })
+    .await;
+    assert_eq!(error, "Invalid client id or secret");
+}
+
+#[tokio::test]
+async fn authorizes_accounts() {
+    let server = server().await;
+    let (client, mut events) = authorized(&server).await;
+    assert_eq!(client.account_state(ACCOUNT).await, Some(AccountAuthState::Authorized));
+
+    let requests = server.requests_of::<ProtoOaAccountAuthReq>(ProtoOaPayloadType::ProtoOaAccountAuthReq as u32);
+    assert_eq!(requests[0].ctid_trader_account_id, ACCOUNT);
+    assert_eq!(requests[0].access_token, "access token");
+
+    client.authorize_account(999).await.unwrap();
+    let error = next(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(error, "Trading account is not found");
+}
+
+#[tokio::test]
+async fn lists_symbols_and_caches_symbol_data() {
+    let server = server().await;
+    let (client, mut events) = authorized(&server).await;
+
+    client.get_symbols(ACCOUNT, false).await.unwrap();
+    let symbols = next(&mut events, |event| match event {
+        StreamEvent::SymbolsData(symbols) => Some(symbols),
+        _ => None,
+    })
+    .await;
+    let names: Vec<&str> = symbols.iter().map(|symbol| symbol.symbol_name.as_str()).collect();
+    assert_eq!(names, ["EURUSD", "GBPUSD"]);
+
+    client.get_symbol_by_id(ACCOUNT, EURUSD, None).await.unwrap();
+    let symbol = symbol_data(&client, EURUSD).await;
+    assert_eq!(symbol.lot_size, Some(10_000_000));
+    assert_eq!(symbol.digits, Some(5));
+}
+
+#[tokio::test]
+async fn converts_trend_bars_to_prices() {
+    let server = MockServer::builder()
+        .account(ACCOUNT, false)
+        .symbol(MockSymbol::new(EURUSD, "EURUSD"))
+        .trendbars(
+            EURUSD,
+            ProtoOaTrendbarPeriod::H1,
+            vec![
+                mock_server::trendbar(28_000_000, 108_050, 108_230, 108_000, 108_120, 1_500),
+                mock_server::trendbar(28_000_060, 108_120, 108_150, 107_910, 107_990, 2_000),
+                mock_server::trendbar(28_000_120, 107_990, 108_010, 107_800, 107_850, 900),
+            ],
+        )
+        .start()
+        .await
+        .unwrap();
+    let (client, mut events) = authorized(&server).await;
+
+    // the last bar is outside the requested range
+    let from = 28_000_000 * 60_000;
+    client
+        .get_trend_bar_data(EURUSD, TimeFrame::H1, ACCOUNT, from, from + 60 * 60_000)
+        .await
+        .unwrap();
+    let bars = next(&mut events, |event| match event {
+        StreamEvent::TrendbarsData(bars) => Some(bars),
+        _ => None,
+    })
+    .await;
+
+    assert_eq!(bars.len(), 2);
+    assert_eq!(bars[0].open, 1.0805);
+    assert_eq!(bars[0].high, 1.0823);
+    assert_eq!(bars[0].low, 1.08);
+    assert_eq!(bars[0].close, Some(1.0812));
+    assert_eq!(bars[0].volume, 1_500);
+    assert_eq!(bars[1].low, 1.0791);
+}
+
+#[tokio::test]
+async fn streams_spot_prices() {
+    let server = server().await;
+    let (client, mut events) = authorized(&server).await;
+
+    client.subscribe_spot(ACCOUNT, EURUSD).await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::SubscribeSpotsData(_)).then_some(())).await;
+    let requests = server.requests_of::<ProtoOaSubscribeSpotsReq>(ProtoOaPayloadType::ProtoOaSubscribeSpotsReq as u32);
+    assert_eq!(requests[0].symbol_id, [EURUSD]);
+
+    server.push_spot(ACCOUNT, EURUSD, 108_000, 108_020);
+    let quote = next(&mut events, |event| match event {
+        StreamEvent::LiveData((Some(quote), None, None)) => Some(quote),
+        _ => None,
+    })
+    .await;
+    assert_eq!(quote.symbol_id, EURUSD);
+    assert_eq!(quote.bid, Some(1.08));
+    assert_eq!(quote.ask, Some(1.0802));
+}
+
+#[tokio::test]
+async fn streams_spots_over_a_websocket() {
+    let server = MockServer::builder()
+        .account(ACCOUNT, false)
+        .symbol(MockSymbol::new(EURUSD, "EURUSD"))
+        .websocket()
+        .start()
+        .await
+        .unwrap();
+    let (client, mut events) = start(builder(&server).transport(WebSocketTransport)).await;
+    client.authorize_application().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+    client.authorize_account(ACCOUNT).await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::AccountAuthorized(_)).then_some(())).await;
+
+    client.subscribe_spot(ACCOUNT, EURUSD).await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::SubscribeSpotsData(_)).then_some(())).await;
+    server.push_spot(ACCOUNT, EURUSD, 108_000, 108_020);
+    let quote = next(&mut events, |event| match event {
+        StreamEvent::LiveData((Some(quote), None, None)) => Some(quote),
+        _ => None,
+    })
+    .await;
+    assert_eq!(quote.symbol_id, EURUSD);
+    assert_eq!(quote.bid, Some(1.08));
+    assert_eq!(quote.ask, Some(1.0802));
+
+    let requests = server.requests_of::<ProtoOaApplicationAuthReq>(ProtoOaPayloadType::ProtoOaApplicationAuthReq as u32);
+    assert_eq!(requests[0].client_id, "client id");
+}
+
+#[tokio::test]
+async fn sends_absolute_levels_on_pending_orders_only() {
+    let server = server().await;
+    let (client, _events) = authorized(&server).await;
+    let account = client.account(ACCOUNT);
+    account.get_symbol_by_id(EURUSD).await.unwrap();
+    symbol_data(&client, EURUSD).await;
+
+    let limit = account
+        .order(EURUSD as u64, TradeSide::Buy)
+        .order_type(OrderType::Limit)
+        .limit_price(1.05)
+        .stop_loss(1.04)
+        .take_profit(1.07)
+        .lots(0.1)
+        .build(&client)
+        .await
+        .unwrap();
+    account.new_order(limit).await.unwrap();
+    let requests = server
+        .wait_for::<ProtoOaNewOrderReq>(ProtoOaPayloadType::ProtoOaNewOrderReq as u32, 1, TIMEOUT)
+        .await
+        .expect("the order was not sent");
+    assert_eq!(requests[0].stop_loss, Some(1.04));
+    assert_eq!(requests[0].take_profit, Some(1.07));
+
+    // a market order would have them ignored
+    let market = account
+        .order(EURUSD as u64, TradeSide::Buy)
+        .stop_loss(1.04)
+        .lots(0.1)
+        .build(&client)
+        .await
+        .unwrap();
+    assert!(account.new_order(market).await.is_err());
+    assert_eq!(server.requests_of::<ProtoOaNewOrderReq>(ProtoOaPayloadType::ProtoOaNewOrderReq as u32).len(), 1);
+}
+
+#[tokio::test]
+async fn fills_and_closes_market_orders() {
+    let server = server().await;
+    let (client, mut events) = authorized(&server).await;
+    let account = client.account(ACCOUNT);
+
+    // the volume is computed from the cached lot size
+    account.get_symbol_by_id(EURUSD).await.unwrap();
+    symbol_data(&client, EURUSD).await;
+    server.push_spot(ACCOUNT, EURUSD, 108_000, 108_020);
+
+    let order = account
+        .order(EURUSD as u64, TradeSide::Buy)
+        .lots(0.25)
+        .label("mock")
+        .build(&client)
+        .await
+        .unwrap();
+    account.new_order(order).await.unwrap();
+    let position = next(&mut events, |event| match event {
+        StreamEvent::ExecutionEvent(position) => Some(position),
+        _ => None,
+    })
+    .await;
+
+    let requests = server.requests_of::<ProtoOaNewOrderReq>(ProtoOaPayloadType::ProtoOaNewOrderReq as u32);
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].volume, 2_500_000);
+    assert_eq!(requests[0].label.as_deref(), Some("mock"));
+    assert_eq!(position.account_id, Some(ACCOUNT));
+    assert_eq!(position.symbol_id, Some(EURUSD));
+    assert_eq!(position.volume, 2_500_000);
+    assert_eq!(position.price, Some(1.0802));
+    assert!(matches!(position.trade_side, Some(TradeSide::Buy)));
+
+    account.close_position(position.id, position.volume).await.unwrap();
+    let closed = server
+        .wait_for::<ProtoOaClosePositionReq>(ProtoOaPayloadType::ProtoOaClosePositionReq as u32, 1, TIMEOUT)
+        .await
+        .unwrap();
+    assert_eq!(closed[0].position_id, position.id);
+
+    // the position is gone now
+    account.close_position(position.id, position.volume).await.unwrap();
+    let error = next(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(error, "Order error: Position is not found");
+}
+
+#[tokio::test]
+async fn checks_the_server_version() {
+    let server = server().await;
+    let (client, mut events) = start(builder(&server).version_check(VersionPolicy::Warn)).await;
+
+    let version = next(&mut events, |event| match event {
+        StreamEvent::ServerVersionData(version) => Some(version),
+        _ => None,
+    })
+    .await;
+    assert_eq!(version.server, rust_ctrader::open_api::PROTO_VERSION);
+    assert_eq!(version.matches, VersionMatch::Same);
+    assert_eq!(client.server_version().await, Some(version));
+}
+
+#[tokio::test]
+async fn refuses_trading_on_an_older_server() {
+    let server = MockServer::builder()
+        .account(ACCOUNT, false)
+        .symbol(MockSymbol::new(EURUSD, "EURUSD"))
+        .version("1")
+        .start()
+        .await
+        .unwrap();
+    let (client, mut events) = start(builder(&server).version_check(VersionPolicy::Refuse)).await;
+    let version = next(&mut events, |event| match event {
+        StreamEvent::ServerVersionData(version) => Some(version),
+        _ => None,
+    })
+    .await;
+    assert_eq!(version.matches, VersionMatch::ServerOlder);
+
+    let result = client.close_position(ACCOUNT, 1, 100_000).await;
+    assert!(result.unwrap_err().to_string().starts_with("trading refused"));
+    assert!(server.requests_of::<ProtoOaClosePositionReq>(ProtoOaPayloadType::ProtoOaClosePositionReq as u32).is_empty());
+
+    // everything else still goes out
+    client.get_symbols(ACCOUNT, false).await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::SymbolsData(_)).then_some(())).await;
+}
+
+#[tokio::test]
+async fn refuses_trading_until_the_version_is_known() {
+    let server = MockServer::builder()
+        .account(ACCOUNT, false)
+        .symbol(MockSymbol::new(EURUSD, "EURUSD"))
+        .version("200")
+        .start()
+        .await
+        .unwrap();
+    // not started yet, so the version was not asked for
+    let (client, mut events) = builder(&server).version_check(VersionPolicy::Refuse).build().await.unwrap();
+
+    let result = client.close_position(ACCOUNT, 1, 100_000).await;
+    assert!(result.unwrap_err().to_string().starts_with("trading refused"));
+
+    client.start().await;
+    let version = next(&mut events, |event| match event {
+        StreamEvent::ServerVersionData(version) => Some(version),
+        _ => None,
+    })
+    .await;
+    assert_eq!(version.server, "200");
+    assert_eq!(version.matches, VersionMatch::ServerNewer);
+
+    // the request reaches the server, which knows no such position
+    client.close_position(ACCOUNT, 1, 100_000).await.unwrap();
+    let error = next(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(error, "Order error: Position is not found");
+}
+
+#[tokio::test]
+async fn answers_with_scripted_responses() {
+    let server = server().await;
+    let (client, mut events) = start(builder(&server)).await;
+
+    server.script(
+        ProtoOaPayloadType::ProtoOaApplicationAuthReq as u32,
+        vec![mock_server::error_response(None, "SERVER_IS_UNDER_MAINTENANCE", "Server is under maintenance")],
+    );
+    client.authorize_application().await.unwrap();
+    let error = next(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(error, "Server is under maintenance");
+
+    // scripts are used once, then the simulated answer is back
+    client.authorize_application().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+}
+
+#[tokio::test]
+async fn reconnects_and_authorizes_again() {
+    let server = server().await;
+    let policy = ReconnectPolicy {
+        max_attempts: 3,
+        initial_delay_ms: 10,
+        max_delay_ms: 100,
+    };
+    let (client, mut events) = start(builder(&server).reconnect(Some(policy))).await;
+    client.authorize_application().await.unwrap();
+    client.authorize_account(ACCOUNT).await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::AccountAuthorized(_)).then_some(())).await;
+    server
+        .wait_for::<ProtoOaReconcileReq>(ProtoOaPayloadType::ProtoOaReconcileReq as u32, 1, TIMEOUT)
+        .await
+        .unwrap();
+
+    server.disconnect_all();
+    let attempt = next(&mut events, |event| match event {
+        StreamEvent::Reconnecting(attempt) => Some(attempt),
+        _ => None,
+    })
+    .await;
+    assert_eq!(attempt, 1);
+    next(&mut events, |event| matches!(event, StreamEvent::Reconnected).then_some(())).await;
+    next(&mut events, |event| matches!(event, StreamEvent::AccountAuthorized(_)).then_some(())).await;
+
+    assert_eq!(server.connections_accepted(), 2);
+    let requests = server.requests_of::<ProtoOaAccountAuthReq>(ProtoOaPayloadType::ProtoOaAccountAuthReq as u32);
+    assert_eq!(requests.len(), 2);
+    assert_eq!(client.account_state(ACCOUNT).await, Some(AccountAuthState::Authorized));
+    // the margin guard is seeded again after every authorization
+    let reconciles = server
+        .wait_for::<ProtoOaReconcileReq>(ProtoOaPayloadType::ProtoOaReconcileReq as u32, 2, TIMEOUT)
+        .await
+        .unwrap();
+    assert!(reconciles.iter().all(|req| req.ctid_trader_account_id == ACCOUNT));
+}
+
+#[tokio::test]
+async fn connects_over_tls() {
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let cert = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(certified.signing_key.serialize_der().into());
+    let server = MockServer::builder()
+        .account(ACCOUNT, false)
+        .tls(vec![cert.clone()], key)
+        .start()
+        .await
+        .unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::clone(&cert)).unwrap();
+    let (client, mut events) = start(builder(&server).tls(TlsMode::CustomRoots(roots))).await;
+
+    client.authorize_application().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+
+    // the bundled roots do not trust the mock certificate
+    assert!(builder(&server).tls(TlsMode::WebPkiRoots).build().await.is_err());
+}
+
+#[tokio::test]
+async fn sends_heartbeats() {
+    let server = server().await;
+    let (_client, _events) = start(builder(&server).heartbeat_interval(Some(Duration::from_millis(20)))).await;
+
+    let heartbeats = server
+        .wait_for::<ProtoHeartbeatEvent>(ProtoPayloadType::HeartbeatEvent as u32, 3, TIMEOUT)
+        .await;
+    assert!(heartbeats.is_some());
+}
+
+#[tokio::test]
+async fn replays_a_recorded_session() {
+    let path = std::env::temp_dir().join(format!("rust_ctrader_replay_{}.bin", std::process::id()));
+    let server = server().await;
+    {
+        let (client, mut events) = start(builder(&server).record_to(&path)).await;
+        client.authorize_application().await.unwrap();
+        client.get_accounts().await.unwrap();
+        next(&mut events, |event| matches!(event, StreamEvent::AccountsData(_)).then_some(())).await;
+        // writes the rest of the recording
+        client.shutdown().await.unwrap();
+    }
+
+    let replay = ReplayTransport::open(&path).unwrap().instant();
+    let (_client, mut events) = start(builder(&server).transport(replay)).await;
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+    let accounts = next(&mut events, |event| match event {
+        StreamEvent::AccountsData(accounts) => Some(accounts),
+        _ => None,
+    })
+    .await;
+    assert_eq!(accounts.len(), 2);
+
+    // nothing reached the server during the replay
+    assert_eq!(server.connections_accepted(), 1);
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn counts_messages_and_serves_metrics() {
+    let server = server().await;
+    let (client, mut events) = authorized(&server).await;
+    let addr = client.serve_metrics("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+    server.push_spot(ACCOUNT, EURUSD, 108_000, 108_020);
+    next(&mut events, |event| matches!(event, StreamEvent::LiveData(_)).then_some(())).await;
+
+    let metrics = client.metrics().await;
+    let account_auth = ProtoOaPayloadType::ProtoOaAccountAuthReq as u32;
+    assert_eq!(metrics.messages_sent[&account_auth], 1);
+    assert_eq!(metrics.request_latency[&account_auth].count, 1);
+    assert_eq!(metrics.messages_received[&(ProtoOaPayloadType::ProtoOaSpotEvent as u32)], 1);
+    assert_eq!(metrics.spot_events[&EURUSD], 1);
+    // the spot handler answers every spot with a heartbeat
+    assert_eq!(metrics.heartbeats_sent, 1);
+
+    let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+    socket.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("ctrader_spot_events_total{symbol_id=\"1\"} 1"));
+    assert!(response.contains("ctrader_messages_sent_total{payload_type=\"PROTO_OA_ACCOUNT_AUTH_REQ\"} 1"));
+    assert!(response.contains("ctrader_request_latency_seconds_count{request=\"PROTO_OA_ACCOUNT_AUTH_REQ\"} 1"));
+}
+
+#[tokio::test]
+async fn shuts_down_cleanly() {
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let cert = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(certified.signing_key.serialize_der().into());
+    let server = MockServer::builder()
+        .account(ACCOUNT, false)
+        .symbol(MockSymbol::new(EURUSD, "EURUSD"))
+        .tls(vec![cert.clone()], key)
+        .start()
+        .await
+        .unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::clone(&cert)).unwrap();
+    let (client, mut events) = builder(&server)
+        .tls(TlsMode::CustomRoots(roots))
+        .heartbeat_interval(Some(Duration::from_secs(60)))
+        .build()
+        .await
+        .unwrap();
+    let tasks = client.start().await;
+
+    client.authorize_application().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+    let account = client.account(ACCOUNT);
+    account.authorize().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::AccountAuthorized(_)).then_some(())).await;
+
+    account.get_symbol_by_id(EURUSD).await.unwrap();
+    symbol_data(&client, EURUSD).await;
+    account.subscribe_live_bars(EURUSD, TimeFrame::M1).await.unwrap();
+    let order = account
+        .order(EURUSD as u64, TradeSide::Buy)
+        .order_type(OrderType::Limit)
+        .limit_price(1.05)
+        .lots(0.1)
+        .build(&client)
+        .await
+        .unwrap();
+    account.new_order(order).await.unwrap();
+    let pending = async {
+        loop {
+            let orders = account.pending_orders().await;
+            if !orders.is_empty() {
+                return orders;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let pending = tokio::time::timeout(TIMEOUT, pending).await.expect("the order was not accepted");
+
+    let options = ShutdownOptions {
+        cancel_pending_orders: true,
+        ..ShutdownOptions::default()
+    };
+    client.shutdown_with(options).await.unwrap();
+
+    let live_bars = server.requests_of::<ProtoOaUnsubscribeLiveTrendbarReq>(ProtoOaPayloadType::ProtoOaUnsubscribeLiveTrendbarReq as u32);
+    assert_eq!(live_bars.len(), 1);
+    assert_eq!(live_bars[0].symbol_id, EURUSD);
+    let spots = server.requests_of::<ProtoOaUnsubscribeSpotsReq>(ProtoOaPayloadType::ProtoOaUnsubscribeSpotsReq as u32);
+    assert_eq!(spots[0].symbol_id, [EURUSD]);
+    let cancels = server.requests_of::<ProtoOaCancelOrderReq>(ProtoOaPayloadType::ProtoOaCancelOrderReq as u32);
+    assert_eq!(cancels.iter().map(|req| req.order_id).collect::<Vec<_>>(), pending);
+    let logouts = server.requests_of::<ProtoOaAccountLogoutReq>(ProtoOaPayloadType::ProtoOaAccountLogoutReq as u32);
+    assert_eq!(logouts[0].ctid_trader_account_id, ACCOUNT);
+
+    next(&mut events, |event| matches!(event, StreamEvent::Shutdown).then_some(())).await;
+    tokio::time::timeout(TIMEOUT, tasks.read_loop).await.unwrap().unwrap();
+    tokio::time::timeout(TIMEOUT, tasks.heartbeat.unwrap()).await.unwrap().unwrap();
+    assert!(client.is_shut_down());
+    assert!(client.keep_alive().await.is_err());
+
+    let closed = async {
+        while server.connections_closed() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(TIMEOUT, closed).await.expect("the connection was not closed cleanly");
+}
+
+#[tokio::test]
+async fn pool_routes_calls_to_the_connection_of_the_account() {
+    let demo_server = server().await;
+    let live_server = server().await;
+    let demo = builder(&demo_server).event_channel_capacity(4).build().await.unwrap();
+    let live = builder(&live_server).event_channel_capacity(8).build().await.unwrap();
+    let (pool, mut events) = ConnectionPool::from_clients(demo, live);
+    pool.start().await;
+
+    pool.authorize_application().await.unwrap();
+    pool.get_accounts().await.unwrap();
+    let listed = async {
+        loop {
+            let PoolEvent { is_live, event } = events.recv().await.expect("event channel closed");
+            if let StreamEvent::AccountsData(_) = event {
+                return is_live;
+            }
+        }
+    };
+    let is_live = tokio::time::timeout(TIMEOUT, listed).await.expect("no accounts listed");
+    assert!(!is_live);
+
+    const LIVE_ACCOUNT: i64 = 4_100_002;
+    assert!(pool.cancel_order(4_100_003, 1).await.is_err());
+    pool.subscribe_spot(LIVE_ACCOUNT, EURUSD).await.unwrap();
+    pool.unsubscribe_spot(LIVE_ACCOUNT, EURUSD).await.unwrap();
+    pool.unsubscribe_live_bars(LIVE_ACCOUNT, EURUSD, TimeFrame::M1).await.unwrap();
+    pool.cancel_order(LIVE_ACCOUNT, 77).await.unwrap();
+
+    let cancels = live_server
+        .wait_for::<ProtoOaCancelOrderReq>(ProtoOaPayloadType::ProtoOaCancelOrderReq as u32, 1, TIMEOUT)
+        .await
+        .unwrap();
+    assert_eq!((cancels[0].ctid_trader_account_id, cancels[0].order_id), (LIVE_ACCOUNT, 77));
+    let spots = live_server.requests_of::<ProtoOaUnsubscribeSpotsReq>(ProtoOaPayloadType::ProtoOaUnsubscribeSpotsReq as u32);
+    assert_eq!(spots[0].symbol_id, [EURUSD]);
+    let live_bars = live_server
+        .requests_of::<ProtoOaUnsubscribeLiveTrendbarReq>(ProtoOaPayloadType::ProtoOaUnsubscribeLiveTrendbarReq as u32);
+    assert_eq!(live_bars[0].ctid_trader_account_id, LIVE_ACCOUNT);
+
+    for payload_type in [
+        ProtoOaPayloadType::ProtoOaCancelOrderReq,
+        ProtoOaPayloadType::ProtoOaUnsubscribeSpotsReq,
+        ProtoOaPayloadType::ProtoOaUnsubscribeLiveTrendbarReq,
+    ] {
+        assert!(demo_server.requests().iter().all(|req| req.payload_type != payload_type as u32));
+    }
+}
+
+#[tokio::test]
+async fn pool_opens_its_connections_with_the_builder_settings() {
+    let demo_server = server().await;
+    let live_server = server().await;
+    let heartbeats = Some(Duration::from_millis(20));
+    let (pool, _events) = ConnectionPool::from_builders(
+        builder(&demo_server).heartbeat_interval(heartbeats),
+        builder(&live_server).heartbeat_interval(heartbeats),
+    )
+    .await
+    .unwrap();
+    pool.start().await;
+
+    // both connections send the heartbeats the builders asked for
+    for server in [&demo_server, &live_server] {
+        server
+            .wait_for::<ProtoHeartbeatEvent>(ProtoPayloadType::HeartbeatEvent as u32, 2, TIMEOUT)
+            .await
+            .expect("no heartbeats sent");
+    }
+    pool.shutdown(ShutdownOptions::default()).await.unwrap();
+}
+
+#[tokio::test]
+async fn emits_refreshed_tokens_on_the_shared_stream() {
+    let server = server().await;
+    let (client, mut events) = start(builder(&server)).await;
+    // an account with its own channel does not take connection wide events
+    let _account_events = client.account_events(ACCOUNT).await;
+
+    let refreshed = ProtoOaRefreshTokenRes {
+        payload_type: Some(ProtoOaPayloadType::ProtoOaRefreshTokenRes as i32),
+        access_token: "new access".to_string(),
+        token_type: "bearer".to_string(),
+        expires_in: 2_628_000,
+        refresh_token: "new refresh".to_string(),
+    };
+    server.script(
+        ProtoOaPayloadType::ProtoOaRefreshTokenReq as u32,
+        vec![mock_server::message(ProtoOaPayloadType::ProtoOaRefreshTokenRes as u32, refreshed.clone())],
+    );
+    client.set_refresh_token("refresh").await;
+    client.refresh_access_token().await.unwrap();
+    let tokens = next(&mut events, |event| match event {
+        StreamEvent::AccessTokenRefreshed(tokens) => Some(tokens),
+        _ => None,
+    })
+    .await;
+    assert_eq!(tokens.access_token, "new access");
+    assert_eq!(tokens.refresh_token, "new refresh");
+
+    // with nobody listening the event is counted as dropped
+    drop(events);
+    server.script(
+        ProtoOaPayloadType::ProtoOaRefreshTokenReq as u32,
+        vec![mock_server::message(ProtoOaPayloadType::ProtoOaRefreshTokenRes as u32, refreshed)],
+    );
+    client.refresh_access_token().await.unwrap();
+    let dropped = async {
+        while client.metrics().await.dropped_events == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(TIMEOUT, dropped).await.expect("the refreshed tokens were not counted as dropped");
+}
+
+#[tokio::test]
+async fn refreshes_short_lived_tokens_before_they_expire() {
+    let server = server().await;
+    let (client, mut events) = start(builder(&server)).await;
+    client.authorize_application().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+
+    let refreshed = ProtoOaRefreshTokenRes {
+        payload_type: Some(ProtoOaPayloadType::ProtoOaRefreshTokenRes as i32),
+        access_token: "new access".to_string(),
+        token_type: "bearer".to_string(),
+        expires_in: 600,
+        refresh_token: "new refresh".to_string(),
+    };
+    server.script(
+        ProtoOaPayloadType::ProtoOaRefreshTokenReq as u32,
+        vec![mock_server::message(ProtoOaPayloadType::ProtoOaRefreshTokenRes as u32, refreshed)],
+    );
+    client.set_refresh_token("refresh").await;
+
+    // a ten minute token is refreshed after nine, not right away
+    tokio::time::pause();
+    let started = tokio::time::Instant::now();
+    let refresh = client.start_token_refresh(600);
+    let tokens = next_paused(&mut events, |event| match event {
+        StreamEvent::AccessTokenRefreshed(tokens) => Some(tokens),
+        _ => None,
+    })
+    .await;
+    let elapsed = started.elapsed();
+    assert_eq!(tokens.access_token, "new access");
+    assert!(elapsed >= Duration::from_secs(540) && elapsed < Duration::from_secs(600), "{elapsed:?}");
+    assert_eq!(server.requests_of::<ProtoOaRefreshTokenReq>(ProtoOaPayloadType::ProtoOaRefreshTokenReq as u32).len(), 1);
+    refresh.abort();
+}
+
+#[tokio::test]
+async fn gives_up_refreshing_the_token_after_repeated_failures() {
+    let server = server().await;
+    let transport = RefusingTransport {
+        refused: ProtoOaPayloadType::ProtoOaRefreshTokenReq,
+    };
+    let (client, mut events) = start(builder(&server).transport(transport)).await;
+    client.authorize_application().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+    client.set_refresh_token("refresh").await;
+
+    tokio::time::pause();
+    let started = tokio::time::Instant::now();
+    let refresh = client.start_token_refresh(600);
+    let error = next_paused(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(error, "Failed to refresh the access token after 5 attempts: write refused");
+    // the first attempt at nine minutes, then retries after 5, 10, 20 and 40 seconds
+    assert_eq!(started.elapsed().as_secs(), 540 + 5 + 10 + 20 + 40);
+    tokio::time::timeout(TIMEOUT, refresh).await.expect("the refresh task kept running").unwrap();
+}
+
+#[tokio::test]
+async fn keeps_reading_when_the_margin_guard_cannot_flatten() {
+    let server = MockServer::builder()
+        .account(ACCOUNT, false)
+        .symbol(MockSymbol::new(EURUSD, "EURUSD"))
+        .version("1")
+        .start()
+        .await
+        .unwrap();
+    let (client, mut events) = start(builder(&server).version_check(VersionPolicy::Refuse)).await;
+    next(&mut events, |event| matches!(event, StreamEvent::ServerVersionData(_)).then_some(())).await;
+    client.set_margin_guard(ACCOUNT, Some(150.0), GuardAction::Flatten).await;
+
+    // 100.00 of balance against 1000.00 of used margin, far under 150%
+    server.push(mock_server::message(
+        ProtoOaPayloadType::ProtoOaTraderRes as u32,
+        ProtoOaTraderRes {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaTraderRes as i32),
+            ctid_trader_account_id: ACCOUNT,
+            trader: ProtoOaTrader {
+                ctid_trader_account_id: ACCOUNT,
+                balance: 10_000,
+                money_digits: Some(2),
+                ..Default::default()
+            },
+        },
+    ));
+    next(&mut events, |event| matches!(event, StreamEvent::TraderData(_)).then_some(())).await;
+    server.push(mock_server::message(
+        ProtoOaPayloadType::ProtoOaExecutionEvent as u32,
+        ProtoOaExecutionEvent {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaExecutionEvent as i32),
+            ctid_trader_account_id: ACCOUNT,
+            execution_type: ProtoOaExecutionType::OrderFilled as i32,
+            position: Some(ProtoOaPosition {
+                position_id: 5,
+                trade_data: ProtoOaTradeData { symbol_id: EURUSD, volume: 100_000, ..Default::default() },
+                position_status: ProtoOaPositionStatus::PositionStatusOpen as i32,
+                used_margin: Some(100_000),
+                money_digits: Some(2),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    ));
+
+    // the refused close is reported, the alert still goes out
+    let error = next(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        _ => None,
+    })
+    .await;
+    assert!(error.starts_with("could not close position 5 for the margin guard: trading refused"), "{error}");
+    next(&mut events, |event| matches!(event, StreamEvent::MarginGuardTriggered(_)).then_some(())).await;
+
+    // and the connection is still read
+    server.push_spot(ACCOUNT, EURUSD, 108_000, 108_020);
+    next(&mut events, |event| matches!(event, StreamEvent::LiveData((Some(_), None, None))).then_some(())).await;
+}
+
+/// The protobuf transport, except that writing `refused` fails.
+#[derive(Debug)]
+struct RefusingTransport {
+    refused: ProtoOaPayloadType,
+}
+
+struct RefusingSink {
+    inner: MessageSink,
+    refused: u32,
+}
+
+impl Sink<ProtoMessage> for RefusingSink {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: ProtoMessage) -> io::Result<()> {
+        if message.payload_type == self.refused {
+            return Err(io::Error::other("write refused"));
+        }
+        self.inner.as_mut().start_send(message)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_close(cx)
+    }
+}
+
+impl Transport for RefusingTransport {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        options: &'a ConnectOptions,
+        max_frame_size: usize,
+    ) -> BoxFuture<'a, Result<(MessageStream, MessageSink), Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            let (messages, inner) = ProtobufTransport.connect(endpoint, options, max_frame_size).await?;
+            let sink: MessageSink = Box::pin(RefusingSink {
+                inner,
+                refused: self.refused as u32,
+            });
+            Ok((messages, sink))
+        })
+    }
+}
+
+#[tokio::test]
+async fn keeps_reading_when_a_reauthorization_cannot_be_sent() {
+    let server = server().await;
+    let transport = RefusingTransport {
+        refused: ProtoOaPayloadType::ProtoOaRefreshTokenReq,
+    };
+    let (client, mut events) = start(builder(&server).transport(transport)).await;
+    client.authorize_application().await.unwrap();
+    client.authorize_account(ACCOUNT).await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::AccountAuthorized(_)).then_some(())).await;
+    // the session was authorized with the current token, so it needs a refresh
+    client.set_refresh_token("refresh").await;
+
+    server.push(mock_server::message(
+        ProtoOaPayloadType::ProtoOaAccountDisconnectEvent as u32,
+        ProtoOaAccountDisconnectEvent {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaAccountDisconnectEvent as i32),
+            ctid_trader_account_id: ACCOUNT,
+        },
+    ));
+    let error = next(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(error, "could not refresh the token to re-authorize the account: write refused");
+
+    // the failed refresh is not waited for, the next invalidation tries again
+    server.push(mock_server::message(
+        ProtoOaPayloadType::ProtoOaAccountsTokenInvalidatedEvent as u32,
+        ProtoOaAccountsTokenInvalidatedEvent {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaAccountsTokenInvalidatedEvent as i32),
+            ctid_trader_account_ids: vec![ACCOUNT],
+            reason: None,
+        },
+    ));
+    let error = next(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(error, "could not refresh the token to re-authorize the account: write refused");
+
+    // and the connection is still read
+    server.push_spot(ACCOUNT, EURUSD, 108_000, 108_020);
+    next(&mut events, |event| matches!(event, StreamEvent::LiveData((Some(_), None, None))).then_some(())).await;
+    assert_eq!(client.account_state(ACCOUNT).await, Some(AccountAuthState::Unauthorized));
+}
+
+#[tokio::test]
+async fn keeps_reading_when_a_conversion_feed_cannot_be_opened() {
+    let server = server().await;
+    let transport = RefusingTransport {
+        refused: ProtoOaPayloadType::ProtoOaSubscribeSpotsReq,
+    };
+    let (client, mut events) = start(builder(&server).transport(transport)).await;
+    client.authorize_application().await.unwrap();
+    next(&mut events, |event| matches!(event, StreamEvent::ApplicationAuthorized(_)).then_some(())).await;
+
+    // EUR to USD over EURUSD, as asked for with `get_conversion_chain`
+    let mut chain = mock_server::message(
+        ProtoOaPayloadType::ProtoOaSymbolsForConversionRes as u32,
+        ProtoOaSymbolsForConversionRes {
+            payload_type: Some(ProtoOaPayloadType::ProtoOaSymbolsForConversionRes as i32),
+            ctid_trader_account_id: ACCOUNT,
+            symbol: vec![ProtoOaLightSymbol {
+                symbol_id: EURUSD,
+                base_asset_id: Some(1),
+                quote_asset_id: Some(2),
+                ..Default::default()
+            }],
+        },
+    );
+    chain.client_msg_id = Some("conversion chain 1 2".to_string());
+    server.push(chain);
+
+    let error = next(&mut events, |event| match event {
+        StreamEvent::Error(error) => Some(error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(error, "could not subscribe to the spots of symbol 1 for a conversion chain: write refused");
+    next(&mut events, |event| matches!(event, StreamEvent::ConversionChainData(_)).then_some(())).await;
+
+    server.push_spot(ACCOUNT, EURUSD, 108_000, 108_020);
+    next(&mut events, |event| matches!(event, StreamEvent::LiveData((Some(_), None, None))).then_some(())).await;
+}
@@ -0,0 +1,232 @@
+// connecting to the mock server through local stand-ins for an http CONNECT
+// and a socks5 proxy.  each stand-in checks the handshake, then relays bytes
+// both ways like the real thing.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::Engine;
+use rust_ctrader::mock_server::MockServer;
+use rust_ctrader::{CtraderClient, CtraderClientBuilder, Endpoint, Proxy, StreamEvent, TlsMode};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// user "trader@desk", password "p@ss:w%rd", percent-encoded as a url needs it
+const USERINFO: &str = "trader%40desk:p%40ss%3Aw%25rd";
+const USERNAME: &str = "trader@desk";
+const PASSWORD: &str = "p@ss:w%rd";
+
+/// What a stand-in saw: the target it was asked to connect to and the
+/// credentials it got.
+#[derive(Debug, Default, Clone)]
+struct Seen {
+    target: Option<String>,
+    host_header: Option<String>,
+    credentials: Option<(String, String)>,
+}
+
+fn builder(server: &MockServer, proxy: Proxy) -> CtraderClientBuilder {
+    CtraderClient::builder()
+        .credentials("client id", "client secret", "access token")
+        .endpoint(server.endpoint())
+        .tls(TlsMode::Plain)
+        .heartbeat_interval(None)
+        .proxy(proxy)
+}
+
+// connects through the proxy and authorizes the application over the tunnel
+async fn authorize_through(server: &MockServer, proxy: Proxy) {
+    let (client, mut events) = builder(server, proxy).build().await.unwrap();
+    client.start().await;
+    client.authorize_application().await.unwrap();
+    let authorized = async {
+        loop {
+            match events.recv().await.expect("event channel closed") {
+                StreamEvent::ApplicationAuthorized(_) => return,
+                StreamEvent::Error(e) => panic!("{e}"),
+                _ => {}
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, authorized).await.expect("timed out waiting for the authorization");
+}
+
+fn mock_address(server: &MockServer) -> String {
+    match server.endpoint() {
+        Endpoint::Custom { host, port } => format!("{host}:{port}"),
+        endpoint => panic!("unexpected mock endpoint {endpoint:?}"),
+    }
+}
+
+// `upstream` replaces the requested target, e.g. to reach the mock for an
+// address it does not listen on
+async fn http_proxy(status: &'static str, upstream: Option<String>) -> (u16, Arc<Mutex<Seen>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let seen = Arc::new(Mutex::new(Seen::default()));
+    let record = Arc::clone(&seen);
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+        let mut request_line = String::new();
+        socket.read_line(&mut request_line).await.unwrap();
+        let target = request_line.split_whitespace().nth(1).unwrap().to_string();
+        loop {
+            let mut header = String::new();
+            socket.read_line(&mut header).await.unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some(host) = header.strip_prefix("Host: ") {
+                record.lock().unwrap().host_header = Some(host.trim().to_string());
+            }
+            if let Some(credentials) = header.strip_prefix("Proxy-Authorization: Basic ") {
+                let decoded = base64::engine::general_purpose::STANDARD.decode(credentials.trim()).unwrap();
+                let decoded = String::from_utf8(decoded).unwrap();
+                let (username, password) = decoded.split_once(':').unwrap();
+                record.lock().unwrap().credentials = Some((username.to_string(), password.to_string()));
+            }
+        }
+        record.lock().unwrap().target = Some(target.clone());
+
+        let response = format!("HTTP/1.1 {status}\r\nProxy-Agent: stand-in\r\n\r\n");
+        socket.get_mut().write_all(response.as_bytes()).await.unwrap();
+        if status.starts_with('2') {
+            let mut upstream = TcpStream::connect(upstream.unwrap_or(target)).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(socket.get_mut(), &mut upstream).await;
+        }
+    });
+    (port, seen)
+}
+
+// `version` is the version byte the stand-in answers with, 5 for a well
+// behaved proxy
+async fn socks5_proxy(version: u8) -> (u16, Arc<Mutex<Seen>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let seen = Arc::new(Mutex::new(Seen::default()));
+    let record = Arc::clone(&seen);
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let mut greeting = [0u8; 2];
+        socket.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting[0], 0x05);
+        let mut methods = vec![0u8; greeting[1] as usize];
+        socket.read_exact(&mut methods).await.unwrap();
+        assert!(methods.contains(&0x02), "username/password not offered");
+        socket.write_all(&[version, 0x02]).await.unwrap();
+        if version != 0x05 {
+            return;
+        }
+
+        // rfc 1929: version 1, then length prefixed user name and password
+        let mut header = [0u8; 2];
+        socket.read_exact(&mut header).await.unwrap();
+        let mut username = vec![0u8; header[1] as usize];
+        socket.read_exact(&mut username).await.unwrap();
+        let mut password = vec![0u8; socket.read_u8().await.unwrap() as usize];
+        socket.read_exact(&mut password).await.unwrap();
+        record.lock().unwrap().credentials = Some((
+            String::from_utf8(username).unwrap(),
+            String::from_utf8(password).unwrap(),
+        ));
+        socket.write_all(&[0x01, 0x00]).await.unwrap();
+
+        // CONNECT by domain name
+        let mut request = [0u8; 5];
+        socket.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request[..4], &[0x05, 0x01, 0x00, 0x03]);
+        let mut host = vec![0u8; request[4] as usize];
+        socket.read_exact(&mut host).await.unwrap();
+        let port = socket.read_u16().await.unwrap();
+        let target = format!("{}:{}", String::from_utf8(host).unwrap(), port);
+        record.lock().unwrap().target = Some(target.clone());
+
+        let mut upstream = TcpStream::connect(target).await.unwrap();
+        socket.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0]).await.unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut socket, &mut upstream).await;
+    });
+    (port, seen)
+}
+
+#[tokio::test]
+async fn tunnels_through_an_http_proxy() {
+    let server = MockServer::builder().start().await.unwrap();
+    let (port, seen) = http_proxy("200 Connection established", None).await;
+    let proxy = Proxy::from_url(&format!("http://{USERINFO}@127.0.0.1:{port}")).unwrap();
+
+    authorize_through(&server, proxy).await;
+
+    let seen = seen.lock().unwrap().clone();
+    assert_eq!(seen.target, Some(mock_address(&server)));
+    assert_eq!(seen.host_header, Some(mock_address(&server)));
+    assert_eq!(seen.credentials, Some((USERNAME.to_string(), PASSWORD.to_string())));
+    assert_eq!(server.connections_accepted(), 1);
+}
+
+#[tokio::test]
+async fn brackets_ipv6_hosts_in_the_http_connect() {
+    let server = MockServer::builder().start().await.unwrap();
+    let (port, seen) = http_proxy("200 Connection established", Some(mock_address(&server))).await;
+    let proxy = Proxy::from_url(&format!("http://127.0.0.1:{port}")).unwrap();
+    let endpoint = Endpoint::Custom {
+        host: "::1".to_string(),
+        port: server.addr().port(),
+    };
+    let (client, mut events) = builder(&server, proxy).endpoint(endpoint).build().await.unwrap();
+    client.start().await;
+    client.authorize_application().await.unwrap();
+    let authorized = async {
+        while !matches!(events.recv().await, Some(StreamEvent::ApplicationAuthorized(_))) {}
+    };
+    tokio::time::timeout(TIMEOUT, authorized).await.expect("timed out waiting for the authorization");
+
+    let expected = format!("[::1]:{}", server.addr().port());
+    let seen = seen.lock().unwrap().clone();
+    assert_eq!(seen.target, Some(expected.clone()));
+    assert_eq!(seen.host_header, Some(expected));
+}
+
+#[tokio::test]
+async fn fails_when_the_http_proxy_refuses() {
+    let server = MockServer::builder().start().await.unwrap();
+    let (port, _) = http_proxy("407 Proxy Authentication Required", None).await;
+    let proxy = Proxy::from_url(&format!("http://127.0.0.1:{port}")).unwrap();
+
+    let Err(error) = builder(&server, proxy).build().await else {
+        panic!("connected through a refusing proxy");
+    };
+
+    assert!(error.to_string().contains("407"), "{error}");
+    assert_eq!(server.connections_accepted(), 0);
+}
+
+#[tokio::test]
+async fn tunnels_through_a_socks5_proxy() {
+    let server = MockServer::builder().start().await.unwrap();
+    let (port, seen) = socks5_proxy(0x05).await;
+    let proxy = Proxy::from_url(&format!("socks5://{USERINFO}@127.0.0.1:{port}")).unwrap();
+
+    authorize_through(&server, proxy).await;
+
+    let seen = seen.lock().unwrap().clone();
+    assert_eq!(seen.target, Some(mock_address(&server)));
+    assert_eq!(seen.credentials, Some((USERNAME.to_string(), PASSWORD.to_string())));
+    assert_eq!(server.connections_accepted(), 1);
+}
+
+#[tokio::test]
+async fn rejects_a_proxy_speaking_another_socks_version() {
+    let server = MockServer::builder().start().await.unwrap();
+    let (port, _) = socks5_proxy(0x04).await;
+    let proxy = Proxy::from_url(&format!("socks5://{USERINFO}@127.0.0.1:{port}")).unwrap();
+
+    let Err(error) = builder(&server, proxy).build().await else {
+        panic!("connected through a refusing proxy");
+    };
+
+    assert!(error.to_string().contains("socks version 4"), "{error}");
+}